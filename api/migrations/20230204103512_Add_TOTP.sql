@@ -0,0 +1,18 @@
+CREATE TABLE totp_secrets (
+    user_id uuid PRIMARY KEY REFERENCES users ON DELETE CASCADE,
+    secret bytea NOT NULL,
+    -- NULL until the user proved that the authenticator is set up
+    -- correctly by submitting the first code
+    confirmed_at timestamp,
+    -- Used to reject replays of an already used code
+    last_used_step bigint,
+    created_at timestamp NOT NULL DEFAULT NOW()
+);
+
+-- A password check succeeded but a second factor is still missing
+CREATE TABLE login_challenges (
+    id uuid PRIMARY KEY,
+    user_id uuid NOT NULL REFERENCES users ON DELETE CASCADE,
+    failed_attempts integer NOT NULL DEFAULT 0,
+    created_at timestamp NOT NULL DEFAULT NOW()
+);
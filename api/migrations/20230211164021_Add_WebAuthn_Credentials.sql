@@ -0,0 +1,17 @@
+CREATE TABLE webauthn_credentials (
+    id uuid PRIMARY KEY,
+    -- The id chosen by the authenticator
+    credential_id bytea UNIQUE NOT NULL,
+    user_id uuid NOT NULL REFERENCES users ON DELETE CASCADE,
+    -- A user chosen name, so multiple keys can be told apart
+    name text NOT NULL,
+    -- The serialized `webauthn_rs::prelude::Passkey`, includes the public key
+    passkey jsonb NOT NULL,
+    -- Duplicated from the passkey for easier inspection, the counter that
+    -- is actually checked lives inside `passkey`
+    sign_count bigint NOT NULL DEFAULT 0,
+    created_at timestamp NOT NULL DEFAULT NOW(),
+    last_used_at timestamp
+);
+
+CREATE INDEX webauthn_credentials_user_id_idx ON webauthn_credentials (user_id);
@@ -0,0 +1,23 @@
+-- Apps that can use Hausmeister as OpenID Provider
+CREATE TABLE oidc_clients (
+    -- The `client_id`
+    id text PRIMARY KEY,
+    name text NOT NULL,
+    -- Argon2 hash of the client secret, NULL for public clients
+    -- (i.e. single page apps) which can only rely on PKCE
+    secret text,
+    redirect_uris text[] NOT NULL,
+    created_at timestamp NOT NULL DEFAULT NOW()
+);
+
+CREATE TABLE oidc_authorization_codes (
+    id uuid PRIMARY KEY,
+    client_id text NOT NULL REFERENCES oidc_clients ON DELETE CASCADE,
+    user_id uuid NOT NULL REFERENCES users ON DELETE CASCADE,
+    redirect_uri text NOT NULL,
+    scope text NOT NULL,
+    nonce text,
+    -- BASE64URL(SHA256(code_verifier)), only S256 is supported
+    code_challenge text NOT NULL,
+    created_at timestamp NOT NULL DEFAULT NOW()
+);
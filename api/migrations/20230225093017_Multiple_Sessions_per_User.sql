@@ -0,0 +1,8 @@
+-- Every device gets its own session
+ALTER TABLE sessions DROP CONSTRAINT sessions_user_id_key;
+CREATE INDEX sessions_user_id_idx ON sessions (user_id);
+
+ALTER TABLE sessions
+    ADD COLUMN user_agent text,
+    ADD COLUMN ip_address text,
+    ADD COLUMN last_seen_at timestamp NOT NULL DEFAULT NOW();
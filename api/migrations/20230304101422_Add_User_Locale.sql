@@ -0,0 +1,2 @@
+-- Preferred language, mails are sent in the default locale if unset
+ALTER TABLE users ADD COLUMN locale text;
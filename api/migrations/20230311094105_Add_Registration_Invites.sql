@@ -0,0 +1,6 @@
+-- Every invite allows a single registration if registration is invite-only
+CREATE TABLE registration_invites (
+    id uuid PRIMARY KEY,
+    created_by uuid NOT NULL REFERENCES users ON DELETE CASCADE,
+    created_at timestamp NOT NULL DEFAULT NOW()
+);
@@ -0,0 +1,12 @@
+ALTER TABLE users ADD COLUMN email_verified_at timestamp;
+-- Accounts created before verification existed are trusted
+UPDATE users SET email_verified_at = NOW();
+
+CREATE TABLE email_verifications (
+    id uuid PRIMARY KEY,
+    user_id uuid NOT NULL REFERENCES users ON DELETE CASCADE,
+    -- The verified address, so changing the email invalidates the token
+    email text NOT NULL,
+    created_at timestamp NOT NULL DEFAULT NOW()
+);
+CREATE INDEX email_verifications_user_id_idx ON email_verifications (user_id);
@@ -0,0 +1,14 @@
+-- Email changes have to be confirmed by the new address and can be
+-- reverted by the old address, even after they were confirmed
+CREATE TABLE email_changes (
+    -- Sent to the new address
+    id uuid PRIMARY KEY,
+    -- Sent to the old address
+    revert_token uuid UNIQUE NOT NULL,
+    user_id uuid NOT NULL REFERENCES users ON DELETE CASCADE,
+    old_email text NOT NULL,
+    new_email text NOT NULL,
+    created_at timestamp NOT NULL DEFAULT NOW(),
+    confirmed_at timestamp
+);
+CREATE INDEX email_changes_user_id_idx ON email_changes (user_id);
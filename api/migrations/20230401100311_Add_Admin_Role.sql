@@ -0,0 +1,16 @@
+ALTER TABLE users
+    ADD COLUMN is_admin boolean NOT NULL DEFAULT false,
+    ADD COLUMN disabled_at timestamp;
+
+-- The account created by create_admin_if_no_user_exist
+UPDATE users SET is_admin = true WHERE name = 'Admin' AND email = 'admin@example.com';
+
+-- Deleting users has to remove their sessions and reset requests as well
+ALTER TABLE sessions
+    DROP CONSTRAINT sessions_user_id_fkey,
+    ADD CONSTRAINT sessions_user_id_fkey
+        FOREIGN KEY (user_id) REFERENCES users ON DELETE CASCADE;
+ALTER TABLE password_reset_requests
+    DROP CONSTRAINT password_reset_requests_user_id_fkey,
+    ADD CONSTRAINT password_reset_requests_user_id_fkey
+        FOREIGN KEY (user_id) REFERENCES users ON DELETE CASCADE;
@@ -0,0 +1,27 @@
+CREATE TABLE permissions (
+    name text PRIMARY KEY,
+    description text NOT NULL DEFAULT ''
+);
+
+CREATE TABLE roles (
+    name text PRIMARY KEY,
+    description text NOT NULL DEFAULT ''
+);
+
+CREATE TABLE role_permissions (
+    role text NOT NULL REFERENCES roles ON DELETE CASCADE,
+    permission text NOT NULL REFERENCES permissions ON DELETE CASCADE,
+    PRIMARY KEY (role, permission)
+);
+
+CREATE TABLE user_roles (
+    user_id uuid NOT NULL REFERENCES users ON DELETE CASCADE,
+    role text NOT NULL REFERENCES roles ON DELETE CASCADE,
+    PRIMARY KEY (user_id, role)
+);
+
+CREATE INDEX user_roles_role_idx ON user_roles (role);
+
+-- Permissions checked by Hausmeister itself, admins have every permission
+INSERT INTO permissions (name, description)
+    VALUES ('users:read', 'List and view all users');
@@ -0,0 +1,37 @@
+CREATE TABLE organizations (
+    id uuid PRIMARY KEY,
+    name text NOT NULL,
+    created_at timestamp NOT NULL DEFAULT NOW()
+);
+
+CREATE TABLE organization_members (
+    organization_id uuid NOT NULL REFERENCES organizations ON DELETE CASCADE,
+    user_id uuid NOT NULL REFERENCES users ON DELETE CASCADE,
+    -- Organization admins manage members and invites of their organization
+    is_admin boolean NOT NULL DEFAULT false,
+    PRIMARY KEY (organization_id, user_id)
+);
+
+CREATE INDEX organization_members_user_id_idx ON organization_members (user_id);
+
+CREATE TABLE organization_member_roles (
+    organization_id uuid NOT NULL,
+    user_id uuid NOT NULL,
+    role text NOT NULL REFERENCES roles ON DELETE CASCADE,
+    PRIMARY KEY (organization_id, user_id, role),
+    FOREIGN KEY (organization_id, user_id) REFERENCES organization_members ON DELETE CASCADE
+);
+
+CREATE TABLE organization_invites (
+    id uuid PRIMARY KEY,
+    organization_id uuid NOT NULL REFERENCES organizations ON DELETE CASCADE,
+    email text NOT NULL,
+    is_admin boolean NOT NULL DEFAULT false,
+    roles text[] NOT NULL DEFAULT '{}',
+    created_by uuid NOT NULL REFERENCES users ON DELETE CASCADE,
+    created_at timestamp NOT NULL DEFAULT NOW()
+);
+
+-- Removing a member has to reset this as well, see remove_member
+ALTER TABLE sessions
+    ADD COLUMN active_organization uuid REFERENCES organizations ON DELETE SET NULL;
@@ -0,0 +1,10 @@
+-- One-time codes replacing the second factor if the device is lost
+CREATE TABLE recovery_codes (
+    id uuid PRIMARY KEY,
+    user_id uuid NOT NULL REFERENCES users ON DELETE CASCADE,
+    -- SHA-256 of the code, the codes are random enough to not need Argon2
+    code_hash bytea NOT NULL,
+    used_at timestamp,
+    created_at timestamp NOT NULL DEFAULT NOW(),
+    UNIQUE (user_id, code_hash)
+);
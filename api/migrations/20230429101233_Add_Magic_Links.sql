@@ -0,0 +1,6 @@
+-- Single-use links for logging in without a password, at most one per user
+CREATE TABLE magic_links (
+    id uuid PRIMARY KEY,
+    user_id uuid UNIQUE NOT NULL REFERENCES users ON DELETE CASCADE,
+    created_at timestamp NOT NULL DEFAULT NOW()
+);
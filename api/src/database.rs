@@ -1,18 +1,29 @@
 //! All methods to talk to the database reside here.
 //!
 //! This makes any changes to tables, relations etc. easier.
-//! Currently this module is also responsible for password hashing,
-//! but this should propably be moved to a different module.
+//! Password hashing is done by [hashing](crate::hashing).
 
+pub(crate) mod admin;
 pub(crate) mod auth;
+pub(crate) mod email_change;
+pub(crate) mod import;
+pub(crate) mod lockout;
+pub(crate) mod magic_link;
+pub(crate) mod oidc;
+pub(crate) mod organizations;
+pub(crate) mod recovery_codes;
+pub(crate) mod registration;
+pub(crate) mod roles;
+pub(crate) mod sessions;
+pub(crate) mod totp;
+pub(crate) mod verification;
+pub(crate) mod webauthn;
+
+#[cfg(test)]
+mod tests;
 
 use std::time::Duration;
 
-use argon2::{
-    password_hash::{rand_core::OsRng, SaltString},
-    Argon2, PasswordHasher,
-};
-
 use color_eyre::{
     eyre::{eyre, Context},
     Report,
@@ -28,7 +39,9 @@ use tracing::{debug, debug_span, info, Instrument};
 use uuid::Uuid;
 
 use crate::{
-    settings::DbConfig,
+    hashing::Hasher,
+    password_policy::{PasswordPolicy, PolicyViolation},
+    settings::{DbConfig, ResetConfig},
     types::{EMail, Password},
 };
 
@@ -37,11 +50,13 @@ use self::auth::Credentials;
 /// This directly mirrors the `users` table, expect for the password
 /// column, since we don't want to return a password on accident
 #[allow(clippy::missing_docs_in_private_items)]
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, Clone)]
 pub(crate) struct User {
     pub(crate) id: Uuid,
     pub(crate) name: String,
     pub(crate) email: EMail,
+    /// Preferred language as BCP 47 tag, i.e. `de-AT`, used for mails
+    pub(crate) locale: Option<String>,
 }
 
 /// This is an subset of [User], containing all the updatable properties
@@ -50,7 +65,10 @@ pub(crate) struct User {
 #[derive(Debug, Deserialize)]
 pub(crate) struct UserUpdate {
     pub(crate) name: Option<String>,
-    pub(crate) email: Option<String>,
+    /// Not updated by [update_current_user], changing the email has to be
+    /// confirmed, see [email_change]
+    pub(crate) email: Option<EMail>,
+    pub(crate) locale: Option<String>,
 }
 
 /// The same as [User], just including a password where it is
@@ -109,22 +127,19 @@ pub(crate) async fn count_user(pool: &PgPool) -> Result<i64, Report> {
 /// the same admin + password is chosen by all instances
 /// (and this method probably needs to be removed for
 /// security reasons anyway)
-#[tracing::instrument(skip(pool))]
+#[tracing::instrument(skip(pool, hasher))]
 pub(crate) async fn create_admin_if_no_user_exist(
     pool: &PgPool,
+    hasher: &Hasher,
     Credentials { password, email }: &Credentials,
 ) -> Result<(), Report> {
-    let argon2 = Argon2::default();
-    let salt = SaltString::generate(&mut OsRng);
-    let hash = argon2
-        .hash_password(password.0.as_bytes(), &salt)?
-        .to_string();
-
     if count_user(pool).await? == 0 {
         debug!("No user exist: Creating some.");
+        let hash = hasher.hash(&password.0).await?;
         let query_result = sqlx::query!(
             r#"
-    INSERT INTO users (id, email, password, name) VALUES ($1, $2, $3, 'Admin')
+    INSERT INTO users (id, email, password, name, email_verified_at, is_admin)
+        VALUES ($1, $2, $3, 'Admin', NOW(), true)
         ON CONFLICT DO NOTHING"#,
             Uuid::new_v4(),
             email.0,
@@ -158,6 +173,21 @@ pub(crate) async fn get_user_by_email(
         id: db_user.id,
         name: db_user.name,
         email: EMail(db_user.email),
+        locale: db_user.locale,
+    }))
+}
+
+#[tracing::instrument(skip(pool))]
+pub(crate) async fn get_user_by_id(pool: &PgPool, id: &Uuid) -> Result<Option<User>, Report> {
+    let db_user = sqlx::query!("SELECT * FROM users WHERE id=$1", id)
+        .fetch_optional(pool)
+        .await?;
+
+    Ok(db_user.map(|db_user| User {
+        id: db_user.id,
+        name: db_user.name,
+        email: EMail(db_user.email),
+        locale: db_user.locale,
     }))
 }
 
@@ -187,6 +217,12 @@ pub(crate) async fn new_reset_request(pool: &PgPool, user_id: &Uuid) -> Result<U
 pub(crate) enum ResetError {
     /// The reset token does not exist so no password was reset
     TokenNotFound,
+    /// The reset token exists, but is older than the configured lifetime.
+    /// It is deleted anyway, so the user has to request a new one.
+    TokenExpired,
+    /// The new password does not follow the policy, the token can be
+    /// used again
+    WeakPassword(Vec<PolicyViolation>),
 }
 
 /// Resets the password of the user associated with the given reset token.
@@ -196,30 +232,69 @@ pub(crate) enum ResetError {
 /// for errors that have a concrete reason and can be fixed by the caller.
 ///
 /// See [ResetError] for the possible failures.
-#[tracing::instrument(skip(pool))]
+#[tracing::instrument(skip(pool, config, policy, hasher))]
 pub(crate) async fn reset_password(
     pool: &PgPool,
+    config: &ResetConfig,
+    policy: &PasswordPolicy,
+    hasher: &Hasher,
     reset_token: &Uuid,
     new_password: &Password,
 ) -> Result<Result<(), ResetError>, Report> {
-    let mut transaction = pool.begin().await?;
-
     let reset_request = sqlx::query!(
-        "DELETE FROM password_reset_requests WHERE id = $1 RETURNING user_id",
-        reset_token
+        r#"SELECT
+            user_id,
+            name,
+            email,
+            password_reset_requests.created_at > NOW() - make_interval(secs => $2) AS "fresh!"
+        FROM
+            password_reset_requests INNER JOIN users ON (user_id = users.id)
+        WHERE
+            password_reset_requests.id = $1"#,
+        reset_token,
+        Duration::from_secs(config.token_lifetime).as_secs_f64(),
     )
-    .fetch_optional(&mut transaction)
+    .fetch_optional(pool)
     .await?;
 
     let Some(reset_request) = reset_request else {
         return Ok(Err(ResetError::TokenNotFound));
     };
+    if !reset_request.fresh {
+        // The expired token is gone for good
+        sqlx::query!(
+            "DELETE FROM password_reset_requests WHERE id = $1",
+            reset_token
+        )
+        .execute(pool)
+        .await?;
+        return Ok(Err(ResetError::TokenExpired));
+    }
+
+    let violations = policy
+        .check(new_password, &[&reset_request.name, &reset_request.email])
+        .await?;
+    if !violations.is_empty() {
+        return Ok(Err(ResetError::WeakPassword(violations)));
+    }
+
+    // Hashed before the transaction, so no connection is held while
+    // waiting for the hasher
+    let hash = hasher.hash(&new_password.0).await?;
+
+    let mut transaction = pool.begin().await?;
 
-    let argon2 = Argon2::default();
-    let salt = SaltString::generate(&mut OsRng);
-    let hash = argon2
-        .hash_password(new_password.0.as_bytes(), &salt)?
-        .to_string();
+    let consumed = sqlx::query!(
+        "DELETE FROM password_reset_requests WHERE id = $1",
+        reset_token
+    )
+    .execute(&mut transaction)
+    .await?
+    .rows_affected();
+    if consumed == 0 {
+        // Used by a concurrent reset in the meantime
+        return Ok(Err(ResetError::TokenNotFound));
+    }
 
     sqlx::query!(
         "UPDATE users
@@ -236,20 +311,45 @@ pub(crate) async fn reset_password(
     Ok(Ok(()))
 }
 
-#[tracing::instrument(skip(pool))]
+/// Whether the token exists and has not expired yet
+#[tracing::instrument(skip(pool, config))]
 pub(crate) async fn reset_token_is_valid(
     pool: &PgPool,
+    config: &ResetConfig,
     reset_token: &Uuid,
 ) -> Result<bool, Report> {
     Ok(sqlx::query!(
-        "SELECT * FROM password_reset_requests WHERE id = $1",
+        "SELECT * FROM password_reset_requests
+            WHERE id = $1 AND created_at > NOW() - make_interval(secs => $2)",
         reset_token,
+        Duration::from_secs(config.token_lifetime).as_secs_f64(),
     )
     .fetch_optional(pool)
     .await?
     .is_some())
 }
 
+/// Removes all expired reset tokens
+#[tracing::instrument(skip(pool, config))]
+pub(crate) async fn purge_expired_reset_requests(
+    pool: &PgPool,
+    config: &ResetConfig,
+) -> Result<(), Report> {
+    let purged = sqlx::query!(
+        "DELETE FROM password_reset_requests WHERE created_at < NOW() - make_interval(secs => $1)",
+        Duration::from_secs(config.token_lifetime).as_secs_f64(),
+    )
+    .execute(pool)
+    .await?
+    .rows_affected();
+
+    if purged > 0 {
+        info!("Purged {purged} expired reset requests");
+    }
+
+    Ok(())
+}
+
 #[tracing::instrument(skip(pool, redis_connection))]
 pub(crate) async fn remove_session(
     pool: &PgPool,
@@ -270,7 +370,7 @@ pub(crate) async fn get_user_from_session(
     id: &Uuid,
 ) -> Result<Option<User>, Report> {
     Ok(sqlx::query!(
-        "SELECT users.id, name, email FROM sessions INNER JOIN users ON (user_id=users.id) WHERE sessions.id = $1",
+        "SELECT users.id, name, email, locale FROM sessions INNER JOIN users ON (user_id=users.id) WHERE sessions.id = $1",
         id
     ).fetch_optional(pool)
     .await?
@@ -279,11 +379,15 @@ pub(crate) async fn get_user_from_session(
                 id: db_user.id,
                 name: db_user.name,
                 email: EMail(db_user.email),
+                locale: db_user.locale,
             }
         })
     )
 }
 
+/// Updates the owner of the session, returns `None` for unknown sessions
+///
+/// The email is not touched, see [UserUpdate].
 #[tracing::instrument(skip(pool))]
 pub(crate) async fn update_current_user(
     pool: &PgPool,
@@ -295,16 +399,17 @@ pub(crate) async fn update_current_user(
             users
         SET
             name = coalesce($2, name),
-            email = coalesce($3, email)
+            locale = coalesce($3, locale)
         FROM
             sessions
         WHERE
             sessions.id = $1
+            AND users.id = sessions.user_id
         RETURNING
-            users.id, name, email",
+            users.id, name, email, locale",
         session_id,
         update.name,
-        update.email,
+        update.locale,
     )
     .fetch_optional(pool)
     .await?
@@ -312,6 +417,7 @@ pub(crate) async fn update_current_user(
         id: db_user.id,
         name: db_user.name,
         email: EMail(db_user.email),
+        locale: db_user.locale,
     });
 
     Ok(user)
@@ -0,0 +1,322 @@
+//! Managing users as admin
+//!
+//! Whether a session belongs to an admin is checked by
+//! [AuthenticatedAdmin](crate::middlewares::admin::AuthenticatedAdmin).
+
+use color_eyre::Report;
+use redis::aio::Connection;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::{
+    hashing::Hasher,
+    types::{EMail, Password},
+};
+
+use super::{sessions::revoke_all_sessions, User};
+
+/// The known errors of user management
+#[derive(Debug)]
+pub(crate) enum AdminError {
+    /// There is no user with the given id
+    UserNotFound,
+    /// Another account uses the email
+    EmailTaken,
+}
+
+/// A user as shown to admins
+#[allow(clippy::missing_docs_in_private_items)]
+#[derive(Debug, Serialize)]
+pub(crate) struct AdminUserInfo {
+    id: Uuid,
+    name: String,
+    email: String,
+    locale: Option<String>,
+    is_admin: bool,
+    email_verified: bool,
+    disabled: bool,
+}
+
+/// A user created by an admin
+#[allow(clippy::missing_docs_in_private_items)]
+#[derive(Debug, Deserialize)]
+pub(crate) struct NewUser {
+    pub(crate) name: String,
+    pub(crate) email: EMail,
+    pub(crate) password: Password,
+    #[serde(default)]
+    pub(crate) is_admin: bool,
+}
+
+/// Partial update of a user by an admin
+///
+/// Unlike [UserUpdate](super::UserUpdate) this changes the email directly,
+/// it is unverified afterwards.
+#[allow(clippy::missing_docs_in_private_items)]
+#[derive(Debug, Deserialize)]
+pub(crate) struct AdminUserUpdate {
+    pub(crate) name: Option<String>,
+    pub(crate) email: Option<EMail>,
+    pub(crate) locale: Option<String>,
+    pub(crate) is_admin: Option<bool>,
+}
+
+/// Returns the user of the session, if they are an admin
+#[tracing::instrument(skip(pool))]
+pub(crate) async fn get_admin_from_session(
+    pool: &PgPool,
+    session_id: &Uuid,
+) -> Result<Option<User>, Report> {
+    Ok(sqlx::query!(
+        "SELECT users.id, name, email, locale
+            FROM sessions INNER JOIN users ON (user_id = users.id)
+            WHERE sessions.id = $1 AND is_admin AND disabled_at IS NULL",
+        session_id
+    )
+    .fetch_optional(pool)
+    .await?
+    .map(|db_user| User {
+        id: db_user.id,
+        name: db_user.name,
+        email: EMail(db_user.email),
+        locale: db_user.locale,
+    }))
+}
+
+/// Escapes `%`, `_` and `\` for use in a LIKE pattern
+fn escape_like(term: &str) -> String {
+    term.replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+/// Lists users ordered by name, optionally only those whose name or
+/// email contains `search` (case insensitive)
+#[tracing::instrument(skip(pool))]
+pub(crate) async fn list_users(
+    pool: &PgPool,
+    search: Option<&str>,
+    limit: i64,
+    offset: i64,
+) -> Result<Vec<AdminUserInfo>, Report> {
+    let pattern = search.map(|search| format!("%{}%", escape_like(search)));
+
+    Ok(sqlx::query_as!(
+        AdminUserInfo,
+        r#"SELECT
+            id,
+            name,
+            email,
+            locale,
+            is_admin,
+            email_verified_at IS NOT NULL AS "email_verified!",
+            disabled_at IS NOT NULL AS "disabled!"
+        FROM users
+        WHERE $1::text IS NULL OR name ILIKE $1 OR email ILIKE $1
+        ORDER BY name, email
+        LIMIT $2 OFFSET $3"#,
+        pattern,
+        limit,
+        offset,
+    )
+    .fetch_all(pool)
+    .await?)
+}
+
+/// Returns a single user
+#[tracing::instrument(skip(pool))]
+pub(crate) async fn get_user_info(
+    pool: &PgPool,
+    user_id: &Uuid,
+) -> Result<Option<AdminUserInfo>, Report> {
+    Ok(sqlx::query_as!(
+        AdminUserInfo,
+        r#"SELECT
+            id,
+            name,
+            email,
+            locale,
+            is_admin,
+            email_verified_at IS NOT NULL AS "email_verified!",
+            disabled_at IS NOT NULL AS "disabled!"
+        FROM users
+        WHERE id = $1"#,
+        user_id,
+    )
+    .fetch_optional(pool)
+    .await?)
+}
+
+/// Creates a user, bypassing the registration settings
+///
+/// The email counts as verified, since the admin vouches for it.
+#[tracing::instrument(skip(pool, hasher, user))]
+pub(crate) async fn create_user(
+    pool: &PgPool,
+    hasher: &Hasher,
+    user: &NewUser,
+) -> Result<Result<AdminUserInfo, AdminError>, Report> {
+    let hash = hasher.hash(&user.password.0).await?;
+
+    Ok(sqlx::query_as!(
+        AdminUserInfo,
+        r#"INSERT INTO
+            users (id, name, email, password, is_admin, email_verified_at)
+        VALUES
+            ($1, $2, $3, $4, $5, NOW())
+        ON CONFLICT (email) DO NOTHING
+        RETURNING
+            id,
+            name,
+            email,
+            locale,
+            is_admin,
+            email_verified_at IS NOT NULL AS "email_verified!",
+            disabled_at IS NOT NULL AS "disabled!""#,
+        Uuid::new_v4(),
+        user.name,
+        user.email.0,
+        hash,
+        user.is_admin,
+    )
+    .fetch_optional(pool)
+    .await?
+    .ok_or(AdminError::EmailTaken))
+}
+
+/// Updates a user
+#[tracing::instrument(skip(pool))]
+pub(crate) async fn update_user(
+    pool: &PgPool,
+    user_id: &Uuid,
+    update: &AdminUserUpdate,
+) -> Result<Result<AdminUserInfo, AdminError>, Report> {
+    let new_email = update.email.as_ref().map(|email| email.0.as_str());
+    if let Some(email) = new_email {
+        let taken = sqlx::query!(
+            "SELECT id FROM users WHERE email = $1 AND id <> $2",
+            email,
+            user_id
+        )
+        .fetch_optional(pool)
+        .await?
+        .is_some();
+        if taken {
+            return Ok(Err(AdminError::EmailTaken));
+        }
+    }
+
+    Ok(sqlx::query_as!(
+        AdminUserInfo,
+        r#"UPDATE
+            users
+        SET
+            name = coalesce($2, name),
+            email = coalesce($3, email),
+            locale = coalesce($4, locale),
+            is_admin = coalesce($5, is_admin),
+            email_verified_at = CASE
+                WHEN $3 IS NULL OR $3 = email THEN email_verified_at
+            END
+        WHERE
+            id = $1
+        RETURNING
+            id,
+            name,
+            email,
+            locale,
+            is_admin,
+            email_verified_at IS NOT NULL AS "email_verified!",
+            disabled_at IS NOT NULL AS "disabled!""#,
+        user_id,
+        update.name,
+        new_email,
+        update.locale,
+        update.is_admin,
+    )
+    .fetch_optional(pool)
+    .await?
+    .ok_or(AdminError::UserNotFound))
+}
+
+/// Disables or enables a user, returns whether the user exists
+///
+/// Disabled users can't log in, their sessions are revoked.
+#[tracing::instrument(skip(pool, redis_connection))]
+pub(crate) async fn set_disabled(
+    pool: &PgPool,
+    redis_connection: &mut Connection,
+    user_id: &Uuid,
+    disabled: bool,
+) -> Result<bool, Report> {
+    let found = sqlx::query!(
+        "UPDATE users
+            SET disabled_at = CASE WHEN $2 THEN coalesce(disabled_at, NOW()) END
+            WHERE id = $1",
+        user_id,
+        disabled,
+    )
+    .execute(pool)
+    .await?
+    .rows_affected()
+        > 0;
+
+    if found && disabled {
+        revoke_all_sessions(pool, redis_connection, user_id).await?;
+    }
+
+    Ok(found)
+}
+
+/// Deletes a user and everything belonging to them, returns whether
+/// the user existed
+#[tracing::instrument(skip(pool, redis_connection))]
+pub(crate) async fn delete_user(
+    pool: &PgPool,
+    redis_connection: &mut Connection,
+    user_id: &Uuid,
+) -> Result<bool, Report> {
+    // Sessions are deleted by the cascade anyway, but not from redis
+    revoke_all_sessions(pool, redis_connection, user_id).await?;
+
+    Ok(sqlx::query!("DELETE FROM users WHERE id = $1", user_id)
+        .execute(pool)
+        .await?
+        .rows_affected()
+        > 0)
+}
+
+/// Makes the current password unusable and revokes all sessions
+///
+/// The user has to reset their password to log in again. Returns the
+/// user, if they exist.
+#[tracing::instrument(skip(pool, redis_connection, hasher))]
+pub(crate) async fn invalidate_password(
+    pool: &PgPool,
+    redis_connection: &mut Connection,
+    hasher: &Hasher,
+    user_id: &Uuid,
+) -> Result<Option<User>, Report> {
+    // Nobody knows this password, so it can't be used to log in
+    let hash = hasher.hash(&Uuid::new_v4().to_string()).await?;
+
+    let Some(user) = sqlx::query!(
+        "UPDATE users SET password = $2 WHERE id = $1 RETURNING id, name, email, locale",
+        user_id,
+        hash,
+    )
+    .fetch_optional(pool)
+    .await? else {
+        return Ok(None);
+    };
+
+    revoke_all_sessions(pool, redis_connection, user_id).await?;
+
+    Ok(Some(User {
+        id: user.id,
+        name: user.name,
+        email: EMail(user.email),
+        locale: user.locale,
+    }))
+}
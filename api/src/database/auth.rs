@@ -4,41 +4,65 @@
 //! interact with the passwords saved in the database.
 //! Limiting this to this file allows easier changes
 //! to hashing algorithms, security updates and helps
-//! hiding passwords from attackers
+//! hiding passwords from attackers. The hashing itself
+//! is done by [Hasher].
+
+use std::time::Duration;
 
-use argon2::{password_hash, Argon2, PasswordHash, PasswordVerifier};
 use color_eyre::Report;
+use redis::aio::Connection;
 use serde::{Deserialize, Serialize};
-use sqlx::PgPool;
+use sqlx::{PgPool, Postgres, Transaction};
+use tracing::{debug, info};
 use uuid::Uuid;
+use webauthn_rs::prelude::{PublicKeyCredential, RequestChallengeResponse, Webauthn};
+
+use crate::{
+    hashing::Hasher,
+    middlewares::client_info::ClientInfo,
+    settings::{Config, LockoutConfig, VerificationConfig},
+    types::{EMail, Password},
+};
+
+use super::{
+    get_user_by_email, get_user_by_id,
+    lockout::LoginAttempt,
+    recovery_codes,
+    totp::{self, TotpError},
+    webauthn::{self, PasskeyError},
+    User,
+};
 
-use crate::types::{EMail, Password};
+/// How long a [PendingLogin] can be completed, in minutes
+const CHALLENGE_LIFETIME_MINUTES: i32 = 5;
 
-use super::User;
+/// How many wrong second factors are accepted before a [PendingLogin]
+/// is invalidated and the password has to be entered again
+const MAX_CHALLENGE_ATTEMPTS: i32 = 5;
 
 /// Create a new session
 ///
 /// Does not check any credentials, use [check_credentials_and_get_user]
 /// for that.
 ///
-/// Note that this currently deletes an old session, which probably does
-/// not make sense in a multi-device scenario, but we will think about
-/// this once we are ready for multi-device.
+/// Every login creates its own session, so a user can be logged in
+/// on multiple devices, see [sessions](super::sessions) for managing them.
 #[tracing::instrument(skip(pool))]
-async fn create_new_session(pool: &PgPool, user_id: &Uuid) -> Result<Uuid, Report> {
+async fn create_new_session(
+    pool: &PgPool,
+    user_id: &Uuid,
+    client: &ClientInfo,
+) -> Result<Uuid, Report> {
     let session_id = Uuid::new_v4();
     sqlx::query!(
         "INSERT INTO
-            sessions (id, user_id)
+            sessions (id, user_id, user_agent, ip_address)
         VALUES
-            ($1, $2)
-        ON CONFLICT(user_id) DO
-            UPDATE SET
-                id = EXCLUDED.id,
-                user_id = EXCLUDED.user_id,
-                created_at = EXCLUDED.created_at",
+            ($1, $2, $3, $4)",
         session_id,
         user_id,
+        client.user_agent,
+        client.ip_address,
     )
     .execute(pool)
     .await?;
@@ -50,11 +74,31 @@ async fn create_new_session(pool: &PgPool, user_id: &Uuid) -> Result<Uuid, Repor
 pub(crate) enum LoginError {
     /// User has not been found
     ///
-    /// Currently user enumeration is trivially possible, should it?
-    /// (Return "UserNotFound" allows for better UX), maybe configurable
+    /// Allows user enumeration (for better UX), unless
+    /// [AppConfig::prevent_user_enumeration] is set, then logins return
+    /// [LoginError::InvalidCredentials] instead.
     UserNotFound,
     /// Currently equal to wrong password.
     InvalidCredentials,
+    /// The login challenge does not exist, has expired or too many wrong
+    /// second factors were submitted
+    ChallengeNotFound,
+    /// The submitted second factor was wrong
+    InvalidSecondFactor,
+    /// A login with a passkey was requested, but the user has none
+    NoPasskeys,
+    /// The credentials are correct, but logins require a verified
+    /// email, see [VerificationConfig]
+    EmailNotVerified,
+    /// The account was disabled by an admin
+    UserDisabled,
+    /// The magic link does not exist, it was used or replaced already
+    MagicLinkNotFound,
+    /// The magic link exists, but has expired
+    MagicLinkExpired,
+    /// Too many wrong second factors for the user, contains the seconds
+    /// until the next try, see [lockout](super::lockout)
+    TooManyAttempts(u64),
 }
 
 /// Unhashed Login Credentials
@@ -72,40 +116,108 @@ pub(crate) struct Credentials {
 /// The double result is used as always:
 /// The outside result contains unexpected errors, the inner the expected ones
 /// This function properly differentiates between a user not existing and
-/// a credentials being wrong, see [LoginError] for details.
-#[tracing::instrument]
+/// a credentials being wrong, unless `hide_unknown_users` is set, see
+/// [LoginError] for details.
+///
+/// A correct password stored with weaker parameters than configured is
+/// rehashed, see [Hasher::needs_rehash].
+#[tracing::instrument(skip(pool, hasher))]
 async fn check_credentials_and_get_user(
     pool: &PgPool,
+    hasher: &Hasher,
     credentials: Credentials,
+    hide_unknown_users: bool,
 ) -> Result<Result<User, LoginError>, Report> {
     let Some(saved_user) = sqlx::query!("SELECT * FROM users WHERE email=$1", credentials.email.0)
         .fetch_optional(pool)
         .await? else {
+        if hide_unknown_users {
+            // Take as long as a wrong password would
+            hasher.verify_dummy(&credentials.password.0).await?;
+
+            return Ok(Err(LoginError::InvalidCredentials));
+        }
+
         // Expected error, so outer Ok
         return Ok(Err(LoginError::UserNotFound));
     };
 
-    let hash = PasswordHash::new(&saved_user.password)?;
-
     // All this double result stuff can be confusing, but the basic idea is
     // that we only return an outer error if something unexpected goes wrong
     // So InvalidCredentials are wrapped in Ok (since DB etc. did not have a problem),
     // but are still an Err
-    let user_or_error = Argon2::default()
-        .verify_password(credentials.password.0.as_bytes(), &hash)
-        .map(|_| {
-            Ok(User {
-                id: saved_user.id,
-                name: saved_user.name,
-                email: EMail(saved_user.email),
-            })
-        })
-        .or_else(|e| match e {
-            password_hash::Error::Password => Ok(Err(LoginError::InvalidCredentials)),
-            e => Err(e),
-        })?;
-
-    Ok(user_or_error)
+    if !hasher
+        .verify(&credentials.password.0, &saved_user.password)
+        .await?
+    {
+        return Ok(Err(LoginError::InvalidCredentials));
+    }
+
+    if hasher.needs_rehash(&saved_user.password) {
+        debug!("Rehashing password of {}", saved_user.id);
+        let hash = hasher.hash(&credentials.password.0).await?;
+        // Only if the password was not changed in the meantime
+        sqlx::query!(
+            "UPDATE users SET password = $1 WHERE id = $2 AND password = $3",
+            hash,
+            saved_user.id,
+            saved_user.password,
+        )
+        .execute(pool)
+        .await?;
+    }
+
+    Ok(Ok(User {
+        id: saved_user.id,
+        name: saved_user.name,
+        email: EMail(saved_user.email),
+        locale: saved_user.locale,
+    }))
+}
+
+/// Changes the password of `user` if `current_password` is correct
+///
+/// Pending password resets of the user are cancelled, since they
+/// were most likely requested because the old password was forgotten.
+/// The new password is not checked against the password policy, that
+/// is up to the caller.
+#[tracing::instrument(skip_all)]
+pub(crate) async fn change_password(
+    pool: &PgPool,
+    hasher: &Hasher,
+    user: &User,
+    current_password: Password,
+    new_password: &Password,
+) -> Result<Result<(), LoginError>, Report> {
+    let credentials = Credentials {
+        email: EMail(user.email.0.clone()),
+        password: current_password,
+    };
+    if let Err(err) = check_credentials_and_get_user(pool, hasher, credentials, false).await? {
+        return Ok(Err(err));
+    }
+
+    let hash = hasher.hash(&new_password.0).await?;
+
+    let mut transaction = pool.begin().await?;
+
+    sqlx::query!(
+        "UPDATE users SET password = $1 WHERE id = $2",
+        hash,
+        user.id
+    )
+    .execute(&mut transaction)
+    .await?;
+    sqlx::query!(
+        "DELETE FROM password_reset_requests WHERE user_id = $1",
+        user.id
+    )
+    .execute(&mut transaction)
+    .await?;
+
+    transaction.commit().await?;
+
+    Ok(Ok(()))
 }
 
 /// A successfully created session
@@ -122,19 +234,560 @@ pub(crate) struct Session {
     user: User,
 }
 
+/// A second factor which can be used to complete a [PendingLogin]
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum SecondFactor {
+    /// A code of an authenticator app, see [totp]
+    Totp,
+    /// A passkey or security key, see [webauthn]
+    Webauthn,
+    /// One of the user's recovery codes, see [recovery_codes]
+    RecoveryCode,
+}
+
+/// The password was correct, but the user still has to provide
+/// one of the listed second factors
+#[derive(Serialize, Debug)]
+pub(crate) struct PendingLogin {
+    /// Has to be sent together with the second factor
+    challenge_id: Uuid,
+    /// The second factors the user has set up
+    methods: Vec<SecondFactor>,
+}
+
+/// Result of a successful password check
+///
+/// Serialized with a `status` field, so a client can tell if the login
+/// is complete.
+#[derive(Serialize, Debug)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub(crate) enum LoginOutcome {
+    /// No second factor required, the session is ready to be used
+    LoggedIn(Session),
+    /// A second factor is required to receive a [Session]
+    SecondFactorRequired(PendingLogin),
+}
+
+/// Whether the user may log in at all
+///
+/// Disabled accounts never may, unverified ones only if
+/// [VerificationConfig::required_for_login] is false.
+async fn check_may_log_in(
+    pool: &PgPool,
+    config: &VerificationConfig,
+    user_id: &Uuid,
+) -> Result<Result<(), LoginError>, Report> {
+    let Some(user) = sqlx::query!(
+        "SELECT email_verified_at, disabled_at FROM users WHERE id = $1",
+        user_id
+    )
+    .fetch_optional(pool)
+    .await? else {
+        return Ok(Err(LoginError::UserNotFound));
+    };
+
+    if user.disabled_at.is_some() {
+        return Ok(Err(LoginError::UserDisabled));
+    }
+    if config.required_for_login && user.email_verified_at.is_none() {
+        return Ok(Err(LoginError::EmailNotVerified));
+    }
+
+    Ok(Ok(()))
+}
+
+/// Returns all second factors the user has set up
+///
+/// Recovery codes are only listed if there is another second factor,
+/// on their own they don't make a login require one.
+pub(crate) async fn second_factors(
+    pool: &PgPool,
+    user_id: &Uuid,
+) -> Result<Vec<SecondFactor>, Report> {
+    let mut methods = Vec::new();
+    if totp::is_enabled(pool, user_id).await? {
+        methods.push(SecondFactor::Totp);
+    }
+    if webauthn::has_passkeys(pool, user_id).await? {
+        methods.push(SecondFactor::Webauthn);
+    }
+    if !methods.is_empty() && recovery_codes::remaining(pool, user_id).await? > 0 {
+        methods.push(SecondFactor::RecoveryCode);
+    }
+
+    Ok(methods)
+}
+
+/// Remembers that the user has entered a correct password
+#[tracing::instrument(skip(pool))]
+async fn create_login_challenge(pool: &PgPool, user_id: &Uuid) -> Result<Uuid, Report> {
+    let challenge_id = Uuid::new_v4();
+    sqlx::query!(
+        "INSERT INTO login_challenges (id, user_id) VALUES ($1, $2)",
+        challenge_id,
+        user_id,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(challenge_id)
+}
+
+/// Returns the user of a login challenge which is still usable
+#[tracing::instrument(skip(pool))]
+async fn get_challenge_user(pool: &PgPool, challenge_id: &Uuid) -> Result<Option<User>, Report> {
+    Ok(sqlx::query!(
+        "SELECT users.id, name, email, locale
+            FROM login_challenges INNER JOIN users ON (user_id = users.id)
+            WHERE
+                login_challenges.id = $1
+                AND failed_attempts < $2
+                AND login_challenges.created_at > NOW() - make_interval(mins => $3)",
+        challenge_id,
+        MAX_CHALLENGE_ATTEMPTS,
+        CHALLENGE_LIFETIME_MINUTES,
+    )
+    .fetch_optional(pool)
+    .await?
+    .map(|db_user| User {
+        id: db_user.id,
+        name: db_user.name,
+        email: EMail(db_user.email),
+        locale: db_user.locale,
+    }))
+}
+
+/// Counts a wrong second factor against the challenge
+#[tracing::instrument(skip(pool))]
+async fn record_failed_challenge_attempt(pool: &PgPool, challenge_id: &Uuid) -> Result<(), Report> {
+    sqlx::query!(
+        "UPDATE login_challenges SET failed_attempts = failed_attempts + 1 WHERE id = $1",
+        challenge_id
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Deletes the challenge if it is still usable, returns whether it was
+///
+/// The row stays locked until `transaction` ends, so concurrent requests
+/// for the same challenge wait for the outcome.
+#[tracing::instrument(skip(transaction))]
+async fn consume_login_challenge(
+    transaction: &mut Transaction<'_, Postgres>,
+    challenge_id: &Uuid,
+) -> Result<bool, Report> {
+    Ok(sqlx::query!(
+        "DELETE FROM login_challenges
+            WHERE
+                id = $1
+                AND failed_attempts < $2
+                AND created_at > NOW() - make_interval(mins => $3)",
+        challenge_id,
+        MAX_CHALLENGE_ATTEMPTS,
+        CHALLENGE_LIFETIME_MINUTES,
+    )
+    .execute(transaction)
+    .await?
+    .rows_affected()
+        > 0)
+}
+
+/// Counts a guessable second factor of `user_id` against the lockout
+///
+/// The limit of a single challenge is not enough, since everybody who
+/// knows the password can start as many challenges as they like.
+async fn reserve_second_factor_attempt(
+    redis_connection: &mut Connection,
+    config: &LockoutConfig,
+    user_id: &Uuid,
+) -> Result<Result<LoginAttempt, LoginError>, Report> {
+    let mut attempt = LoginAttempt::second_factor(config, user_id);
+    Ok(match attempt.reserve(redis_connection, config).await? {
+        Some(retry_after) => Err(LoginError::TooManyAttempts(retry_after)),
+        None => Ok(attempt),
+    })
+}
+
+/// Removes login challenges which can't be completed anymore
+#[tracing::instrument(skip(pool))]
+pub(crate) async fn purge_expired_login_challenges(pool: &PgPool) -> Result<(), Report> {
+    let purged = sqlx::query!(
+        "DELETE FROM login_challenges WHERE created_at < NOW() - make_interval(mins => $1)",
+        CHALLENGE_LIFETIME_MINUTES,
+    )
+    .execute(pool)
+    .await?
+    .rows_affected();
+
+    if purged > 0 {
+        info!("Purged {purged} expired login challenges");
+    }
+
+    Ok(())
+}
+
+/// Consumes the challenge and creates the session
+///
+/// Fails with [LoginError::ChallengeNotFound] if another request
+/// consumed the challenge in the meantime.
+#[tracing::instrument(skip(pool))]
+async fn finish_login_challenge(
+    pool: &PgPool,
+    challenge_id: &Uuid,
+    user: User,
+    client: &ClientInfo,
+) -> Result<Result<Session, LoginError>, Report> {
+    let deleted = sqlx::query!("DELETE FROM login_challenges WHERE id = $1", challenge_id)
+        .execute(pool)
+        .await?;
+    if deleted.rows_affected() == 0 {
+        return Ok(Err(LoginError::ChallengeNotFound));
+    }
+
+    let session_id = create_new_session(pool, &user.id, client).await?;
+
+    Ok(Ok(Session { user, session_id }))
+}
+
 /// Check credentials & create session
 ///
-#[tracing::instrument]
+/// If the user has set up a second factor no session is created,
+/// instead a [PendingLogin] is returned which has to be completed
+/// using e.g. [complete_login_with_totp].
+#[tracing::instrument(skip(pool, config, hasher))]
 pub(crate) async fn login_user(
     pool: &PgPool,
+    config: &Config,
+    hasher: &Hasher,
     credentials: Credentials,
-) -> Result<Result<Session, LoginError>, Report> {
-    let user = match check_credentials_and_get_user(pool, credentials).await? {
+    client: &ClientInfo,
+) -> Result<Result<LoginOutcome, LoginError>, Report> {
+    let user = match check_credentials_and_get_user(
+        pool,
+        hasher,
+        credentials,
+        config.app.prevent_user_enumeration,
+    )
+    .await?
+    {
         Ok(user) => user,
         Err(err) => return Ok(Err(err)),
     };
+    if let Err(err) = check_may_log_in(pool, &config.verification, &user.id).await? {
+        return Ok(Err(err));
+    }
+
+    Ok(Ok(start_session(pool, user, client).await?))
+}
+
+/// Creates the session of a user whose first factor was correct, or a
+/// [PendingLogin] if they have a second factor
+async fn start_session(
+    pool: &PgPool,
+    user: User,
+    client: &ClientInfo,
+) -> Result<LoginOutcome, Report> {
+    let methods = second_factors(pool, &user.id).await?;
+    if !methods.is_empty() {
+        let challenge_id = create_login_challenge(pool, &user.id).await?;
+
+        return Ok(LoginOutcome::SecondFactorRequired(PendingLogin {
+            challenge_id,
+            methods,
+        }));
+    }
+
+    let session_id = create_new_session(pool, &user.id, client).await?;
+
+    Ok(LoginOutcome::LoggedIn(Session { user, session_id }))
+}
+
+/// Logs in using the token of a magic link instead of a password
+///
+/// The link is consumed, even if the login fails afterwards. Disabled
+/// and unverified users are refused just like on [login_user], and
+/// users with a second factor still have to provide it.
+#[tracing::instrument(skip(pool, config))]
+pub(crate) async fn login_with_magic_link(
+    pool: &PgPool,
+    config: &Config,
+    token: &Uuid,
+    client: &ClientInfo,
+) -> Result<Result<LoginOutcome, LoginError>, Report> {
+    let Some(link) = sqlx::query!(
+        r#"DELETE FROM
+            magic_links
+        WHERE
+            id = $1
+        RETURNING
+            user_id,
+            created_at > NOW() - make_interval(secs => $2) AS "fresh!""#,
+        token,
+        Duration::from_secs(config.magic_link.token_lifetime).as_secs_f64(),
+    )
+    .fetch_optional(pool)
+    .await? else {
+        return Ok(Err(LoginError::MagicLinkNotFound));
+    };
+    if !link.fresh {
+        return Ok(Err(LoginError::MagicLinkExpired));
+    }
+
+    let Some(user) = get_user_by_id(pool, &link.user_id).await? else {
+        return Ok(Err(LoginError::UserNotFound));
+    };
+    if let Err(err) = check_may_log_in(pool, &config.verification, &user.id).await? {
+        return Ok(Err(err));
+    }
+
+    Ok(Ok(start_session(pool, user, client).await?))
+}
+
+/// Completes a [PendingLogin] using a TOTP code
+///
+/// Wrong codes count against the challenge and against the user, see
+/// [reserve_second_factor_attempt].
+#[tracing::instrument(skip(pool, redis_connection, config, code))]
+pub(crate) async fn complete_login_with_totp(
+    pool: &PgPool,
+    redis_connection: &mut Connection,
+    config: &LockoutConfig,
+    challenge_id: &Uuid,
+    code: &str,
+    client: &ClientInfo,
+) -> Result<Result<Session, LoginError>, Report> {
+    let Some(user) = get_challenge_user(pool, challenge_id).await? else {
+        return Ok(Err(LoginError::ChallengeNotFound));
+    };
+    let attempt = match reserve_second_factor_attempt(redis_connection, config, &user.id).await? {
+        Ok(attempt) => attempt,
+        Err(err) => return Ok(Err(err)),
+    };
+
+    match totp::verify_code(pool, &user.id, code).await? {
+        Ok(()) => {
+            attempt.succeeded(redis_connection, config).await?;
+            finish_login_challenge(pool, challenge_id, user, client).await
+        }
+        Err(TotpError::InvalidCode | TotpError::NotEnrolled | TotpError::AlreadyEnabled) => {
+            attempt.failed(redis_connection, config).await?;
+            record_failed_challenge_attempt(pool, challenge_id).await?;
+            Ok(Err(LoginError::InvalidSecondFactor))
+        }
+    }
+}
+
+/// Completes a [PendingLogin] using a recovery code
+///
+/// The code can't be used again. It is only redeemed together with
+/// consuming the challenge, so a code is never used up by a login that
+/// fails anyway. Returns the user as well, so they can be told that a
+/// code was used.
+#[tracing::instrument(skip(pool, redis_connection, config, code))]
+pub(crate) async fn complete_login_with_recovery_code(
+    pool: &PgPool,
+    redis_connection: &mut Connection,
+    config: &LockoutConfig,
+    challenge_id: &Uuid,
+    code: &str,
+    client: &ClientInfo,
+) -> Result<Result<(Session, User), LoginError>, Report> {
+    let Some(user) = get_challenge_user(pool, challenge_id).await? else {
+        return Ok(Err(LoginError::ChallengeNotFound));
+    };
+    let attempt = match reserve_second_factor_attempt(redis_connection, config, &user.id).await? {
+        Ok(attempt) => attempt,
+        Err(err) => return Ok(Err(err)),
+    };
+
+    let mut transaction = pool.begin().await?;
+    if !consume_login_challenge(&mut transaction, challenge_id).await? {
+        attempt.cancelled(redis_connection, config).await?;
+        return Ok(Err(LoginError::ChallengeNotFound));
+    }
+    if !recovery_codes::redeem(&mut transaction, &user.id, code).await? {
+        // Keeps the challenge, and releases its lock before counting the attempt
+        transaction.rollback().await?;
+        attempt.failed(redis_connection, config).await?;
+        record_failed_challenge_attempt(pool, challenge_id).await?;
+        return Ok(Err(LoginError::InvalidSecondFactor));
+    }
+    transaction.commit().await?;
+    attempt.succeeded(redis_connection, config).await?;
+
+    let session_id = create_new_session(pool, &user.id, client).await?;
+
+    Ok(Ok((
+        Session {
+            user: user.clone(),
+            session_id,
+        },
+        user,
+    )))
+}
+
+/// A started WebAuthn authentication
+#[derive(Serialize, Debug)]
+pub(crate) struct PasskeyChallenge {
+    /// Has to be sent together with the response of the authenticator
+    ceremony_id: Uuid,
+    /// Options for `navigator.credentials.get()`
+    options: RequestChallengeResponse,
+}
+
+/// Maps errors of a WebAuthn ceremony during a login
+///
+/// `rejected` is returned if the authenticator response was invalid.
+fn passkey_login_error(error: PasskeyError, rejected: LoginError) -> LoginError {
+    match error {
+        PasskeyError::CeremonyNotFound => LoginError::ChallengeNotFound,
+        PasskeyError::NoPasskeys => LoginError::NoPasskeys,
+        PasskeyError::Rejected(e) => {
+            debug!("WebAuthn assertion rejected: {e}");
+            rejected
+        }
+    }
+}
+
+/// Starts a passwordless login using a passkey
+///
+/// If [AppConfig::prevent_user_enumeration] is set, unknown users and
+/// users without passkeys get a challenge as well, which can't be
+/// completed.
+#[tracing::instrument(skip(pool, config, redis_connection, webauthn))]
+pub(crate) async fn begin_passkey_login(
+    pool: &PgPool,
+    config: &Config,
+    redis_connection: &mut Connection,
+    webauthn: &Webauthn,
+    email: &EMail,
+) -> Result<Result<PasskeyChallenge, LoginError>, Report> {
+    let ceremony_id = Uuid::new_v4();
+    let result = match get_user_by_email(pool, email).await? {
+        Some(user) => webauthn::begin_authentication(
+            pool,
+            redis_connection,
+            webauthn,
+            &format!("webauthn_login:{ceremony_id}"),
+            &user.id,
+        )
+        .await?
+        .map_err(|e| passkey_login_error(e, LoginError::InvalidCredentials)),
+        None => Err(LoginError::UserNotFound),
+    };
+
+    let options = match result {
+        Ok(options) => options,
+        Err(LoginError::UserNotFound | LoginError::NoPasskeys)
+            if config.app.prevent_user_enumeration =>
+        {
+            webauthn::dummy_authentication(redis_connection, &config.webauthn, &email.0).await?
+        }
+        Err(err) => return Ok(Err(err)),
+    };
 
-    let session_id = create_new_session(pool, &user.id).await?;
+    Ok(Ok(PasskeyChallenge {
+        ceremony_id,
+        options,
+    }))
+}
+
+/// Completes a passwordless login, see [begin_passkey_login]
+///
+/// A passkey already combines possession and user verification,
+/// so no further factor is required.
+#[tracing::instrument(skip(pool, config, redis_connection, webauthn, credential))]
+pub(crate) async fn complete_passkey_login(
+    pool: &PgPool,
+    config: &VerificationConfig,
+    redis_connection: &mut Connection,
+    webauthn: &Webauthn,
+    ceremony_id: &Uuid,
+    credential: &PublicKeyCredential,
+    client: &ClientInfo,
+) -> Result<Result<Session, LoginError>, Report> {
+    let user_id = match webauthn::finish_authentication(
+        pool,
+        redis_connection,
+        webauthn,
+        &format!("webauthn_login:{ceremony_id}"),
+        credential,
+    )
+    .await?
+    {
+        Ok(user_id) => user_id,
+        Err(e) => return Ok(Err(passkey_login_error(e, LoginError::InvalidCredentials))),
+    };
+
+    let Some(user) = get_user_by_id(pool, &user_id).await? else {
+        return Ok(Err(LoginError::UserNotFound));
+    };
+    if let Err(err) = check_may_log_in(pool, config, &user.id).await? {
+        return Ok(Err(err));
+    }
+
+    let session_id = create_new_session(pool, &user.id, client).await?;
 
     Ok(Ok(Session { user, session_id }))
 }
+
+/// Starts the WebAuthn ceremony to complete a [PendingLogin]
+#[tracing::instrument(skip(pool, redis_connection, webauthn))]
+pub(crate) async fn begin_webauthn_second_factor(
+    pool: &PgPool,
+    redis_connection: &mut Connection,
+    webauthn: &Webauthn,
+    challenge_id: &Uuid,
+) -> Result<Result<RequestChallengeResponse, LoginError>, Report> {
+    let Some(user) = get_challenge_user(pool, challenge_id).await? else {
+        return Ok(Err(LoginError::ChallengeNotFound));
+    };
+
+    Ok(webauthn::begin_authentication(
+        pool,
+        redis_connection,
+        webauthn,
+        &format!("webauthn_second_factor:{challenge_id}"),
+        &user.id,
+    )
+    .await?
+    .map_err(|e| passkey_login_error(e, LoginError::InvalidSecondFactor)))
+}
+
+/// Completes a [PendingLogin] using a passkey or security key
+#[tracing::instrument(skip(pool, redis_connection, webauthn, credential))]
+pub(crate) async fn complete_login_with_webauthn(
+    pool: &PgPool,
+    redis_connection: &mut Connection,
+    webauthn: &Webauthn,
+    challenge_id: &Uuid,
+    credential: &PublicKeyCredential,
+    client: &ClientInfo,
+) -> Result<Result<Session, LoginError>, Report> {
+    let Some(user) = get_challenge_user(pool, challenge_id).await? else {
+        return Ok(Err(LoginError::ChallengeNotFound));
+    };
+
+    match webauthn::finish_authentication(
+        pool,
+        redis_connection,
+        webauthn,
+        &format!("webauthn_second_factor:{challenge_id}"),
+        credential,
+    )
+    .await?
+    {
+        Ok(user_id) if user_id == user.id => {
+            finish_login_challenge(pool, challenge_id, user, client).await
+        }
+        Ok(_) | Err(PasskeyError::Rejected(_)) => {
+            record_failed_challenge_attempt(pool, challenge_id).await?;
+            Ok(Err(LoginError::InvalidSecondFactor))
+        }
+        Err(e) => Ok(Err(passkey_login_error(e, LoginError::InvalidSecondFactor))),
+    }
+}
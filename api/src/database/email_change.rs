@@ -0,0 +1,252 @@
+//! Changing the email of a user
+//!
+//! Changing the email directly would let anyone with a stolen session
+//! take over the account (by resetting the password afterwards). So a
+//! change is only pending until the new address confirms it, and the
+//! old address can revert it until [VerificationConfig::revert_lifetime]
+//! has passed, even if it was already confirmed.
+
+use std::time::Duration;
+
+use color_eyre::Report;
+use redis::aio::Connection;
+use sqlx::{PgPool, Postgres, Transaction};
+use tracing::info;
+use uuid::Uuid;
+
+use crate::{settings::VerificationConfig, types::EMail};
+
+use super::{sessions::revoke_all_sessions, User};
+
+/// The known errors of email changes
+#[derive(Debug)]
+pub(crate) enum EmailChangeError {
+    /// The token does not exist, was already used or the email was
+    /// changed otherwise in the meantime
+    TokenNotFound,
+    /// The token exists, but is too old
+    TokenExpired,
+    /// Another account uses the email
+    EmailTaken,
+}
+
+/// The tokens of a new pending change
+pub(crate) struct PendingEmailChange {
+    /// Confirms the change, sent to the new address
+    pub(crate) confirm_token: Uuid,
+    /// Reverts the change, sent to the old address
+    pub(crate) revert_token: Uuid,
+}
+
+/// Whether an account other than `user_id` uses `email`
+async fn email_taken(
+    transaction: &mut Transaction<'_, Postgres>,
+    email: &str,
+    user_id: &Uuid,
+) -> Result<bool, Report> {
+    Ok(sqlx::query!(
+        "SELECT id FROM users WHERE email = $1 AND id <> $2",
+        email,
+        user_id
+    )
+    .fetch_optional(transaction)
+    .await?
+    .is_some())
+}
+
+/// Starts changing the email of `user` to `new_email`
+///
+/// Replaces other unconfirmed changes of the user, confirmed changes
+/// are kept so they can still be reverted.
+#[tracing::instrument(skip(pool))]
+pub(crate) async fn request_email_change(
+    pool: &PgPool,
+    user: &User,
+    new_email: &EMail,
+) -> Result<Result<PendingEmailChange, EmailChangeError>, Report> {
+    let mut transaction = pool.begin().await?;
+
+    if email_taken(&mut transaction, &new_email.0, &user.id).await? {
+        return Ok(Err(EmailChangeError::EmailTaken));
+    }
+
+    sqlx::query!(
+        "DELETE FROM email_changes WHERE user_id = $1 AND confirmed_at IS NULL",
+        user.id
+    )
+    .execute(&mut transaction)
+    .await?;
+
+    let change = PendingEmailChange {
+        confirm_token: Uuid::new_v4(),
+        revert_token: Uuid::new_v4(),
+    };
+    sqlx::query!(
+        "INSERT INTO
+            email_changes (id, revert_token, user_id, old_email, new_email)
+        VALUES
+            ($1, $2, $3, $4, $5)",
+        change.confirm_token,
+        change.revert_token,
+        user.id,
+        user.email.0,
+        new_email.0,
+    )
+    .execute(&mut transaction)
+    .await?;
+
+    transaction.commit().await?;
+
+    Ok(Ok(change))
+}
+
+/// Commits a pending change, the new email counts as verified
+#[tracing::instrument(skip(pool, config))]
+pub(crate) async fn confirm_email_change(
+    pool: &PgPool,
+    config: &VerificationConfig,
+    token: &Uuid,
+) -> Result<Result<(), EmailChangeError>, Report> {
+    let mut transaction = pool.begin().await?;
+
+    let Some(change) = sqlx::query!(
+        r#"UPDATE
+            email_changes
+        SET
+            confirmed_at = NOW()
+        WHERE
+            id = $1 AND confirmed_at IS NULL
+        RETURNING
+            user_id,
+            old_email,
+            new_email,
+            created_at > NOW() - make_interval(secs => $2) AS "fresh!""#,
+        token,
+        Duration::from_secs(config.token_lifetime).as_secs_f64(),
+    )
+    .fetch_optional(&mut transaction)
+    .await? else {
+        return Ok(Err(EmailChangeError::TokenNotFound));
+    };
+    if !change.fresh {
+        return Ok(Err(EmailChangeError::TokenExpired));
+    }
+    if email_taken(&mut transaction, &change.new_email, &change.user_id).await? {
+        return Ok(Err(EmailChangeError::EmailTaken));
+    }
+
+    let changed = sqlx::query!(
+        "UPDATE users
+            SET email = $3, email_verified_at = NOW()
+            WHERE id = $1 AND email = $2",
+        change.user_id,
+        change.old_email,
+        change.new_email,
+    )
+    .execute(&mut transaction)
+    .await?
+    .rows_affected();
+    if changed == 0 {
+        // The email was changed by another request in the meantime
+        return Ok(Err(EmailChangeError::TokenNotFound));
+    }
+
+    transaction.commit().await?;
+
+    Ok(Ok(()))
+}
+
+/// Cancels a pending change or reverts a confirmed one
+///
+/// Restores the old email (which counts as verified, since the token
+/// was sent to it), drops all other changes of the user and revokes
+/// every session, since the change was most likely done by an attacker.
+/// Password reset requests and magic links are dropped as well, they
+/// may have been sent to the attacker's address.
+#[tracing::instrument(skip(pool, redis_connection, config))]
+pub(crate) async fn revert_email_change(
+    pool: &PgPool,
+    redis_connection: &mut Connection,
+    config: &VerificationConfig,
+    revert_token: &Uuid,
+) -> Result<Result<(), EmailChangeError>, Report> {
+    let mut transaction = pool.begin().await?;
+
+    let Some(change) = sqlx::query!(
+        r#"DELETE FROM
+            email_changes
+        WHERE
+            revert_token = $1
+        RETURNING
+            user_id,
+            old_email,
+            created_at > NOW() - make_interval(secs => $2) AS "fresh!""#,
+        revert_token,
+        Duration::from_secs(config.revert_lifetime).as_secs_f64(),
+    )
+    .fetch_optional(&mut transaction)
+    .await? else {
+        return Ok(Err(EmailChangeError::TokenNotFound));
+    };
+    if !change.fresh {
+        // Commit, so the expired token is gone for good
+        transaction.commit().await?;
+        return Ok(Err(EmailChangeError::TokenExpired));
+    }
+    if email_taken(&mut transaction, &change.old_email, &change.user_id).await? {
+        return Ok(Err(EmailChangeError::EmailTaken));
+    }
+
+    sqlx::query!(
+        "DELETE FROM email_changes WHERE user_id = $1",
+        change.user_id
+    )
+    .execute(&mut transaction)
+    .await?;
+    sqlx::query!(
+        "UPDATE users
+            SET email = $2, email_verified_at = NOW()
+            WHERE id = $1",
+        change.user_id,
+        change.old_email,
+    )
+    .execute(&mut transaction)
+    .await?;
+    sqlx::query!(
+        "DELETE FROM password_reset_requests WHERE user_id = $1",
+        change.user_id
+    )
+    .execute(&mut transaction)
+    .await?;
+    sqlx::query!("DELETE FROM magic_links WHERE user_id = $1", change.user_id)
+        .execute(&mut transaction)
+        .await?;
+
+    transaction.commit().await?;
+
+    let revoked = revoke_all_sessions(pool, redis_connection, &change.user_id).await?;
+    info!("Reverted email change, revoked {revoked} sessions");
+
+    Ok(Ok(()))
+}
+
+/// Removes changes which can neither be confirmed nor reverted anymore
+#[tracing::instrument(skip(pool, config))]
+pub(crate) async fn purge_expired_email_changes(
+    pool: &PgPool,
+    config: &VerificationConfig,
+) -> Result<(), Report> {
+    let purged = sqlx::query!(
+        "DELETE FROM email_changes WHERE created_at < NOW() - make_interval(secs => $1)",
+        Duration::from_secs(config.token_lifetime.max(config.revert_lifetime)).as_secs_f64(),
+    )
+    .execute(pool)
+    .await?
+    .rows_affected();
+
+    if purged > 0 {
+        info!("Purged {purged} expired email changes");
+    }
+
+    Ok(())
+}
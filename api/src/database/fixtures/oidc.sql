@@ -0,0 +1,3 @@
+-- A public OIDC client, it has to use PKCE like every client
+INSERT INTO oidc_clients (id, name, secret, redirect_uris) VALUES
+    ('test-client', 'Test Client', NULL, '{https://client.example.com/callback}');
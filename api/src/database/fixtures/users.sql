@@ -0,0 +1,8 @@
+-- Two users with one session each, the passwords are never checked
+INSERT INTO users (id, name, email, password, email_verified_at) VALUES
+    ('11111111-1111-1111-1111-111111111111', 'Alice', 'alice@example.com', 'unused', NOW()),
+    ('22222222-2222-2222-2222-222222222222', 'Bob', 'bob@example.com', 'unused', NOW());
+
+INSERT INTO sessions (id, user_id) VALUES
+    ('aaaaaaaa-aaaa-aaaa-aaaa-aaaaaaaaaaaa', '11111111-1111-1111-1111-111111111111'),
+    ('bbbbbbbb-bbbb-bbbb-bbbb-bbbbbbbbbbbb', '22222222-2222-2222-2222-222222222222');
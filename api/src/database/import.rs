@@ -0,0 +1,92 @@
+//! Bulk import of users from another system
+//!
+//! The password hashes are kept as they are, see [hashing](crate::hashing)
+//! for the supported formats. They are replaced by Argon2id on the next
+//! successful login of each user.
+
+use color_eyre::{eyre::eyre, Report};
+use serde::Deserialize;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::{hashing, types::EMail};
+
+/// A user as read from the import file
+#[derive(Deserialize)]
+pub(crate) struct ImportedUser {
+    /// Display name
+    name: String,
+    /// Has to be unique, users with a taken email are skipped
+    email: EMail,
+    /// The hash from the old system
+    password_hash: String,
+    /// See [User::locale](super::User::locale)
+    #[serde(default)]
+    locale: Option<String>,
+    /// Whether the old system verified the email
+    #[serde(default)]
+    email_verified: bool,
+}
+
+/// How many users were imported
+#[derive(Debug, Default)]
+pub(crate) struct ImportSummary {
+    /// Created users
+    pub(crate) imported: usize,
+    /// Users skipped since their email was already taken
+    pub(crate) skipped: usize,
+}
+
+/// Creates all users within one transaction
+///
+/// If any hash has an unsupported format nobody is imported.
+#[tracing::instrument(skip_all)]
+pub(crate) async fn import_users(
+    pool: &PgPool,
+    users: &[ImportedUser],
+) -> Result<ImportSummary, Report> {
+    if let Some((number, user)) = users
+        .iter()
+        .enumerate()
+        .find(|(_, user)| !hashing::is_supported(&user.password_hash))
+    {
+        return Err(eyre!(
+            "Unsupported password hash of user {} ({:?})",
+            number + 1,
+            user.email
+        ));
+    }
+
+    let mut summary = ImportSummary::default();
+    let mut transaction = pool.begin().await?;
+
+    for user in users {
+        let inserted = sqlx::query!(
+            "INSERT INTO
+                users (id, name, email, password, locale, email_verified_at)
+            VALUES
+                ($1, $2, $3, $4, $5, CASE WHEN $6 THEN NOW() END)
+            ON CONFLICT (email) DO NOTHING",
+            Uuid::new_v4(),
+            user.name,
+            user.email.0,
+            user.password_hash,
+            user.locale,
+            user.email_verified,
+        )
+        .execute(&mut transaction)
+        .await?
+        .rows_affected()
+            > 0;
+
+        if inserted {
+            summary.imported += 1;
+        } else {
+            summary.skipped += 1;
+        }
+    }
+
+    transaction.commit().await?;
+
+    Ok(summary)
+}
@@ -0,0 +1,290 @@
+//! Limiting failed logins
+//!
+//! Failed logins are counted in redis, per account and per IP address,
+//! wrong TOTP and recovery codes per user.
+//! Every attempt is counted before the password is checked and given
+//! back unless it fails. Once a counter reaches its limit further logins
+//! are refused for [lockout_duration](LockoutConfig::lockout_duration),
+//! which doubles with every failure after that. Refused logins never
+//! check the password, so they don't cost an Argon2 hash either.
+
+use color_eyre::{eyre::Context, Report};
+use redis::{aio::Connection, AsyncCommands};
+use uuid::Uuid;
+
+use crate::{settings::LockoutConfig, types::EMail};
+
+/// Failure counter and lock of one account or IP address
+struct Limit {
+    /// Counts failures and attempts in progress within [LockoutConfig::window]
+    failures_key: String,
+    /// Exists while logins are refused
+    lock_key: String,
+    /// Counts the locks so far, each one lasts twice as long as the last
+    locks_key: String,
+    /// Failures before the lock, 0 means unlimited
+    max_failures: u64,
+    /// Position of the attempt within the window, see [Limit::reserve]
+    reserved: Option<u64>,
+}
+
+impl Limit {
+    /// The limit of `subject`, i.e. `account:alice@example.com`
+    fn new(subject: &str, max_failures: u64) -> Self {
+        Self {
+            failures_key: format!("login_failures:{subject}"),
+            lock_key: format!("login_lock:{subject}"),
+            locks_key: format!("login_locks:{subject}"),
+            max_failures,
+            reserved: None,
+        }
+    }
+
+    /// Counts the attempt as a failure in advance
+    ///
+    /// Counting and reading the counter is one step, so parallel attempts
+    /// can't all slip through before the first failure is recorded.
+    /// Returns the seconds until the next attempt is allowed if this one
+    /// is refused, refused attempts are not counted.
+    async fn reserve(
+        &mut self,
+        redis_connection: &mut Connection,
+        config: &LockoutConfig,
+    ) -> Result<Option<u64>, Report> {
+        if self.max_failures == 0 {
+            return Ok(None);
+        }
+
+        let window = usize::try_from(config.window).wrap_err("Lockout window out of range")?;
+        let (attempts, lock_ttl): (u64, i64) = redis::pipe()
+            .atomic()
+            .incr(&self.failures_key, 1)
+            .expire(&self.failures_key, window)
+            .ignore()
+            .ttl(&self.lock_key)
+            .query_async(redis_connection)
+            .await?;
+
+        // -2 if there is no lock
+        let locked_for = u64::try_from(lock_ttl).ok().filter(|ttl| *ttl > 0);
+        if locked_for.is_some() || attempts > self.max_failures {
+            self.release_slot(redis_connection, window).await?;
+            // Without a lock the remaining attempts are all in progress
+            return Ok(Some(locked_for.unwrap_or(1)));
+        }
+
+        self.reserved = Some(attempts);
+        Ok(None)
+    }
+
+    /// Turns the reserved attempt into a failure, locks once there are
+    /// too many
+    ///
+    /// Every lock leaves exactly one attempt for after it has expired,
+    /// so the locks double with every failure after the limit.
+    async fn record_failure(
+        &self,
+        redis_connection: &mut Connection,
+        config: &LockoutConfig,
+    ) -> Result<(), Report> {
+        let Some(attempts) = self.reserved else {
+            return Ok(());
+        };
+        if attempts < self.max_failures {
+            return Ok(());
+        }
+
+        let locks: u64 = redis_connection.incr(&self.locks_key, 1).await?;
+        let factor = u32::try_from(locks.saturating_sub(1))
+            .ok()
+            .and_then(|excess| 2_u64.checked_pow(excess))
+            .unwrap_or(u64::MAX);
+        let duration = config
+            .lockout_duration
+            .saturating_mul(factor)
+            .min(config.max_lockout_duration)
+            .max(1);
+
+        let window = usize::try_from(config.window).wrap_err("Lockout window out of range")?;
+        let duration = usize::try_from(duration).wrap_err("Lockout duration out of range")?;
+        redis::pipe()
+            .atomic()
+            .set_ex(&self.lock_key, locks, duration)
+            .ignore()
+            .expire(&self.locks_key, duration.saturating_add(window))
+            .ignore()
+            .set_ex(
+                &self.failures_key,
+                self.max_failures.saturating_sub(1),
+                window,
+            )
+            .ignore()
+            .query_async::<_, ()>(redis_connection)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Gives the reserved attempt back, it was not a failure after all
+    async fn release(
+        &self,
+        redis_connection: &mut Connection,
+        config: &LockoutConfig,
+    ) -> Result<(), Report> {
+        if self.reserved.is_none() {
+            return Ok(());
+        }
+
+        let window = usize::try_from(config.window).wrap_err("Lockout window out of range")?;
+        self.release_slot(redis_connection, window).await
+    }
+
+    /// Decrements the counter, keeping it from living forever in case
+    /// it expired in the meantime
+    async fn release_slot(
+        &self,
+        redis_connection: &mut Connection,
+        window: usize,
+    ) -> Result<(), Report> {
+        redis::pipe()
+            .atomic()
+            .decr(&self.failures_key, 1)
+            .ignore()
+            .expire(&self.failures_key, window)
+            .ignore()
+            .query_async::<_, ()>(redis_connection)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Forgets all failures and lifts the lock
+    async fn reset(&self, redis_connection: &mut Connection) -> Result<(), Report> {
+        redis_connection
+            .del::<_, ()>(&[&self.failures_key, &self.lock_key, &self.locks_key])
+            .await?;
+
+        Ok(())
+    }
+}
+
+/// A login for an account from an IP address
+pub(crate) struct LoginAttempt {
+    /// Limit of the account, even if it does not exist
+    account: Limit,
+    /// Limit of the IP address, if it is known
+    ip_address: Option<Limit>,
+}
+
+impl LoginAttempt {
+    /// Prepares checking the limits of `email` and `ip_address`
+    pub(crate) fn new(config: &LockoutConfig, email: &EMail, ip_address: Option<&str>) -> Self {
+        Self {
+            account: Limit::new(&format!("account:{}", email.0), config.max_account_failures),
+            ip_address: ip_address
+                .map(|ip_address| Limit::new(&format!("ip:{ip_address}"), config.max_ip_failures)),
+        }
+    }
+
+    /// Prepares checking the limit of second factors of `user_id`
+    ///
+    /// The password was correct already, so there is no limit per IP
+    /// address. Uses the same limit as passwords of an account.
+    pub(crate) fn second_factor(config: &LockoutConfig, user_id: &Uuid) -> Self {
+        Self {
+            account: Limit::new(
+                &format!("second_factor:{user_id}"),
+                config.max_account_failures,
+            ),
+            ip_address: None,
+        }
+    }
+
+    /// Counts the attempt against both limits before the password is
+    /// checked
+    ///
+    /// Returns the seconds until the login may be tried again if it is
+    /// refused. Otherwise it has to end in [failed](Self::failed),
+    /// [succeeded](Self::succeeded) or [cancelled](Self::cancelled).
+    #[tracing::instrument(skip_all)]
+    pub(crate) async fn reserve(
+        &mut self,
+        redis_connection: &mut Connection,
+        config: &LockoutConfig,
+    ) -> Result<Option<u64>, Report> {
+        let account = self.account.reserve(redis_connection, config).await?;
+        let ip_address = match &mut self.ip_address {
+            Some(limit) => limit.reserve(redis_connection, config).await?,
+            None => None,
+        };
+
+        let retry_after = account.max(ip_address);
+        if retry_after.is_some() {
+            self.cancelled(redis_connection, config).await?;
+        }
+
+        Ok(retry_after)
+    }
+
+    /// Counts a wrong password (or unknown account)
+    #[tracing::instrument(skip_all)]
+    pub(crate) async fn failed(
+        &self,
+        redis_connection: &mut Connection,
+        config: &LockoutConfig,
+    ) -> Result<(), Report> {
+        self.account
+            .record_failure(redis_connection, config)
+            .await?;
+        if let Some(limit) = &self.ip_address {
+            limit.record_failure(redis_connection, config).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Forgets the failures of the account after a correct password
+    ///
+    /// Failures of the IP address are kept, otherwise an attacker could
+    /// reset them using their own account.
+    #[tracing::instrument(skip_all)]
+    pub(crate) async fn succeeded(
+        &self,
+        redis_connection: &mut Connection,
+        config: &LockoutConfig,
+    ) -> Result<(), Report> {
+        self.account.reset(redis_connection).await?;
+        if let Some(limit) = &self.ip_address {
+            limit.release(redis_connection, config).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Gives the attempt back if the login ended for another reason than
+    /// the password, i.e. a disabled account
+    #[tracing::instrument(skip_all)]
+    pub(crate) async fn cancelled(
+        &self,
+        redis_connection: &mut Connection,
+        config: &LockoutConfig,
+    ) -> Result<(), Report> {
+        self.account.release(redis_connection, config).await?;
+        if let Some(limit) = &self.ip_address {
+            limit.release(redis_connection, config).await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Lifts the lock of an account and forgets its failures
+#[tracing::instrument(skip(redis_connection))]
+pub(crate) async fn unlock_account(
+    redis_connection: &mut Connection,
+    email: &EMail,
+) -> Result<(), Report> {
+    Limit::new(&format!("account:{}", email.0), 0)
+        .reset(redis_connection)
+        .await
+}
@@ -0,0 +1,59 @@
+//! Links for logging in without a password
+//!
+//! Stored like password reset requests: Every user has at most one link,
+//! requesting a new one replaces it. Logging in with a link is part of
+//! [auth](super::auth::login_with_magic_link).
+
+use std::time::Duration;
+
+use color_eyre::Report;
+use sqlx::PgPool;
+use tracing::info;
+use uuid::Uuid;
+
+use crate::settings::MagicLinkConfig;
+
+/// Creates a new link for the user, replacing the previous one
+///
+/// Returns the token, which has to be sent to the user.
+#[tracing::instrument(skip(pool))]
+pub(crate) async fn new_magic_link(pool: &PgPool, user_id: &Uuid) -> Result<Uuid, Report> {
+    let token = Uuid::new_v4();
+    sqlx::query!(
+        "INSERT INTO
+            magic_links (id, user_id)
+        VALUES
+            ($1, $2)
+        ON CONFLICT(user_id) DO
+            UPDATE SET
+                id = EXCLUDED.id,
+                created_at = EXCLUDED.created_at",
+        token,
+        user_id,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(token)
+}
+
+/// Removes all expired links
+#[tracing::instrument(skip(pool, config))]
+pub(crate) async fn purge_expired_magic_links(
+    pool: &PgPool,
+    config: &MagicLinkConfig,
+) -> Result<(), Report> {
+    let purged = sqlx::query!(
+        "DELETE FROM magic_links WHERE created_at < NOW() - make_interval(secs => $1)",
+        Duration::from_secs(config.token_lifetime).as_secs_f64(),
+    )
+    .execute(pool)
+    .await?
+    .rows_affected();
+
+    if purged > 0 {
+        info!("Purged {purged} expired magic links");
+    }
+
+    Ok(())
+}
@@ -0,0 +1,187 @@
+//! Registered OpenID Connect clients and their authorization codes
+
+use color_eyre::Report;
+use sqlx::PgPool;
+use tracing::info;
+use uuid::Uuid;
+
+use crate::{hashing::Hasher, types::EMail};
+
+use super::User;
+
+/// How long an authorization code can be exchanged, in seconds
+const CODE_LIFETIME_SECONDS: f64 = 60.0;
+
+/// A relying party, mirrors the `oidc_clients` table
+#[allow(clippy::missing_docs_in_private_items)]
+#[derive(Debug)]
+pub(crate) struct OidcClient {
+    pub(crate) id: String,
+    pub(crate) redirect_uris: Vec<String>,
+    /// Hashed secret, `None` for public clients
+    secret: Option<String>,
+}
+
+impl OidcClient {
+    /// Whether the client has to authenticate at the token endpoint
+    pub(crate) fn is_confidential(&self) -> bool {
+        self.secret.is_some()
+    }
+
+    /// Checks the client secret
+    ///
+    /// Always fails for public clients.
+    pub(crate) async fn verify_secret(
+        &self,
+        hasher: &Hasher,
+        secret: &str,
+    ) -> Result<bool, Report> {
+        let Some(saved) = &self.secret else {
+            return Ok(false);
+        };
+
+        hasher.verify(secret, saved).await
+    }
+}
+
+#[tracing::instrument(skip(pool))]
+pub(crate) async fn get_client(
+    pool: &PgPool,
+    client_id: &str,
+) -> Result<Option<OidcClient>, Report> {
+    Ok(sqlx::query_as!(
+        OidcClient,
+        "SELECT id, redirect_uris, secret FROM oidc_clients WHERE id = $1",
+        client_id
+    )
+    .fetch_optional(pool)
+    .await?)
+}
+
+/// Everything remembered between `/authorize` and `/token`
+#[allow(clippy::missing_docs_in_private_items)]
+#[derive(Debug)]
+pub(crate) struct AuthorizationGrant {
+    pub(crate) client_id: String,
+    pub(crate) redirect_uri: String,
+    pub(crate) scope: String,
+    pub(crate) nonce: Option<String>,
+    pub(crate) code_challenge: String,
+}
+
+/// Stores the grant and returns the code for it
+#[tracing::instrument(skip(pool))]
+pub(crate) async fn create_authorization_code(
+    pool: &PgPool,
+    user_id: &Uuid,
+    grant: &AuthorizationGrant,
+) -> Result<Uuid, Report> {
+    let code = Uuid::new_v4();
+    sqlx::query!(
+        "INSERT INTO
+            oidc_authorization_codes
+                (id, client_id, user_id, redirect_uri, scope, nonce, code_challenge)
+        VALUES
+            ($1, $2, $3, $4, $5, $6, $7)",
+        code,
+        grant.client_id,
+        user_id,
+        grant.redirect_uri,
+        grant.scope,
+        grant.nonce,
+        grant.code_challenge,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(code)
+}
+
+/// Consumes an authorization code
+///
+/// Returns the grant and the user that authorized it, if the code
+/// exists, has not expired and the user has not been disabled. Every
+/// code can only be redeemed once.
+#[tracing::instrument(skip(pool))]
+pub(crate) async fn redeem_authorization_code(
+    pool: &PgPool,
+    code: &Uuid,
+) -> Result<Option<(AuthorizationGrant, User)>, Report> {
+    let Some(record) = sqlx::query!(
+        r#"DELETE FROM
+            oidc_authorization_codes
+        USING
+            users
+        WHERE
+            oidc_authorization_codes.id = $1
+            AND users.id = oidc_authorization_codes.user_id
+        RETURNING
+            client_id, redirect_uri, scope, nonce, code_challenge,
+            users.id AS user_id, users.name, users.email, users.locale,
+            users.disabled_at IS NOT NULL AS "disabled!",
+            oidc_authorization_codes.created_at > NOW() - make_interval(secs => $2) AS "fresh!""#,
+        code,
+        CODE_LIFETIME_SECONDS,
+    )
+    .fetch_optional(pool)
+    .await? else {
+        return Ok(None);
+    };
+
+    // Expired codes are deleted as well, they are of no use anymore
+    if !record.fresh || record.disabled {
+        return Ok(None);
+    }
+
+    Ok(Some((
+        AuthorizationGrant {
+            client_id: record.client_id,
+            redirect_uri: record.redirect_uri,
+            scope: record.scope,
+            nonce: record.nonce,
+            code_challenge: record.code_challenge,
+        },
+        User {
+            id: record.user_id,
+            name: record.name,
+            email: EMail(record.email),
+            locale: record.locale,
+        },
+    )))
+}
+
+/// Removes authorization codes which can't be redeemed anymore
+#[tracing::instrument(skip(pool))]
+pub(crate) async fn purge_expired_authorization_codes(pool: &PgPool) -> Result<(), Report> {
+    let purged = sqlx::query!(
+        "DELETE FROM oidc_authorization_codes
+            WHERE created_at < NOW() - make_interval(secs => $1)",
+        CODE_LIFETIME_SECONDS,
+    )
+    .execute(pool)
+    .await?
+    .rows_affected();
+
+    if purged > 0 {
+        info!("Purged {purged} expired authorization codes");
+    }
+
+    Ok(())
+}
+
+/// Returns the user of a token, unless it was deleted or disabled
+#[tracing::instrument(skip(pool))]
+pub(crate) async fn get_active_user(pool: &PgPool, user_id: &Uuid) -> Result<Option<User>, Report> {
+    Ok(sqlx::query!(
+        "SELECT id, name, email, locale FROM users WHERE id = $1 AND disabled_at IS NULL",
+        user_id
+    )
+    .fetch_optional(pool)
+    .await?
+    .map(|db_user| User {
+        id: db_user.id,
+        name: db_user.name,
+        email: EMail(db_user.email),
+        locale: db_user.locale,
+    }))
+}
@@ -0,0 +1,477 @@
+//! Organizations, their members and invites
+//!
+//! Every customer gets an organization. Members can have roles within it
+//! (in addition to their global roles, see [roles](super::roles)), which
+//! only count while the organization is the active one of their session.
+//! Organization admins manage the members of their organization, global
+//! admins manage all organizations.
+
+use std::time::Duration;
+
+use color_eyre::Report;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::types::EMail;
+
+use super::{roles::normalize, User};
+
+/// The known errors of managing organizations
+#[derive(Debug)]
+pub(crate) enum OrganizationError {
+    /// The user is not a member of the organization
+    MemberNotFound,
+    /// At least one of the given roles does not exist
+    RoleNotFound,
+    /// There is no invite with the given token
+    InviteNotFound,
+    /// The invite exists, but has expired
+    InviteExpired,
+    /// The invite was sent to another email than the one of the user
+    WrongEmail,
+}
+
+/// An organization
+#[allow(clippy::missing_docs_in_private_items)]
+#[derive(Debug, Serialize)]
+pub(crate) struct Organization {
+    pub(crate) id: Uuid,
+    pub(crate) name: String,
+}
+
+/// An organization the user is a member of
+#[derive(Debug, Serialize)]
+pub(crate) struct Membership {
+    /// Id of the organization
+    id: Uuid,
+    /// Name of the organization
+    name: String,
+    /// Whether the user is an organization admin
+    is_admin: bool,
+    /// Roles of the user within the organization
+    roles: Vec<String>,
+}
+
+/// A member as shown to organization admins
+#[allow(clippy::missing_docs_in_private_items)]
+#[derive(Debug, Serialize)]
+pub(crate) struct Member {
+    id: Uuid,
+    name: String,
+    email: String,
+    is_admin: bool,
+    roles: Vec<String>,
+}
+
+/// Partial update of a member, `roles` replaces all their roles
+#[allow(clippy::missing_docs_in_private_items)]
+#[derive(Debug, Deserialize)]
+pub(crate) struct MemberUpdate {
+    pub(crate) is_admin: Option<bool>,
+    pub(crate) roles: Option<Vec<String>>,
+}
+
+/// An invite to an organization, sent by mail
+#[derive(Debug, Deserialize)]
+pub(crate) struct NewInvite {
+    /// Only a user with this email can accept the invite
+    pub(crate) email: EMail,
+    /// Whether the user becomes an organization admin
+    #[serde(default)]
+    pub(crate) is_admin: bool,
+    /// The roles the user gets within the organization
+    #[serde(default)]
+    pub(crate) roles: Vec<String>,
+}
+
+/// Lists all organizations
+#[tracing::instrument(skip(pool))]
+pub(crate) async fn list_organizations(pool: &PgPool) -> Result<Vec<Organization>, Report> {
+    Ok(sqlx::query_as!(
+        Organization,
+        "SELECT id, name FROM organizations ORDER BY name"
+    )
+    .fetch_all(pool)
+    .await?)
+}
+
+/// Returns a single organization
+#[tracing::instrument(skip(pool))]
+pub(crate) async fn get_organization(
+    pool: &PgPool,
+    organization_id: &Uuid,
+) -> Result<Option<Organization>, Report> {
+    Ok(sqlx::query_as!(
+        Organization,
+        "SELECT id, name FROM organizations WHERE id = $1",
+        organization_id
+    )
+    .fetch_optional(pool)
+    .await?)
+}
+
+/// Creates an organization without any members
+#[tracing::instrument(skip(pool))]
+pub(crate) async fn create_organization(pool: &PgPool, name: &str) -> Result<Organization, Report> {
+    Ok(sqlx::query_as!(
+        Organization,
+        "INSERT INTO organizations (id, name) VALUES ($1, $2) RETURNING id, name",
+        Uuid::new_v4(),
+        name,
+    )
+    .fetch_one(pool)
+    .await?)
+}
+
+/// Deletes an organization with all memberships and invites
+///
+/// Returns whether the organization existed.
+#[tracing::instrument(skip(pool))]
+pub(crate) async fn delete_organization(
+    pool: &PgPool,
+    organization_id: &Uuid,
+) -> Result<bool, Report> {
+    Ok(
+        sqlx::query!("DELETE FROM organizations WHERE id = $1", organization_id)
+            .execute(pool)
+            .await?
+            .rows_affected()
+            > 0,
+    )
+}
+
+/// Returns the user of the session, if they may manage the organization
+///
+/// That is organization admins and global admins, unless they are disabled.
+#[tracing::instrument(skip(pool))]
+pub(crate) async fn get_organization_admin(
+    pool: &PgPool,
+    session_id: &Uuid,
+    organization_id: &Uuid,
+) -> Result<Option<User>, Report> {
+    Ok(sqlx::query!(
+        "SELECT users.id, users.name, users.email, users.locale
+            FROM sessions INNER JOIN users ON (sessions.user_id = users.id)
+            WHERE sessions.id = $1 AND users.disabled_at IS NULL AND (
+                users.is_admin OR EXISTS (
+                    SELECT 1 FROM organization_members AS members
+                        WHERE members.organization_id = $2
+                            AND members.user_id = users.id
+                            AND members.is_admin
+                )
+            )",
+        session_id,
+        organization_id,
+    )
+    .fetch_optional(pool)
+    .await?
+    .map(|db_user| User {
+        id: db_user.id,
+        name: db_user.name,
+        email: EMail(db_user.email),
+        locale: db_user.locale,
+    }))
+}
+
+/// Lists all organizations of a user
+#[tracing::instrument(skip(pool))]
+pub(crate) async fn memberships(pool: &PgPool, user_id: &Uuid) -> Result<Vec<Membership>, Report> {
+    Ok(sqlx::query_as!(
+        Membership,
+        r#"SELECT
+            organizations.id,
+            organizations.name,
+            members.is_admin,
+            array_remove(array_agg(member_roles.role ORDER BY member_roles.role), NULL) AS "roles!"
+        FROM
+            organization_members AS members
+            LEFT JOIN organization_member_roles AS member_roles USING (organization_id, user_id)
+            INNER JOIN organizations ON (organizations.id = members.organization_id)
+        WHERE
+            members.user_id = $1
+        GROUP BY
+            organizations.id,
+            members.is_admin
+        ORDER BY
+            organizations.name"#,
+        user_id
+    )
+    .fetch_all(pool)
+    .await?)
+}
+
+/// Lists all members of an organization
+#[tracing::instrument(skip(pool))]
+pub(crate) async fn list_members(
+    pool: &PgPool,
+    organization_id: &Uuid,
+) -> Result<Vec<Member>, Report> {
+    Ok(sqlx::query_as!(
+        Member,
+        r#"SELECT
+            users.id,
+            users.name,
+            users.email,
+            members.is_admin,
+            array_remove(array_agg(member_roles.role ORDER BY member_roles.role), NULL) AS "roles!"
+        FROM
+            organization_members AS members
+            LEFT JOIN organization_member_roles AS member_roles USING (organization_id, user_id)
+            INNER JOIN users ON (users.id = members.user_id)
+        WHERE
+            members.organization_id = $1
+        GROUP BY
+            users.id,
+            members.is_admin
+        ORDER BY
+            users.name"#,
+        organization_id
+    )
+    .fetch_all(pool)
+    .await?)
+}
+
+/// Makes a member organization admin (or not) and/or replaces their roles
+#[tracing::instrument(skip(pool))]
+pub(crate) async fn update_member(
+    pool: &PgPool,
+    organization_id: &Uuid,
+    user_id: &Uuid,
+    update: &MemberUpdate,
+) -> Result<Result<(), OrganizationError>, Report> {
+    let mut transaction = pool.begin().await?;
+
+    let found = sqlx::query!(
+        "UPDATE organization_members SET is_admin = coalesce($3, is_admin)
+            WHERE organization_id = $1 AND user_id = $2",
+        organization_id,
+        user_id,
+        update.is_admin,
+    )
+    .execute(&mut transaction)
+    .await?
+    .rows_affected();
+    if found == 0 {
+        return Ok(Err(OrganizationError::MemberNotFound));
+    }
+
+    if let Some(roles) = &update.roles {
+        let roles = normalize(roles);
+
+        sqlx::query!(
+            "DELETE FROM organization_member_roles WHERE organization_id = $1 AND user_id = $2",
+            organization_id,
+            user_id,
+        )
+        .execute(&mut transaction)
+        .await?;
+        let inserted = sqlx::query!(
+            "INSERT INTO organization_member_roles (organization_id, user_id, role)
+                SELECT $1, $2, name FROM roles WHERE name = ANY($3)",
+            organization_id,
+            user_id,
+            &roles,
+        )
+        .execute(&mut transaction)
+        .await?
+        .rows_affected();
+        if usize::try_from(inserted)? != roles.len() {
+            return Ok(Err(OrganizationError::RoleNotFound));
+        }
+    }
+
+    transaction.commit().await?;
+
+    Ok(Ok(()))
+}
+
+/// Removes a member from an organization, returns whether they were one
+///
+/// Sessions which had the organization active fall back to none.
+#[tracing::instrument(skip(pool))]
+pub(crate) async fn remove_member(
+    pool: &PgPool,
+    organization_id: &Uuid,
+    user_id: &Uuid,
+) -> Result<bool, Report> {
+    let mut transaction = pool.begin().await?;
+
+    let removed = sqlx::query!(
+        "DELETE FROM organization_members WHERE organization_id = $1 AND user_id = $2",
+        organization_id,
+        user_id,
+    )
+    .execute(&mut transaction)
+    .await?
+    .rows_affected();
+    sqlx::query!(
+        "UPDATE sessions SET active_organization = NULL
+            WHERE user_id = $2 AND active_organization = $1",
+        organization_id,
+        user_id,
+    )
+    .execute(&mut transaction)
+    .await?;
+
+    transaction.commit().await?;
+
+    Ok(removed > 0)
+}
+
+/// Creates an invite, returns its token
+///
+/// The caller has to make sure the organization exists.
+#[tracing::instrument(skip(pool))]
+pub(crate) async fn create_invite(
+    pool: &PgPool,
+    organization_id: &Uuid,
+    created_by: &Uuid,
+    invite: &NewInvite,
+) -> Result<Result<Uuid, OrganizationError>, Report> {
+    let roles = normalize(&invite.roles);
+    let known_roles = sqlx::query!(
+        r#"SELECT count(*) AS "count!" FROM roles WHERE name = ANY($1)"#,
+        &roles
+    )
+    .fetch_one(pool)
+    .await?
+    .count;
+    if usize::try_from(known_roles)? != roles.len() {
+        return Ok(Err(OrganizationError::RoleNotFound));
+    }
+
+    let token = Uuid::new_v4();
+    sqlx::query!(
+        "INSERT INTO organization_invites (id, organization_id, email, is_admin, roles, created_by)
+            VALUES ($1, $2, $3, $4, $5, $6)",
+        token,
+        organization_id,
+        invite.email.0,
+        invite.is_admin,
+        &roles,
+        created_by,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(Ok(token))
+}
+
+/// Makes `user` a member as described by the invite, returns the
+/// organization id
+///
+/// The invite can only be used by the user it was sent to. Accepting an
+/// invite to an organization the user already is a member of adds the
+/// roles of the invite.
+#[tracing::instrument(skip(pool))]
+pub(crate) async fn accept_invite(
+    pool: &PgPool,
+    invite_lifetime: u64,
+    token: &Uuid,
+    user: &User,
+) -> Result<Result<Uuid, OrganizationError>, Report> {
+    let mut transaction = pool.begin().await?;
+
+    let Some(invite) = sqlx::query!(
+        r#"DELETE FROM
+            organization_invites
+        WHERE
+            id = $1
+        RETURNING
+            organization_id,
+            email,
+            is_admin,
+            roles,
+            created_at > NOW() - make_interval(secs => $2) AS "fresh!""#,
+        token,
+        Duration::from_secs(invite_lifetime).as_secs_f64(),
+    )
+    .fetch_optional(&mut transaction)
+    .await? else {
+        return Ok(Err(OrganizationError::InviteNotFound));
+    };
+    if !invite.fresh {
+        // Commit, so the expired invite is gone for good
+        transaction.commit().await?;
+        return Ok(Err(OrganizationError::InviteExpired));
+    }
+    if invite.email != user.email.0 {
+        // Dropping the transaction keeps the invite for the right user
+        return Ok(Err(OrganizationError::WrongEmail));
+    }
+
+    sqlx::query!(
+        "INSERT INTO organization_members (organization_id, user_id, is_admin)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (organization_id, user_id)
+                DO UPDATE SET is_admin = organization_members.is_admin OR excluded.is_admin",
+        invite.organization_id,
+        user.id,
+        invite.is_admin,
+    )
+    .execute(&mut transaction)
+    .await?;
+    // Roles deleted since the invite was sent are skipped
+    sqlx::query!(
+        "INSERT INTO organization_member_roles (organization_id, user_id, role)
+            SELECT $1, $2, name FROM roles WHERE name = ANY($3)
+            ON CONFLICT DO NOTHING",
+        invite.organization_id,
+        user.id,
+        &invite.roles,
+    )
+    .execute(&mut transaction)
+    .await?;
+
+    transaction.commit().await?;
+
+    Ok(Ok(invite.organization_id))
+}
+
+/// Returns the active organization of the session
+#[tracing::instrument(skip(pool))]
+pub(crate) async fn active_organization(
+    pool: &PgPool,
+    session_id: &Uuid,
+) -> Result<Option<Uuid>, Report> {
+    Ok(sqlx::query!(
+        "SELECT active_organization FROM sessions WHERE id = $1",
+        session_id
+    )
+    .fetch_optional(pool)
+    .await?
+    .and_then(|session| session.active_organization))
+}
+
+/// Switches the active organization of the session, `None` deactivates it
+///
+/// Only organizations the owner of the session is a member of can be
+/// activated.
+#[tracing::instrument(skip(pool))]
+pub(crate) async fn set_active_organization(
+    pool: &PgPool,
+    session_id: &Uuid,
+    organization_id: Option<Uuid>,
+) -> Result<Result<(), OrganizationError>, Report> {
+    let updated = sqlx::query!(
+        "UPDATE sessions SET active_organization = $2
+            WHERE id = $1 AND (
+                $2::uuid IS NULL OR EXISTS (
+                    SELECT 1 FROM organization_members
+                        WHERE organization_id = $2 AND user_id = sessions.user_id
+                )
+            )",
+        session_id,
+        organization_id,
+    )
+    .execute(pool)
+    .await?
+    .rows_affected();
+
+    Ok(if updated == 0 {
+        Err(OrganizationError::MemberNotFound)
+    } else {
+        Ok(())
+    })
+}
@@ -0,0 +1,181 @@
+//! One-time recovery codes
+//!
+//! Users with a second factor get a set of codes, each of which can
+//! complete one login instead of the second factor. The codes are 50
+//! random bits, so they are stored as SHA-256 hashes: Argon2 would only
+//! make the lookup expensive without making guessing any harder.
+
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use color_eyre::Report;
+use sha2::{Digest, Sha256};
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+use super::auth::second_factors;
+
+/// Number of codes generated at once
+const CODE_COUNT: usize = 10;
+
+/// Random bytes per code, enough for [CODE_LENGTH] base32 characters
+const CODE_BYTES: usize = 7;
+
+/// Characters of a code, without the separator
+const CODE_LENGTH: usize = 10;
+
+/// Generates a random code like `abcde-fghij`
+fn generate_code() -> String {
+    let mut bytes = [0; CODE_BYTES];
+    OsRng.fill_bytes(&mut bytes);
+    let encoded =
+        base32::encode(base32::Alphabet::RFC4648 { padding: false }, &bytes).to_lowercase();
+    let (first, second) = encoded[..CODE_LENGTH].split_at(CODE_LENGTH / 2);
+
+    format!("{first}-{second}")
+}
+
+/// Hashes a code as entered by the user
+///
+/// Case, separators and whitespace don't matter, so a code read from
+/// paper can be typed however the user likes.
+fn hash_code(code: &str) -> Vec<u8> {
+    let normalized: String = code
+        .chars()
+        .filter(char::is_ascii_alphanumeric)
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+
+    Sha256::digest(normalized.as_bytes()).to_vec()
+}
+
+/// Replaces all codes of the user by new ones
+///
+/// Returns the codes, they have to be shown to the user exactly once.
+#[tracing::instrument(skip(pool))]
+pub(crate) async fn regenerate(pool: &PgPool, user_id: &Uuid) -> Result<Vec<String>, Report> {
+    let codes: Vec<String> = (0..CODE_COUNT).map(|_| generate_code()).collect();
+
+    let mut transaction = pool.begin().await?;
+
+    sqlx::query!("DELETE FROM recovery_codes WHERE user_id = $1", user_id)
+        .execute(&mut transaction)
+        .await?;
+    for code in &codes {
+        sqlx::query!(
+            "INSERT INTO recovery_codes (id, user_id, code_hash) VALUES ($1, $2, $3)",
+            Uuid::new_v4(),
+            user_id,
+            hash_code(code),
+        )
+        .execute(&mut transaction)
+        .await?;
+    }
+
+    transaction.commit().await?;
+
+    Ok(codes)
+}
+
+/// Number of unused codes of the user
+#[tracing::instrument(skip(pool))]
+pub(crate) async fn remaining(pool: &PgPool, user_id: &Uuid) -> Result<i64, Report> {
+    Ok(sqlx::query!(
+        r#"SELECT COUNT(*) AS "count!" FROM recovery_codes WHERE user_id = $1 AND used_at IS NULL"#,
+        user_id
+    )
+    .fetch_one(pool)
+    .await?
+    .count)
+}
+
+/// Marks `code` as used, returns whether it was an unused code of the user
+///
+/// The code is only used up once `transaction` is committed.
+#[tracing::instrument(skip(transaction, code))]
+pub(crate) async fn redeem(
+    transaction: &mut Transaction<'_, Postgres>,
+    user_id: &Uuid,
+    code: &str,
+) -> Result<bool, Report> {
+    Ok(sqlx::query!(
+        "UPDATE recovery_codes
+            SET used_at = NOW()
+            WHERE user_id = $1 AND code_hash = $2 AND used_at IS NULL",
+        user_id,
+        hash_code(code),
+    )
+    .execute(transaction)
+    .await?
+    .rows_affected()
+        > 0)
+}
+
+/// Removes all codes of the user once the last second factor is gone
+///
+/// Otherwise old codes would be valid again as soon as a new second
+/// factor is set up.
+#[tracing::instrument(skip(pool))]
+pub(crate) async fn delete_if_unused(pool: &PgPool, user_id: &Uuid) -> Result<(), Report> {
+    if second_factors(pool, user_id).await?.is_empty() {
+        sqlx::query!("DELETE FROM recovery_codes WHERE user_id = $1", user_id)
+            .execute(pool)
+            .await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    //! Generating and normalising codes, without a database
+
+    use std::collections::HashSet;
+
+    use super::{generate_code, hash_code, CODE_COUNT, CODE_LENGTH};
+
+    /// Codes are two lowercase base32 groups joined by a dash
+    #[test]
+    fn generated_code_format() {
+        let code = generate_code();
+        let (first, second) = code.split_once('-').unwrap_or_default();
+
+        assert_eq!(first.len() + second.len(), CODE_LENGTH, "{code}");
+        assert_eq!(first.len(), second.len(), "{code}");
+        assert!(
+            first
+                .chars()
+                .chain(second.chars())
+                .all(|c| matches!(c, 'a'..='z' | '2'..='7')),
+            "{code}"
+        );
+    }
+
+    /// A set of codes has no duplicates
+    #[test]
+    fn generated_codes_are_unique() {
+        let codes: HashSet<String> = (0..CODE_COUNT * 100).map(|_| generate_code()).collect();
+
+        assert_eq!(codes.len(), CODE_COUNT * 100);
+    }
+
+    /// Case, dashes and whitespace are ignored
+    #[test]
+    fn hash_ignores_formatting() {
+        let expected = hash_code("abcde-fghij");
+
+        for code in [
+            "abcdefghij",
+            "ABCDE-FGHIJ",
+            " abcde fghij\n",
+            "aBcDe - FgHiJ",
+        ] {
+            assert_eq!(hash_code(code), expected, "{code:?}");
+        }
+    }
+
+    /// Different codes have different hashes
+    #[test]
+    fn hash_distinguishes_codes() {
+        assert_ne!(hash_code("abcde-fghij"), hash_code("abcde-fghik"));
+        assert_ne!(hash_code("abcde-fghij"), hash_code("abcde-fghi"));
+    }
+}
@@ -0,0 +1,153 @@
+//! Creating accounts and inviting people to create one
+
+use std::time::Duration;
+
+use color_eyre::Report;
+use serde::Deserialize;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::{
+    hashing::Hasher,
+    password_policy::{PasswordPolicy, PolicyViolation},
+    settings::{RegistrationConfig, RegistrationMode},
+    types::{EMail, Password},
+};
+
+use super::User;
+
+/// Everything needed to create an account
+#[allow(clippy::missing_docs_in_private_items)]
+#[derive(Debug, Deserialize)]
+pub(crate) struct Registration {
+    pub(crate) name: String,
+    pub(crate) email: EMail,
+    pub(crate) password: Password,
+    /// Only required if registration is invite-only
+    pub(crate) invite: Option<Uuid>,
+}
+
+/// The known errors of [register_user]
+#[derive(Debug)]
+pub(crate) enum RegistrationError {
+    /// Registration is disabled by config
+    Disabled,
+    /// Registration is invite-only and the invite is missing, unknown,
+    /// expired or has been used already
+    InvalidInvite,
+    /// There already is an account with this email
+    EmailTaken,
+    /// The password does not follow the password policy
+    WeakPassword(Vec<PolicyViolation>),
+}
+
+/// Creates a new account, as allowed by the configured [RegistrationMode]
+///
+/// In invite-only mode the invite is consumed, but only if the account
+/// could be created. The cheap checks come first, so requests that can't
+/// succeed never check for breached passwords or cost a hash. Hashing
+/// happens before the transaction, so no connection is held while
+/// waiting for the [Hasher].
+#[tracing::instrument(skip(pool, config, policy, hasher, registration))]
+pub(crate) async fn register_user(
+    pool: &PgPool,
+    config: &RegistrationConfig,
+    policy: &PasswordPolicy,
+    hasher: &Hasher,
+    registration: &Registration,
+) -> Result<Result<User, RegistrationError>, Report> {
+    if config.mode == RegistrationMode::Disabled {
+        return Ok(Err(RegistrationError::Disabled));
+    }
+
+    let invite_lifetime = Duration::from_secs(config.invite_lifetime).as_secs_f64();
+    let invite = if config.mode == RegistrationMode::InviteOnly {
+        let Some(invite) = registration.invite else {
+            return Ok(Err(RegistrationError::InvalidInvite));
+        };
+        let valid = sqlx::query!(
+            "SELECT id FROM registration_invites
+                WHERE id = $1 AND created_at > NOW() - make_interval(secs => $2)",
+            invite,
+            invite_lifetime,
+        )
+        .fetch_optional(pool)
+        .await?
+        .is_some();
+        if !valid {
+            return Ok(Err(RegistrationError::InvalidInvite));
+        }
+        Some(invite)
+    } else {
+        None
+    };
+
+    let violations = policy
+        .check(
+            &registration.password,
+            &[&registration.name, &registration.email.0],
+        )
+        .await?;
+    if !violations.is_empty() {
+        return Ok(Err(RegistrationError::WeakPassword(violations)));
+    }
+
+    let hash = hasher.hash(&registration.password.0).await?;
+
+    let mut transaction = pool.begin().await?;
+
+    if let Some(invite) = invite {
+        let redeemed = sqlx::query!(
+            "DELETE FROM registration_invites
+                WHERE id = $1 AND created_at > NOW() - make_interval(secs => $2)",
+            invite,
+            invite_lifetime,
+        )
+        .execute(&mut transaction)
+        .await?
+        .rows_affected();
+        if redeemed == 0 {
+            // Used by a concurrent registration in the meantime
+            return Ok(Err(RegistrationError::InvalidInvite));
+        }
+    }
+
+    let Some(user) = sqlx::query!(
+        "INSERT INTO users (id, name, email, password) VALUES ($1, $2, $3, $4)
+            ON CONFLICT (email) DO NOTHING
+            RETURNING id, name, email, locale",
+        Uuid::new_v4(),
+        registration.name,
+        registration.email.0,
+        hash,
+    )
+    .fetch_optional(&mut transaction)
+    .await? else {
+        // Dropping the transaction keeps the invite
+        return Ok(Err(RegistrationError::EmailTaken));
+    };
+
+    transaction.commit().await?;
+
+    Ok(Ok(User {
+        id: user.id,
+        name: user.name,
+        email: EMail(user.email),
+        locale: user.locale,
+    }))
+}
+
+/// Creates an invite which allows a single registration
+#[tracing::instrument(skip(pool))]
+pub(crate) async fn create_invite(pool: &PgPool, created_by: &Uuid) -> Result<Uuid, Report> {
+    let invite = Uuid::new_v4();
+    sqlx::query!(
+        "INSERT INTO registration_invites (id, created_by) VALUES ($1, $2)",
+        invite,
+        created_by,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(invite)
+}
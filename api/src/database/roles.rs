@@ -0,0 +1,332 @@
+//! Roles and permissions
+//!
+//! Permissions are plain names (i.e. `users:read`), roles bundle them and
+//! are assigned to users, globally or within an
+//! [organization](super::organizations). Hausmeister only checks its own
+//! permissions, see [RequirePermission](crate::middlewares::permission::RequirePermission),
+//! all others are meant for downstream services, which get the roles of
+//! a user from `/user` or the OIDC tokens. Admins implicitly have every
+//! permission.
+
+use color_eyre::Report;
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+use crate::types::EMail;
+
+use super::User;
+
+/// The known errors of managing roles and permissions
+#[derive(Debug)]
+pub(crate) enum RoleError {
+    /// There is no role with the given name
+    RoleNotFound,
+    /// At least one of the given permissions does not exist
+    PermissionNotFound,
+    /// There is no user with the given id
+    UserNotFound,
+    /// A role or permission with this name already exists
+    NameTaken,
+}
+
+/// A permission, as created by admins
+#[allow(clippy::missing_docs_in_private_items)]
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Permission {
+    pub(crate) name: String,
+    #[serde(default)]
+    pub(crate) description: String,
+}
+
+/// A role with all its permissions
+#[allow(clippy::missing_docs_in_private_items)]
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Role {
+    pub(crate) name: String,
+    #[serde(default)]
+    pub(crate) description: String,
+    #[serde(default)]
+    pub(crate) permissions: Vec<String>,
+}
+
+/// Partial update of a role, `permissions` replaces all permissions
+#[allow(clippy::missing_docs_in_private_items)]
+#[derive(Debug, Deserialize)]
+pub(crate) struct RoleUpdate {
+    pub(crate) description: Option<String>,
+    pub(crate) permissions: Option<Vec<String>>,
+}
+
+/// Returns the names of all roles of a user
+#[tracing::instrument(skip(pool))]
+pub(crate) async fn user_roles(pool: &PgPool, user_id: &Uuid) -> Result<Vec<String>, Report> {
+    Ok(sqlx::query!(
+        "SELECT role FROM user_roles WHERE user_id = $1 ORDER BY role",
+        user_id
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|row| row.role)
+    .collect())
+}
+
+/// Returns the user of the session, if they have the permission
+///
+/// Admins have every permission, disabled users none. Only if the
+/// permission is `organization_scoped` the roles within the active
+/// organization of the session count besides the global roles of the
+/// user. Organization admins can give those roles to themselves, so they
+/// must never grant permissions beyond their organization.
+#[tracing::instrument(skip(pool))]
+pub(crate) async fn get_user_with_permission(
+    pool: &PgPool,
+    session_id: &Uuid,
+    permission: &str,
+    organization_scoped: bool,
+) -> Result<Option<User>, Report> {
+    Ok(sqlx::query!(
+        "SELECT users.id, name, email, locale
+            FROM sessions INNER JOIN users ON (sessions.user_id = users.id)
+            WHERE sessions.id = $1 AND disabled_at IS NULL AND (
+                is_admin OR EXISTS (
+                    SELECT 1 FROM user_roles INNER JOIN role_permissions USING (role)
+                        WHERE user_roles.user_id = users.id AND permission = $2
+                ) OR $3 AND EXISTS (
+                    SELECT 1 FROM organization_member_roles AS member_roles
+                        INNER JOIN role_permissions USING (role)
+                        WHERE member_roles.user_id = users.id
+                            AND member_roles.organization_id = sessions.active_organization
+                            AND permission = $2
+                )
+            )",
+        session_id,
+        permission,
+        organization_scoped,
+    )
+    .fetch_optional(pool)
+    .await?
+    .map(|db_user| User {
+        id: db_user.id,
+        name: db_user.name,
+        email: EMail(db_user.email),
+        locale: db_user.locale,
+    }))
+}
+
+/// Lists all permissions
+#[tracing::instrument(skip(pool))]
+pub(crate) async fn list_permissions(pool: &PgPool) -> Result<Vec<Permission>, Report> {
+    Ok(sqlx::query_as!(
+        Permission,
+        "SELECT name, description FROM permissions ORDER BY name"
+    )
+    .fetch_all(pool)
+    .await?)
+}
+
+/// Creates a permission
+#[tracing::instrument(skip(pool))]
+pub(crate) async fn create_permission(
+    pool: &PgPool,
+    permission: &Permission,
+) -> Result<Result<(), RoleError>, Report> {
+    let created = sqlx::query!(
+        "INSERT INTO permissions (name, description) VALUES ($1, $2)
+            ON CONFLICT (name) DO NOTHING",
+        permission.name,
+        permission.description,
+    )
+    .execute(pool)
+    .await?
+    .rows_affected();
+
+    Ok(if created == 0 {
+        Err(RoleError::NameTaken)
+    } else {
+        Ok(())
+    })
+}
+
+/// Deletes a permission, removing it from all roles
+///
+/// Returns whether the permission existed.
+#[tracing::instrument(skip(pool))]
+pub(crate) async fn delete_permission(pool: &PgPool, name: &str) -> Result<bool, Report> {
+    Ok(
+        sqlx::query!("DELETE FROM permissions WHERE name = $1", name)
+            .execute(pool)
+            .await?
+            .rows_affected()
+            > 0,
+    )
+}
+
+/// Lists all roles with their permissions
+#[tracing::instrument(skip(pool))]
+pub(crate) async fn list_roles(pool: &PgPool) -> Result<Vec<Role>, Report> {
+    Ok(sqlx::query_as!(
+        Role,
+        r#"SELECT
+            name,
+            description,
+            array_remove(array_agg(permission ORDER BY permission), NULL) AS "permissions!"
+        FROM roles LEFT JOIN role_permissions ON (role = name)
+        GROUP BY name
+        ORDER BY name"#
+    )
+    .fetch_all(pool)
+    .await?)
+}
+
+/// Sorts and deduplicates names, so they can be counted
+pub(super) fn normalize(names: &[String]) -> Vec<String> {
+    let mut names = names.to_vec();
+    names.sort_unstable();
+    names.dedup();
+    names
+}
+
+/// Replaces all permissions of a role
+///
+/// The caller has to drop the transaction on an error.
+async fn replace_permissions(
+    transaction: &mut Transaction<'_, Postgres>,
+    role: &str,
+    permissions: &[String],
+) -> Result<Result<(), RoleError>, Report> {
+    let permissions = normalize(permissions);
+
+    sqlx::query!("DELETE FROM role_permissions WHERE role = $1", role)
+        .execute(&mut *transaction)
+        .await?;
+    let inserted = sqlx::query!(
+        "INSERT INTO role_permissions (role, permission)
+            SELECT $1, name FROM permissions WHERE name = ANY($2)",
+        role,
+        &permissions,
+    )
+    .execute(&mut *transaction)
+    .await?
+    .rows_affected();
+
+    Ok(if usize::try_from(inserted)? == permissions.len() {
+        Ok(())
+    } else {
+        Err(RoleError::PermissionNotFound)
+    })
+}
+
+/// Creates a role with the given permissions
+#[tracing::instrument(skip(pool))]
+pub(crate) async fn create_role(
+    pool: &PgPool,
+    role: &Role,
+) -> Result<Result<(), RoleError>, Report> {
+    let mut transaction = pool.begin().await?;
+
+    let created = sqlx::query!(
+        "INSERT INTO roles (name, description) VALUES ($1, $2)
+            ON CONFLICT (name) DO NOTHING",
+        role.name,
+        role.description,
+    )
+    .execute(&mut transaction)
+    .await?
+    .rows_affected();
+    if created == 0 {
+        return Ok(Err(RoleError::NameTaken));
+    }
+
+    if let Err(err) = replace_permissions(&mut transaction, &role.name, &role.permissions).await? {
+        return Ok(Err(err));
+    }
+
+    transaction.commit().await?;
+
+    Ok(Ok(()))
+}
+
+/// Updates the description and/or the permissions of a role
+#[tracing::instrument(skip(pool))]
+pub(crate) async fn update_role(
+    pool: &PgPool,
+    name: &str,
+    update: &RoleUpdate,
+) -> Result<Result<(), RoleError>, Report> {
+    let mut transaction = pool.begin().await?;
+
+    let found = sqlx::query!(
+        "UPDATE roles SET description = coalesce($2, description) WHERE name = $1",
+        name,
+        update.description,
+    )
+    .execute(&mut transaction)
+    .await?
+    .rows_affected();
+    if found == 0 {
+        return Ok(Err(RoleError::RoleNotFound));
+    }
+
+    if let Some(permissions) = &update.permissions {
+        if let Err(err) = replace_permissions(&mut transaction, name, permissions).await? {
+            return Ok(Err(err));
+        }
+    }
+
+    transaction.commit().await?;
+
+    Ok(Ok(()))
+}
+
+/// Deletes a role, removing it from all users
+///
+/// Returns whether the role existed.
+#[tracing::instrument(skip(pool))]
+pub(crate) async fn delete_role(pool: &PgPool, name: &str) -> Result<bool, Report> {
+    Ok(sqlx::query!("DELETE FROM roles WHERE name = $1", name)
+        .execute(pool)
+        .await?
+        .rows_affected()
+        > 0)
+}
+
+/// Replaces all roles of a user
+#[tracing::instrument(skip(pool))]
+pub(crate) async fn set_user_roles(
+    pool: &PgPool,
+    user_id: &Uuid,
+    roles: &[String],
+) -> Result<Result<(), RoleError>, Report> {
+    let roles = normalize(roles);
+    let mut transaction = pool.begin().await?;
+
+    let user_exists = sqlx::query!("SELECT id FROM users WHERE id = $1", user_id)
+        .fetch_optional(&mut transaction)
+        .await?
+        .is_some();
+    if !user_exists {
+        return Ok(Err(RoleError::UserNotFound));
+    }
+
+    sqlx::query!("DELETE FROM user_roles WHERE user_id = $1", user_id)
+        .execute(&mut transaction)
+        .await?;
+    let inserted = sqlx::query!(
+        "INSERT INTO user_roles (user_id, role)
+            SELECT $1, name FROM roles WHERE name = ANY($2)",
+        user_id,
+        &roles,
+    )
+    .execute(&mut transaction)
+    .await?
+    .rows_affected();
+    if usize::try_from(inserted)? != roles.len() {
+        return Ok(Err(RoleError::RoleNotFound));
+    }
+
+    transaction.commit().await?;
+
+    Ok(Ok(()))
+}
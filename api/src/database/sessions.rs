@@ -0,0 +1,256 @@
+//! Managing the sessions of a user
+//!
+//! Sessions are created by [auth](super::auth), this module lists and
+//! revokes them. Revoking always removes the session from the redis
+//! cache too, otherwise [AuthenticatedSession](crate::middlewares::session::AuthenticatedSession)
+//! would keep accepting it.
+
+use std::time::Duration;
+
+use color_eyre::Report;
+use redis::{aio::Connection, AsyncCommands};
+use serde::Serialize;
+use sqlx::PgPool;
+use time::OffsetDateTime;
+use tracing::{debug, info};
+use uuid::Uuid;
+
+use crate::settings::SessionConfig;
+
+/// A session as shown to its owner
+#[derive(Debug, Serialize)]
+pub(crate) struct SessionInfo {
+    /// The session id, needed to revoke it
+    id: Uuid,
+    /// User agent of the login request
+    user_agent: Option<String>,
+    /// IP address of the login request
+    ip_address: Option<String>,
+    /// When the user logged in
+    #[serde(with = "time::serde::rfc3339")]
+    created_at: OffsetDateTime,
+    /// When the session was last used
+    #[serde(with = "time::serde::rfc3339")]
+    last_seen_at: OffsetDateTime,
+    /// Whether this is the session of the current request
+    current: bool,
+}
+
+/// Lists all sessions of the owner of `session_id`
+#[tracing::instrument(skip(pool))]
+pub(crate) async fn list_sessions(
+    pool: &PgPool,
+    session_id: &Uuid,
+) -> Result<Vec<SessionInfo>, Report> {
+    Ok(sqlx::query_as!(
+        SessionInfo,
+        r#"SELECT
+            others.id,
+            others.user_agent,
+            others.ip_address,
+            others.created_at AT TIME ZONE 'UTC' AS "created_at!",
+            others.last_seen_at AT TIME ZONE 'UTC' AS "last_seen_at!",
+            others.id = current.id AS "current!"
+        FROM
+            sessions AS current
+            INNER JOIN sessions AS others ON (current.user_id = others.user_id)
+        WHERE
+            current.id = $1
+        ORDER BY
+            others.last_seen_at DESC"#,
+        session_id
+    )
+    .fetch_all(pool)
+    .await?)
+}
+
+/// Revokes a session of the owner of `session_id`
+///
+/// Returns whether the session existed (and belonged to the same user).
+#[tracing::instrument(skip(pool, redis_connection))]
+pub(crate) async fn revoke_session(
+    pool: &PgPool,
+    redis_connection: &mut Connection,
+    session_id: &Uuid,
+    to_revoke: &Uuid,
+) -> Result<bool, Report> {
+    let revoked = sqlx::query!(
+        "DELETE FROM
+            sessions
+        USING
+            sessions AS current
+        WHERE
+            current.id = $1
+            AND sessions.id = $2
+            AND sessions.user_id = current.user_id",
+        session_id,
+        to_revoke,
+    )
+    .execute(pool)
+    .await?
+    .rows_affected()
+        > 0;
+
+    // Sessions of other users have to stay in the cache
+    if revoked {
+        redis_connection.del::<_, ()>(to_revoke.to_string()).await?;
+    }
+
+    Ok(revoked)
+}
+
+/// Revokes all sessions of the owner of `session_id`, except that one
+///
+/// Returns the number of revoked sessions.
+#[tracing::instrument(skip(pool, redis_connection))]
+pub(crate) async fn revoke_other_sessions(
+    pool: &PgPool,
+    redis_connection: &mut Connection,
+    session_id: &Uuid,
+) -> Result<usize, Report> {
+    let revoked = sqlx::query!(
+        "DELETE FROM
+            sessions
+        USING
+            sessions AS current
+        WHERE
+            current.id = $1
+            AND sessions.id <> current.id
+            AND sessions.user_id = current.user_id
+        RETURNING
+            sessions.id",
+        session_id,
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|record| record.id.to_string())
+    .collect::<Vec<_>>();
+
+    if !revoked.is_empty() {
+        redis_connection.del::<_, ()>(&revoked).await?;
+    }
+
+    Ok(revoked.len())
+}
+
+/// Revokes every session of the user, logging them out everywhere
+///
+/// Returns the number of revoked sessions.
+#[tracing::instrument(skip(pool, redis_connection))]
+pub(crate) async fn revoke_all_sessions(
+    pool: &PgPool,
+    redis_connection: &mut Connection,
+    user_id: &Uuid,
+) -> Result<usize, Report> {
+    let revoked = sqlx::query!(
+        "DELETE FROM sessions WHERE user_id = $1 RETURNING id",
+        user_id
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|record| record.id.to_string())
+    .collect::<Vec<_>>();
+
+    if !revoked.is_empty() {
+        redis_connection.del::<_, ()>(&revoked).await?;
+    }
+
+    Ok(revoked.len())
+}
+
+/// The state of a session found by [touch_session]
+pub(crate) enum SessionState {
+    /// The session can be used
+    Valid {
+        /// Owner of the session
+        user_id: Uuid,
+        /// Time until the absolute lifetime of the session is reached
+        remaining_lifetime: Duration,
+    },
+    /// The session existed, but has expired and was removed now
+    Expired,
+    /// There is no such session
+    NotFound,
+}
+
+/// Checks whether the session is still valid and marks it as used
+///
+/// Marking it as used resets the idle timeout, expired sessions
+/// are removed right away.
+#[tracing::instrument(skip(pool, config))]
+pub(crate) async fn touch_session(
+    pool: &PgPool,
+    config: &SessionConfig,
+    session_id: &Uuid,
+) -> Result<SessionState, Report> {
+    let lifetime = Duration::from_secs(config.lifetime).as_secs_f64();
+    let idle_timeout = Duration::from_secs(config.idle_timeout).as_secs_f64();
+
+    let Some(session) = sqlx::query!(
+        r#"SELECT
+            user_id,
+            created_at > NOW() - make_interval(secs => $2)
+                AND last_seen_at > NOW() - make_interval(secs => $3) AS "valid!",
+            EXTRACT(EPOCH FROM created_at + make_interval(secs => $2) - NOW())::float8
+                AS "remaining_lifetime!"
+        FROM sessions
+        WHERE id = $1"#,
+        session_id,
+        lifetime,
+        idle_timeout,
+    )
+    .fetch_optional(pool)
+    .await? else {
+        return Ok(SessionState::NotFound);
+    };
+
+    if !session.valid {
+        debug!("Session {session_id} expired");
+        sqlx::query!("DELETE FROM sessions WHERE id = $1", session_id)
+            .execute(pool)
+            .await?;
+        return Ok(SessionState::Expired);
+    }
+
+    sqlx::query!(
+        "UPDATE sessions SET last_seen_at = NOW() WHERE id = $1",
+        session_id
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(SessionState::Valid {
+        user_id: session.user_id,
+        remaining_lifetime: Duration::from_secs_f64(session.remaining_lifetime.max(0.0)),
+    })
+}
+
+/// Removes all expired sessions from the database
+///
+/// Their redis entries expire on their own.
+#[tracing::instrument(skip(pool, config))]
+pub(crate) async fn purge_expired_sessions(
+    pool: &PgPool,
+    config: &SessionConfig,
+) -> Result<(), Report> {
+    let purged = sqlx::query!(
+        "DELETE FROM
+            sessions
+        WHERE
+            created_at < NOW() - make_interval(secs => $1)
+            OR last_seen_at < NOW() - make_interval(secs => $2)",
+        Duration::from_secs(config.lifetime).as_secs_f64(),
+        Duration::from_secs(config.idle_timeout).as_secs_f64(),
+    )
+    .execute(pool)
+    .await?
+    .rows_affected();
+
+    if purged > 0 {
+        info!("Purged {purged} expired sessions");
+    }
+
+    Ok(())
+}
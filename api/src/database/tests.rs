@@ -0,0 +1,360 @@
+//! Tests against a real database
+//!
+//! Every test gets its own database with all migrations applied, see
+//! [sqlx::test]. Requires `DATABASE_URL` to point to a Postgres server,
+//! i.e. the one of `docker-compose.yaml`.
+
+use std::sync::Arc;
+
+use axum::{
+    extract::Query,
+    http::{header::AUTHORIZATION, HeaderMap, HeaderValue, StatusCode},
+    response::IntoResponse,
+    Extension, Form, Json,
+};
+use color_eyre::{eyre::eyre, Report};
+use serde_json::json;
+use sqlx::PgPool;
+use url::Url;
+use uuid::Uuid;
+
+use crate::{
+    error_handling::ApiError,
+    hashing::Hasher,
+    middlewares::{
+        permission::{ReadUsers, RequirePermission},
+        session::AuthenticatedSession,
+    },
+    oidc::{verify_access_token, SigningKey},
+    routes::oidc::{authorize_session, token, userinfo, AuthorizationRedirect, TokenResponse},
+    settings::Config,
+    types::EMail,
+};
+
+use super::{
+    get_user_by_id,
+    organizations::{set_active_organization, update_member, MemberUpdate},
+    update_current_user, User, UserUpdate,
+};
+
+/// Alice from `fixtures/users.sql`
+const ALICE: Uuid = Uuid::from_u128(0x1111_1111_1111_1111_1111_1111_1111_1111);
+/// The session of [ALICE]
+const ALICE_SESSION: Uuid = Uuid::from_u128(0xaaaa_aaaa_aaaa_aaaa_aaaa_aaaa_aaaa_aaaa);
+/// Bob from `fixtures/users.sql`
+const BOB: Uuid = Uuid::from_u128(0x2222_2222_2222_2222_2222_2222_2222_2222);
+/// The session of [BOB]
+const BOB_SESSION: Uuid = Uuid::from_u128(0xbbbb_bbbb_bbbb_bbbb_bbbb_bbbb_bbbb_bbbb);
+
+/// The client of `fixtures/oidc.sql`
+const CLIENT_ID: &str = "test-client";
+/// The only redirect URI of [CLIENT_ID]
+const REDIRECT_URI: &str = "https://client.example.com/callback";
+/// PKCE verifier from RFC 7636, Appendix B
+const CODE_VERIFIER: &str = "dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk";
+/// The S256 challenge of [CODE_VERIFIER]
+const CODE_CHALLENGE: &str = "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM";
+
+/// Loads a user which has to exist
+async fn user(pool: &PgPool, id: &Uuid) -> Result<User, Report> {
+    get_user_by_id(pool, id)
+        .await?
+        .ok_or_else(|| eyre!("User {id} does not exist"))
+}
+
+/// A patch must not touch any user but the owner of the session
+#[sqlx::test(fixtures("users"))]
+async fn patch_only_updates_session_owner(pool: PgPool) -> Result<(), Report> {
+    let updated = update_current_user(
+        &pool,
+        &ALICE_SESSION,
+        UserUpdate {
+            name: Some("Mallory".to_owned()),
+            email: None,
+            locale: Some("de".to_owned()),
+        },
+    )
+    .await?
+    .ok_or_else(|| eyre!("Session of Alice not found"))?;
+
+    assert_eq!(updated.id, ALICE);
+    assert_eq!(updated.name, "Mallory");
+    assert_eq!(updated.locale.as_deref(), Some("de"));
+
+    let bob = user(&pool, &BOB).await?;
+    assert_eq!(bob.name, "Bob");
+    assert_eq!(bob.email, EMail("bob@example.com".to_owned()));
+    assert_eq!(bob.locale, None);
+
+    Ok(())
+}
+
+/// An unknown session updates nobody instead of everybody
+#[sqlx::test(fixtures("users"))]
+async fn patch_with_unknown_session_updates_nobody(pool: PgPool) -> Result<(), Report> {
+    let updated = update_current_user(
+        &pool,
+        &Uuid::new_v4(),
+        UserUpdate {
+            name: Some("Mallory".to_owned()),
+            email: None,
+            locale: None,
+        },
+    )
+    .await?;
+    assert!(updated.is_none());
+
+    assert_eq!(user(&pool, &ALICE).await?.name, "Alice");
+    assert_eq!(user(&pool, &BOB).await?.name, "Bob");
+
+    Ok(())
+}
+
+/// Email changes have to be confirmed, so a patch ignores them
+#[sqlx::test(fixtures("users"))]
+async fn patch_does_not_change_email(pool: PgPool) -> Result<(), Report> {
+    let updated = update_current_user(
+        &pool,
+        &ALICE_SESSION,
+        UserUpdate {
+            name: None,
+            email: Some(EMail("mallory@example.com".to_owned())),
+            locale: None,
+        },
+    )
+    .await?
+    .ok_or_else(|| eyre!("Session of Alice not found"))?;
+
+    assert_eq!(updated.email, EMail("alice@example.com".to_owned()));
+    assert_eq!(
+        user(&pool, &BOB).await?.email,
+        EMail("bob@example.com".to_owned())
+    );
+
+    Ok(())
+}
+
+/// Turns the error of a route into a report, keeping its status
+fn api_error(error: ApiError) -> Report {
+    eyre!("Request failed with {}", error.into_response().status())
+}
+
+/// A config with OIDC enabled and the key of `fixtures/oidc_signing_key.pem`
+fn oidc_setup() -> Result<(Arc<Config>, Arc<SigningKey>), Report> {
+    let config: Config = serde_json::from_value(json!({
+        "database": { "url": "unused" },
+        "app": {},
+        "oidc": {
+            "issuer": "http://localhost:3779",
+            "signing_key": "unused",
+            "login_url": "http://localhost:5173/login",
+        },
+    }))?;
+    let key = SigningKey::from_pem(include_str!("fixtures/oidc_signing_key.pem"), "test")?;
+
+    Ok((Arc::new(config), Arc::new(key)))
+}
+
+/// Authorizes [CLIENT_ID] for Alice like the frontend would and returns
+/// the code
+async fn authorize_alice(pool: &PgPool) -> Result<String, Report> {
+    let request = serde_json::from_value(json!({
+        "response_type": "code",
+        "client_id": CLIENT_ID,
+        "redirect_uri": REDIRECT_URI,
+        "scope": "openid email",
+        "state": "xyz",
+        "code_challenge": CODE_CHALLENGE,
+        "code_challenge_method": "S256",
+    }))?;
+    let Json(AuthorizationRedirect { redirect_to }) = authorize_session(
+        Extension(pool.clone()),
+        Some(AuthenticatedSession(ALICE_SESSION)),
+        Query(request),
+    )
+    .await
+    .map_err(api_error)?;
+
+    let redirect = Url::parse(&redirect_to)?;
+    assert!(redirect_to.starts_with(REDIRECT_URI));
+    let param = |name: &str| {
+        redirect
+            .query_pairs()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.into_owned())
+    };
+    assert_eq!(param("state").as_deref(), Some("xyz"));
+
+    param("code").ok_or_else(|| eyre!("No code in {redirect_to}"))
+}
+
+/// Exchanges a code at the token endpoint
+async fn exchange_code(
+    pool: &PgPool,
+    config: &Arc<Config>,
+    key: &Arc<SigningKey>,
+    code: &str,
+) -> Result<TokenResponse, ApiError> {
+    let request = serde_json::from_value(json!({
+        "grant_type": "authorization_code",
+        "code": code,
+        "redirect_uri": REDIRECT_URI,
+        "code_verifier": CODE_VERIFIER,
+        "client_id": CLIENT_ID,
+    }))
+    .map_err(|e| ApiError::UnknownError(e.into()))?;
+    let hasher = Hasher::new(&config.hashing)?;
+
+    let (_, Json(response)) = token(
+        Extension(pool.clone()),
+        Extension(Arc::clone(config)),
+        Extension(Arc::clone(key)),
+        Extension(Arc::new(hasher)),
+        HeaderMap::new(),
+        Form(request),
+    )
+    .await?;
+
+    Ok(response)
+}
+
+/// Calls the userinfo endpoint with an access token
+async fn get_userinfo(
+    pool: &PgPool,
+    config: &Arc<Config>,
+    key: &Arc<SigningKey>,
+    access_token: &str,
+) -> Result<serde_json::Value, ApiError> {
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        AUTHORIZATION,
+        HeaderValue::from_str(&format!("Bearer {access_token}"))
+            .map_err(|e| ApiError::UnknownError(e.into()))?,
+    );
+
+    let Json(claims) = userinfo(
+        Extension(pool.clone()),
+        Extension(Arc::clone(config)),
+        Extension(Arc::clone(key)),
+        headers,
+    )
+    .await?;
+
+    serde_json::to_value(claims).map_err(|e| ApiError::UnknownError(e.into()))
+}
+
+/// A logged in user gets a code, which can be exchanged exactly once
+/// for tokens of that user
+#[sqlx::test(fixtures("users", "oidc"))]
+async fn oidc_authorize_and_token(pool: PgPool) -> Result<(), Report> {
+    let (config, key) = oidc_setup()?;
+    let oidc = config
+        .oidc
+        .as_ref()
+        .ok_or_else(|| eyre!("OIDC is not configured"))?;
+
+    let code = authorize_alice(&pool).await?;
+    let tokens = exchange_code(&pool, &config, &key, &code)
+        .await
+        .map_err(api_error)?;
+    assert_eq!(tokens.token_type, "Bearer");
+    assert_eq!(tokens.scope, "openid email");
+
+    let claims = verify_access_token(oidc, &key, &tokens.access_token)
+        .ok_or_else(|| eyre!("Access token is invalid"))?;
+    assert_eq!(claims.sub, ALICE);
+
+    let info = get_userinfo(&pool, &config, &key, &tokens.access_token)
+        .await
+        .map_err(api_error)?;
+    assert_eq!(info["sub"], json!(ALICE));
+    assert_eq!(info["email"], json!("alice@example.com"));
+
+    assert!(exchange_code(&pool, &config, &key, &code).await.is_err());
+
+    Ok(())
+}
+
+/// Disabling a user invalidates their codes and access tokens
+#[sqlx::test(fixtures("users", "oidc"))]
+async fn oidc_rejects_disabled_users(pool: PgPool) -> Result<(), Report> {
+    let (config, key) = oidc_setup()?;
+
+    let code = authorize_alice(&pool).await?;
+    let tokens = exchange_code(&pool, &config, &key, &code)
+        .await
+        .map_err(api_error)?;
+    let unused_code = authorize_alice(&pool).await?;
+
+    sqlx::query!("UPDATE users SET disabled_at = NOW() WHERE id = $1", ALICE)
+        .execute(&pool)
+        .await?;
+
+    assert!(exchange_code(&pool, &config, &key, &unused_code)
+        .await
+        .is_err());
+    assert!(get_userinfo(&pool, &config, &key, &tokens.access_token)
+        .await
+        .is_err());
+
+    Ok(())
+}
+
+/// Organization admins can give themselves any role within their
+/// organization, which must not grant permissions over all users
+#[sqlx::test(fixtures("users"))]
+async fn organization_roles_grant_no_global_permissions(pool: PgPool) -> Result<(), Report> {
+    let organization = Uuid::new_v4();
+    sqlx::query!("INSERT INTO roles (name) VALUES ('reader')")
+        .execute(&pool)
+        .await?;
+    sqlx::query!("INSERT INTO role_permissions (role, permission) VALUES ('reader', 'users:read')")
+        .execute(&pool)
+        .await?;
+    sqlx::query!(
+        "INSERT INTO organizations (id, name) VALUES ($1, 'Acme')",
+        organization
+    )
+    .execute(&pool)
+    .await?;
+    sqlx::query!(
+        "INSERT INTO organization_members (organization_id, user_id, is_admin) VALUES ($1, $2, true)",
+        organization,
+        ALICE,
+    )
+    .execute(&pool)
+    .await?;
+
+    update_member(
+        &pool,
+        &organization,
+        &ALICE,
+        &MemberUpdate {
+            is_admin: None,
+            roles: Some(vec!["reader".to_owned()]),
+        },
+    )
+    .await?
+    .map_err(|e| eyre!("Updating the member failed: {e:?}"))?;
+    set_active_organization(&pool, &ALICE_SESSION, Some(organization))
+        .await?
+        .map_err(|e| eyre!("Activating the organization failed: {e:?}"))?;
+
+    let Err(error) = RequirePermission::<ReadUsers>::check(&pool, &ALICE_SESSION).await else {
+        return Err(eyre!("The organization role granted users:read"));
+    };
+    assert_eq!(error.into_response().status(), StatusCode::FORBIDDEN);
+
+    // The same role does grant it globally
+    sqlx::query!(
+        "INSERT INTO user_roles (user_id, role) VALUES ($1, 'reader')",
+        BOB
+    )
+    .execute(&pool)
+    .await?;
+    RequirePermission::<ReadUsers>::check(&pool, &BOB_SESSION)
+        .await
+        .map_err(api_error)?;
+
+    Ok(())
+}
@@ -0,0 +1,173 @@
+//! Storage of TOTP secrets
+//!
+//! Like [auth](super::auth) for passwords, this is the only
+//! place that reads the secrets back from the database, the actual
+//! algorithm lives in [otp](crate::otp).
+
+use color_eyre::{eyre::Context, Report};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::otp;
+
+/// The known errors of managing TOTP
+pub(crate) enum TotpError {
+    /// The user already has a confirmed TOTP secret, it has to be
+    /// disabled before a new one can be enrolled
+    AlreadyEnabled,
+    /// There is no (pending or confirmed, depending on the operation)
+    /// TOTP secret for this user
+    NotEnrolled,
+    /// The submitted code was wrong, expired or already used
+    InvalidCode,
+}
+
+/// Generates and stores a new, unconfirmed secret for the user
+///
+/// A previous unconfirmed secret is replaced, so restarting the
+/// enrolment is always possible. Returns the raw secret which has
+/// to be shown to the user exactly once.
+#[tracing::instrument(skip(pool))]
+pub(crate) async fn begin_enrolment(
+    pool: &PgPool,
+    user_id: &Uuid,
+) -> Result<Result<Vec<u8>, TotpError>, Report> {
+    let secret = otp::generate_secret();
+
+    let stored = sqlx::query!(
+        "INSERT INTO
+            totp_secrets (user_id, secret)
+        VALUES
+            ($1, $2)
+        ON CONFLICT(user_id) DO
+            UPDATE SET
+                secret = EXCLUDED.secret,
+                last_used_step = NULL,
+                created_at = EXCLUDED.created_at
+            WHERE
+                totp_secrets.confirmed_at IS NULL
+        RETURNING user_id",
+        user_id,
+        secret,
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(match stored {
+        Some(_) => Ok(secret),
+        None => Err(TotpError::AlreadyEnabled),
+    })
+}
+
+/// Confirms a pending enrolment with the first code of the authenticator
+///
+/// From now on the user needs a code to log in.
+#[tracing::instrument(skip(pool, code))]
+pub(crate) async fn confirm_enrolment(
+    pool: &PgPool,
+    user_id: &Uuid,
+    code: &str,
+) -> Result<Result<(), TotpError>, Report> {
+    let Some(pending) = sqlx::query!(
+        "SELECT secret FROM totp_secrets WHERE user_id = $1 AND confirmed_at IS NULL",
+        user_id
+    )
+    .fetch_optional(pool)
+    .await? else {
+        return Ok(Err(TotpError::NotEnrolled));
+    };
+
+    let Some(step) = otp::verify(&pending.secret, code, None)? else {
+        return Ok(Err(TotpError::InvalidCode));
+    };
+
+    sqlx::query!(
+        "UPDATE totp_secrets
+            SET confirmed_at = NOW(), last_used_step = $2
+            WHERE user_id = $1",
+        user_id,
+        i64::try_from(step).wrap_err("TOTP step does not fit into the database")?,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(Ok(()))
+}
+
+/// Removes the confirmed TOTP secret of the user after checking `code`
+#[tracing::instrument(skip(pool, code))]
+pub(crate) async fn disable(
+    pool: &PgPool,
+    user_id: &Uuid,
+    code: &str,
+) -> Result<Result<(), TotpError>, Report> {
+    match verify_code(pool, user_id, code).await? {
+        Ok(()) => {
+            sqlx::query!("DELETE FROM totp_secrets WHERE user_id = $1", user_id)
+                .execute(pool)
+                .await?;
+            Ok(Ok(()))
+        }
+        Err(e) => Ok(Err(e)),
+    }
+}
+
+/// Whether the user has a confirmed TOTP secret
+#[tracing::instrument(skip(pool))]
+pub(crate) async fn is_enabled(pool: &PgPool, user_id: &Uuid) -> Result<bool, Report> {
+    Ok(sqlx::query!(
+        "SELECT user_id FROM totp_secrets WHERE user_id = $1 AND confirmed_at IS NOT NULL",
+        user_id
+    )
+    .fetch_optional(pool)
+    .await?
+    .is_some())
+}
+
+/// Checks `code` against the confirmed secret of the user
+///
+/// A successfully used code is remembered and can not be used again.
+#[tracing::instrument(skip(pool, code))]
+pub(crate) async fn verify_code(
+    pool: &PgPool,
+    user_id: &Uuid,
+    code: &str,
+) -> Result<Result<(), TotpError>, Report> {
+    let Some(saved) = sqlx::query!(
+        "SELECT secret, last_used_step FROM totp_secrets
+            WHERE user_id = $1 AND confirmed_at IS NOT NULL",
+        user_id
+    )
+    .fetch_optional(pool)
+    .await? else {
+        return Ok(Err(TotpError::NotEnrolled));
+    };
+
+    let last_used_step = saved
+        .last_used_step
+        .map(u64::try_from)
+        .transpose()
+        .wrap_err("Negative TOTP step in database")?;
+
+    let Some(step) = otp::verify(&saved.secret, code, last_used_step)? else {
+        return Ok(Err(TotpError::InvalidCode));
+    };
+    let step = i64::try_from(step).wrap_err("TOTP step does not fit into the database")?;
+
+    // Guards against the same code being used by two concurrent requests
+    let updated = sqlx::query!(
+        "UPDATE totp_secrets
+            SET last_used_step = $2
+            WHERE user_id = $1 AND (last_used_step IS NULL OR last_used_step < $2)",
+        user_id,
+        step,
+    )
+    .execute(pool)
+    .await?;
+
+    if updated.rows_affected() == 0 {
+        Ok(Err(TotpError::InvalidCode))
+    } else {
+        Ok(Ok(()))
+    }
+}
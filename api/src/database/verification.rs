@@ -0,0 +1,128 @@
+//! Verifying that users own their email address
+//!
+//! A token is bound to the address it was sent to, so it becomes
+//! useless once the user changes their email again.
+
+use std::time::Duration;
+
+use color_eyre::Report;
+use sqlx::PgPool;
+use tracing::info;
+use uuid::Uuid;
+
+use crate::{settings::VerificationConfig, types::EMail};
+
+/// The known errors of [verify_email]
+#[derive(Debug)]
+pub(crate) enum VerificationError {
+    /// The token does not exist or the user changed their email since
+    TokenNotFound,
+    /// The token exists, but is older than the configured lifetime.
+    /// It is deleted anyway, so the user has to request a new one.
+    TokenExpired,
+}
+
+/// Creates a token verifying that `email` belongs to the user
+#[tracing::instrument(skip(pool))]
+pub(crate) async fn create_verification(
+    pool: &PgPool,
+    user_id: &Uuid,
+    email: &EMail,
+) -> Result<Uuid, Report> {
+    let token = Uuid::new_v4();
+    sqlx::query!(
+        "INSERT INTO email_verifications (id, user_id, email) VALUES ($1, $2, $3)",
+        token,
+        user_id,
+        email.0,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(token)
+}
+
+/// Marks the email of the user as verified and consumes the token
+#[tracing::instrument(skip(pool, config))]
+pub(crate) async fn verify_email(
+    pool: &PgPool,
+    config: &VerificationConfig,
+    token: &Uuid,
+) -> Result<Result<(), VerificationError>, Report> {
+    let mut transaction = pool.begin().await?;
+
+    let Some(verification) = sqlx::query!(
+        r#"DELETE FROM
+            email_verifications
+        WHERE
+            id = $1
+        RETURNING
+            user_id,
+            email,
+            created_at > NOW() - make_interval(secs => $2) AS "fresh!""#,
+        token,
+        Duration::from_secs(config.token_lifetime).as_secs_f64(),
+    )
+    .fetch_optional(&mut transaction)
+    .await? else {
+        return Ok(Err(VerificationError::TokenNotFound));
+    };
+    if !verification.fresh {
+        // Commit, so the expired token is gone for good
+        transaction.commit().await?;
+        return Ok(Err(VerificationError::TokenExpired));
+    }
+
+    let verified = sqlx::query!(
+        "UPDATE users
+            SET email_verified_at = coalesce(email_verified_at, NOW())
+            WHERE id = $1 AND email = $2",
+        verification.user_id,
+        verification.email,
+    )
+    .execute(&mut transaction)
+    .await?
+    .rows_affected();
+
+    // The token is useless either way
+    transaction.commit().await?;
+
+    if verified == 0 {
+        return Ok(Err(VerificationError::TokenNotFound));
+    }
+
+    Ok(Ok(()))
+}
+
+/// Whether the user has verified their current email
+#[tracing::instrument(skip(pool))]
+pub(crate) async fn is_email_verified(pool: &PgPool, user_id: &Uuid) -> Result<bool, Report> {
+    Ok(
+        sqlx::query!("SELECT email_verified_at FROM users WHERE id = $1", user_id)
+            .fetch_optional(pool)
+            .await?
+            .and_then(|user| user.email_verified_at)
+            .is_some(),
+    )
+}
+
+/// Removes all expired verification tokens
+#[tracing::instrument(skip(pool, config))]
+pub(crate) async fn purge_expired_verifications(
+    pool: &PgPool,
+    config: &VerificationConfig,
+) -> Result<(), Report> {
+    let purged = sqlx::query!(
+        "DELETE FROM email_verifications WHERE created_at < NOW() - make_interval(secs => $1)",
+        Duration::from_secs(config.token_lifetime).as_secs_f64(),
+    )
+    .execute(pool)
+    .await?
+    .rows_affected();
+
+    if purged > 0 {
+        info!("Purged {purged} expired email verifications");
+    }
+
+    Ok(())
+}
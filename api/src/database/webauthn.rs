@@ -0,0 +1,399 @@
+//! Storage of WebAuthn credentials and ceremonies
+//!
+//! The cryptography is done by [webauthn_rs], this module only stores
+//! the resulting [Passkey]s and the state in between the two steps of
+//! a ceremony. Ceremony state is short lived and thus kept in redis.
+
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use color_eyre::{eyre::Context, Report};
+use hmac::{Hmac, Mac};
+use redis::{aio::Connection, AsyncCommands};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::json;
+use sha2::Sha256;
+use sqlx::{types::Json, PgPool};
+use time::OffsetDateTime;
+use uuid::Uuid;
+use webauthn_rs::prelude::{
+    AuthenticationResult, CreationChallengeResponse, Passkey, PasskeyAuthentication,
+    PasskeyRegistration, PublicKeyCredential, RegisterPublicKeyCredential,
+    RequestChallengeResponse, Webauthn, WebauthnError,
+};
+
+use crate::settings::WebauthnConfig;
+
+use super::User;
+
+/// How long the client has to answer a challenge, in seconds
+const CEREMONY_TIMEOUT_SECONDS: usize = 300;
+/// Redis key of the secret the credential ids of [dummy_authentication]
+/// are derived from
+const DUMMY_SECRET_KEY: &str = "webauthn_dummy_secret";
+
+/// The known errors of WebAuthn ceremonies
+#[derive(Debug)]
+pub(crate) enum PasskeyError {
+    /// The ceremony does not exist, either it timed out or it has
+    /// already been finished
+    CeremonyNotFound,
+    /// The user has no credentials to authenticate with
+    NoPasskeys,
+    /// The browser response did not pass verification
+    Rejected(WebauthnError),
+}
+
+/// A registered credential, as shown to its owner
+#[derive(Debug, Serialize)]
+pub(crate) struct WebauthnCredential {
+    /// Used to remove the credential
+    id: Uuid,
+    /// Name given while registering
+    name: String,
+    /// When the credential was registered
+    #[serde(with = "time::serde::rfc3339")]
+    created_at: OffsetDateTime,
+    /// When the credential was last used to log in
+    #[serde(with = "time::serde::rfc3339::option")]
+    last_used_at: Option<OffsetDateTime>,
+}
+
+/// State of a registration, remembered between both steps
+#[derive(Serialize, Deserialize)]
+struct RegistrationCeremony {
+    /// The user who started the registration
+    user_id: Uuid,
+    /// Name of the credential
+    name: String,
+    /// State as required by [Webauthn::finish_passkey_registration]
+    state: PasskeyRegistration,
+}
+
+/// State of an authentication, remembered between both steps
+#[derive(Serialize, Deserialize)]
+struct AuthenticationCeremony {
+    /// The user who has to authenticate
+    user_id: Uuid,
+    /// State as required by [Webauthn::finish_passkey_authentication]
+    state: PasskeyAuthentication,
+}
+
+/// Stores the state of a ceremony until it times out
+async fn store_ceremony<T: Serialize>(
+    redis_connection: &mut Connection,
+    key: &str,
+    ceremony: &T,
+) -> Result<(), Report> {
+    redis_connection
+        .set_ex::<_, _, ()>(
+            key,
+            serde_json::to_string(ceremony)?,
+            CEREMONY_TIMEOUT_SECONDS,
+        )
+        .await
+        .wrap_err("Storing WebAuthn ceremony")
+}
+
+/// Removes the state of a ceremony and returns it
+///
+/// Since the state is removed every challenge can only be answered once.
+async fn take_ceremony<T: DeserializeOwned>(
+    redis_connection: &mut Connection,
+    key: &str,
+) -> Result<Option<T>, Report> {
+    let (ceremony,): (Option<String>,) = redis::pipe()
+        .atomic()
+        .get(key)
+        .del(key)
+        .ignore()
+        .query_async(redis_connection)
+        .await
+        .wrap_err("Retrieving WebAuthn ceremony")?;
+
+    ceremony
+        .map(|ceremony| serde_json::from_str(&ceremony))
+        .transpose()
+        .wrap_err("Malformed WebAuthn ceremony in redis")
+}
+
+/// All passkeys of the user
+#[tracing::instrument(skip(pool))]
+async fn get_passkeys(pool: &PgPool, user_id: &Uuid) -> Result<Vec<Passkey>, Report> {
+    Ok(sqlx::query!(
+        r#"SELECT passkey AS "passkey: Json<Passkey>" FROM webauthn_credentials WHERE user_id = $1"#,
+        user_id
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|record| record.passkey.0)
+    .collect())
+}
+
+/// Whether the user has registered at least one credential
+#[tracing::instrument(skip(pool))]
+pub(crate) async fn has_passkeys(pool: &PgPool, user_id: &Uuid) -> Result<bool, Report> {
+    Ok(sqlx::query!(
+        "SELECT id FROM webauthn_credentials WHERE user_id = $1 LIMIT 1",
+        user_id
+    )
+    .fetch_optional(pool)
+    .await?
+    .is_some())
+}
+
+/// Lists the credentials of the user
+#[tracing::instrument(skip(pool))]
+pub(crate) async fn list_credentials(
+    pool: &PgPool,
+    user_id: &Uuid,
+) -> Result<Vec<WebauthnCredential>, Report> {
+    Ok(sqlx::query_as!(
+        WebauthnCredential,
+        r#"SELECT
+            id,
+            name,
+            created_at AT TIME ZONE 'UTC' AS "created_at!",
+            last_used_at AT TIME ZONE 'UTC' AS last_used_at
+        FROM webauthn_credentials
+        WHERE user_id = $1
+        ORDER BY created_at"#,
+        user_id
+    )
+    .fetch_all(pool)
+    .await?)
+}
+
+/// Removes a credential of the user, returns whether it existed
+#[tracing::instrument(skip(pool))]
+pub(crate) async fn remove_credential(
+    pool: &PgPool,
+    user_id: &Uuid,
+    credential_id: &Uuid,
+) -> Result<bool, Report> {
+    Ok(sqlx::query!(
+        "DELETE FROM webauthn_credentials WHERE id = $1 AND user_id = $2",
+        credential_id,
+        user_id,
+    )
+    .execute(pool)
+    .await?
+    .rows_affected()
+        > 0)
+}
+
+/// Starts registering a new credential for the user
+///
+/// Returns the id of the ceremony and the options which have to be
+/// passed to `navigator.credentials.create()`. Passkeys are created
+/// with attestation "none", so any authenticator is accepted.
+#[tracing::instrument(skip(pool, redis_connection, webauthn))]
+pub(crate) async fn begin_registration(
+    pool: &PgPool,
+    redis_connection: &mut Connection,
+    webauthn: &Webauthn,
+    user: &User,
+    name: String,
+) -> Result<(Uuid, CreationChallengeResponse), Report> {
+    let existing = get_passkeys(pool, &user.id)
+        .await?
+        .iter()
+        .map(|passkey| passkey.cred_id().clone())
+        .collect();
+
+    let (options, state) =
+        webauthn.start_passkey_registration(user.id, &user.email.0, &user.name, Some(existing))?;
+
+    let ceremony_id = Uuid::new_v4();
+    store_ceremony(
+        redis_connection,
+        &format!("webauthn_registration:{ceremony_id}"),
+        &RegistrationCeremony {
+            user_id: user.id,
+            name,
+            state,
+        },
+    )
+    .await?;
+
+    Ok((ceremony_id, options))
+}
+
+/// Verifies the response of the authenticator and stores the credential
+#[tracing::instrument(skip(pool, redis_connection, webauthn, credential))]
+pub(crate) async fn finish_registration(
+    pool: &PgPool,
+    redis_connection: &mut Connection,
+    webauthn: &Webauthn,
+    user_id: &Uuid,
+    ceremony_id: &Uuid,
+    credential: &RegisterPublicKeyCredential,
+) -> Result<Result<(), PasskeyError>, Report> {
+    let Some(ceremony) = take_ceremony::<RegistrationCeremony>(
+        redis_connection,
+        &format!("webauthn_registration:{ceremony_id}"),
+    )
+    .await? else {
+        return Ok(Err(PasskeyError::CeremonyNotFound));
+    };
+    if ceremony.user_id != *user_id {
+        return Ok(Err(PasskeyError::CeremonyNotFound));
+    }
+
+    let passkey = match webauthn.finish_passkey_registration(credential, &ceremony.state) {
+        Ok(passkey) => passkey,
+        Err(e) => return Ok(Err(PasskeyError::Rejected(e))),
+    };
+
+    sqlx::query!(
+        "INSERT INTO
+            webauthn_credentials (id, credential_id, user_id, name, passkey)
+        VALUES
+            ($1, $2, $3, $4, $5)",
+        Uuid::new_v4(),
+        passkey.cred_id().0,
+        user_id,
+        ceremony.name,
+        Json(&passkey) as _,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(Ok(()))
+}
+
+/// Starts an authentication with any credential of the user
+///
+/// `key` identifies the ceremony and is needed to finish it.
+#[tracing::instrument(skip(pool, redis_connection, webauthn))]
+pub(crate) async fn begin_authentication(
+    pool: &PgPool,
+    redis_connection: &mut Connection,
+    webauthn: &Webauthn,
+    key: &str,
+    user_id: &Uuid,
+) -> Result<Result<RequestChallengeResponse, PasskeyError>, Report> {
+    let passkeys = get_passkeys(pool, user_id).await?;
+    if passkeys.is_empty() {
+        return Ok(Err(PasskeyError::NoPasskeys));
+    }
+
+    let (options, state) = webauthn.start_passkey_authentication(&passkeys)?;
+
+    store_ceremony(
+        redis_connection,
+        key,
+        &AuthenticationCeremony {
+            user_id: *user_id,
+            state,
+        },
+    )
+    .await?;
+
+    Ok(Ok(options))
+}
+
+/// Builds options which look like the ones of [begin_authentication]
+///
+/// Used for users who don't exist or have no passkeys, to not reveal
+/// that. The credential id is derived from `subject` with a secret, so
+/// repeated requests return the same one like for a real user. Nothing
+/// is stored, so the ceremony can never be finished.
+#[tracing::instrument(skip(redis_connection, config, subject))]
+pub(crate) async fn dummy_authentication(
+    redis_connection: &mut Connection,
+    config: &WebauthnConfig,
+    subject: &str,
+) -> Result<RequestChallengeResponse, Report> {
+    let mut secret = [0; 32];
+    OsRng.fill_bytes(&mut secret);
+    // Only the first request ever stores its secret, all instances share it
+    redis_connection
+        .set_nx::<_, _, ()>(DUMMY_SECRET_KEY, secret.as_slice())
+        .await?;
+    let secret: Vec<u8> = redis_connection.get(DUMMY_SECRET_KEY).await?;
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(&secret).expect("HMAC can take a key of any size");
+    mac.update(subject.as_bytes());
+    let credential_id = mac.finalize().into_bytes();
+
+    let mut challenge = [0; 32];
+    OsRng.fill_bytes(&mut challenge);
+
+    // Same fields and values as webauthn_rs uses for passkeys
+    Ok(serde_json::from_value(json!({
+        "publicKey": {
+            "challenge": URL_SAFE_NO_PAD.encode(challenge),
+            "timeout": CEREMONY_TIMEOUT_SECONDS * 1000,
+            "rpId": config.rp_id,
+            "allowCredentials": [{
+                "type": "public-key",
+                "id": URL_SAFE_NO_PAD.encode(credential_id),
+            }],
+            "userVerification": "required",
+            "extensions": { "uvm": true },
+        }
+    }))?)
+}
+
+/// Verifies the assertion of the authenticator
+///
+/// Returns the id of the authenticated user. The sign counter of
+/// the used credential is updated, a counter that did not increase
+/// is rejected by [webauthn_rs] as a possibly cloned authenticator.
+#[tracing::instrument(skip(pool, redis_connection, webauthn, credential))]
+pub(crate) async fn finish_authentication(
+    pool: &PgPool,
+    redis_connection: &mut Connection,
+    webauthn: &Webauthn,
+    key: &str,
+    credential: &PublicKeyCredential,
+) -> Result<Result<Uuid, PasskeyError>, Report> {
+    let Some(ceremony) = take_ceremony::<AuthenticationCeremony>(redis_connection, key).await? else {
+        return Ok(Err(PasskeyError::CeremonyNotFound));
+    };
+
+    let result = match webauthn.finish_passkey_authentication(credential, &ceremony.state) {
+        Ok(result) => result,
+        Err(e) => return Ok(Err(PasskeyError::Rejected(e))),
+    };
+
+    update_passkey(pool, &result).await?;
+
+    Ok(Ok(ceremony.user_id))
+}
+
+/// Stores the new sign counter (and backup state) of the used credential
+#[tracing::instrument(skip(pool, result))]
+async fn update_passkey(pool: &PgPool, result: &AuthenticationResult) -> Result<(), Report> {
+    let mut transaction = pool.begin().await?;
+
+    let mut passkey = sqlx::query!(
+        r#"SELECT passkey AS "passkey: Json<Passkey>"
+            FROM webauthn_credentials
+            WHERE credential_id = $1
+            FOR UPDATE"#,
+        result.cred_id().0,
+    )
+    .fetch_one(&mut transaction)
+    .await?
+    .passkey
+    .0;
+
+    passkey.update_credential(result);
+
+    sqlx::query!(
+        "UPDATE webauthn_credentials
+            SET passkey = $2, sign_count = $3, last_used_at = NOW()
+            WHERE credential_id = $1",
+        result.cred_id().0,
+        Json(&passkey) as _,
+        i64::from(result.counter()),
+    )
+    .execute(&mut transaction)
+    .await?;
+
+    transaction.commit().await?;
+
+    Ok(())
+}
@@ -45,37 +45,233 @@
 //! side, thus the handler can match on this enum and return the appropriate
 //! 4XX status codes.
 
-use axum::{http::StatusCode, response::IntoResponse, Json};
+use axum::{
+    http::{header::RETRY_AFTER, StatusCode},
+    response::IntoResponse,
+    Json,
+};
 use color_eyre::Report;
 use serde::Serialize;
 use tracing::error;
 
+use webauthn_rs::prelude::WebauthnError;
+
+use crate::{
+    database::{
+        admin::AdminError, auth::LoginError, email_change::EmailChangeError,
+        organizations::OrganizationError, registration::RegistrationError, roles::RoleError,
+        totp::TotpError, verification::VerificationError, webauthn::PasskeyError,
+    },
+    password_policy::PolicyViolation,
+};
+
 impl From<Report> for ApiError {
     fn from(value: Report) -> Self {
         ApiError::UnknownError(value)
     }
 }
 
+impl From<LoginError> for ApiError {
+    fn from(value: LoginError) -> Self {
+        match value {
+            LoginError::UserNotFound => ApiError::UserNotFound,
+            LoginError::InvalidCredentials => ApiError::WrongCredentials,
+            LoginError::ChallengeNotFound => ApiError::ChallengeNotFound,
+            LoginError::InvalidSecondFactor => ApiError::InvalidSecondFactor,
+            LoginError::NoPasskeys => ApiError::NoPasskeys,
+            LoginError::EmailNotVerified => ApiError::EmailNotVerified,
+            LoginError::UserDisabled => ApiError::UserDisabled,
+            LoginError::MagicLinkNotFound => ApiError::TokenNotFound,
+            LoginError::MagicLinkExpired => ApiError::TokenExpired,
+            LoginError::TooManyAttempts(retry_after) => ApiError::TooManyAttempts(retry_after),
+        }
+    }
+}
+
+impl From<TotpError> for ApiError {
+    fn from(value: TotpError) -> Self {
+        match value {
+            TotpError::AlreadyEnabled => ApiError::TotpAlreadyEnabled,
+            TotpError::NotEnrolled => ApiError::TotpNotEnrolled,
+            TotpError::InvalidCode => ApiError::InvalidSecondFactor,
+        }
+    }
+}
+
+impl From<PasskeyError> for ApiError {
+    fn from(value: PasskeyError) -> Self {
+        match value {
+            PasskeyError::CeremonyNotFound => ApiError::CeremonyNotFound,
+            PasskeyError::NoPasskeys => ApiError::NoPasskeys,
+            PasskeyError::Rejected(e) => ApiError::PasskeyRejected(e),
+        }
+    }
+}
+
+impl From<RegistrationError> for ApiError {
+    fn from(value: RegistrationError) -> Self {
+        match value {
+            RegistrationError::Disabled => ApiError::RegistrationDisabled,
+            RegistrationError::InvalidInvite => ApiError::InvalidInvite,
+            RegistrationError::EmailTaken => ApiError::EmailTaken,
+            RegistrationError::WeakPassword(violations) => ApiError::WeakPassword(violations),
+        }
+    }
+}
+
+impl From<VerificationError> for ApiError {
+    fn from(value: VerificationError) -> Self {
+        match value {
+            VerificationError::TokenNotFound => ApiError::TokenNotFound,
+            VerificationError::TokenExpired => ApiError::TokenExpired,
+        }
+    }
+}
+
+impl From<EmailChangeError> for ApiError {
+    fn from(value: EmailChangeError) -> Self {
+        match value {
+            EmailChangeError::TokenNotFound => ApiError::TokenNotFound,
+            EmailChangeError::TokenExpired => ApiError::TokenExpired,
+            EmailChangeError::EmailTaken => ApiError::EmailTaken,
+        }
+    }
+}
+
+impl From<AdminError> for ApiError {
+    fn from(value: AdminError) -> Self {
+        match value {
+            AdminError::UserNotFound => ApiError::UserNotFound,
+            AdminError::EmailTaken => ApiError::EmailTaken,
+        }
+    }
+}
+
+impl From<RoleError> for ApiError {
+    fn from(value: RoleError) -> Self {
+        match value {
+            RoleError::RoleNotFound => ApiError::RoleNotFound,
+            RoleError::PermissionNotFound => ApiError::PermissionNotFound,
+            RoleError::UserNotFound => ApiError::UserNotFound,
+            RoleError::NameTaken => ApiError::NameTaken,
+        }
+    }
+}
+
+impl From<OrganizationError> for ApiError {
+    fn from(value: OrganizationError) -> Self {
+        match value {
+            OrganizationError::MemberNotFound => ApiError::MemberNotFound,
+            OrganizationError::RoleNotFound => ApiError::RoleNotFound,
+            OrganizationError::InviteNotFound => ApiError::TokenNotFound,
+            OrganizationError::InviteExpired => ApiError::TokenExpired,
+            OrganizationError::WrongEmail => ApiError::InviteForOtherEmail,
+        }
+    }
+}
+
 /// The type for all possible Errors that can be returned by a handler.
 pub(crate) enum ApiError {
     /// A request contains an email or user-id, that does not map to an user
     UserNotFound,
     /// A session id was specified that does'nt map to a session
     InvalidSession,
+    /// The session to revoke does not exist or belongs to another user
+    SessionNotFound,
     /// Session was specified using a invalid syntax, details
     /// are in the inner Report.
     MisformedAuth(Report),
     /// A specified token (i.e. for password reset) was not found
     TokenNotFound,
+    /// A specified token (i.e. for password reset) exists, but has expired
+    TokenExpired,
     /// The wrong password was submitted
     WrongCredentials,
     /// A route required authentication, but none was provided
     NotLoggedIn,
+    /// A login challenge (password was correct, second factor missing)
+    /// does not exist anymore
+    ChallengeNotFound,
+    /// The submitted second factor (i.e. TOTP code) was wrong
+    InvalidSecondFactor,
+    /// The user tried to set up TOTP while it is already active
+    TotpAlreadyEnabled,
+    /// The user tried to confirm or disable TOTP without having set it up
+    TotpNotEnrolled,
+    /// A WebAuthn login was requested for a user without credentials
+    NoPasskeys,
+    /// Recovery codes were requested by a user without a second factor
+    NoSecondFactor,
+    /// The user has no WebAuthn credential with the given id
+    CredentialNotFound,
+    /// A WebAuthn ceremony timed out or was already finished
+    CeremonyNotFound,
+    /// The response of the authenticator did not pass verification
+    PasskeyRejected(WebauthnError),
+    /// Registration (or inviting people) is disabled by config
+    RegistrationDisabled,
+    /// Registration is invite-only and no valid invite was given
+    InvalidInvite,
+    /// There already is an account with this email
+    EmailTaken,
+    /// Logins require a verified email, but the user has not verified theirs
+    EmailNotVerified,
+    /// The account was disabled by an admin
+    UserDisabled,
+    /// A route requires an admin, but the user is none
+    NotAdmin,
+    /// An admin tried to disable, delete or demote their own account
+    OwnAccount,
+    /// A route requires a permission none of the roles of the user grants
+    MissingPermission(&'static str),
+    /// There is no role with the given name
+    RoleNotFound,
+    /// There is no permission with the given name
+    PermissionNotFound,
+    /// A role or permission with this name already exists
+    NameTaken,
+    /// There is no organization with the given id
+    OrganizationNotFound,
+    /// The user is not a member of the organization
+    MemberNotFound,
+    /// A route requires an admin of the organization, but the user is none
+    NotOrganizationAdmin,
+    /// The organization invite was sent to another email
+    InviteForOtherEmail,
+    /// A new password does not follow the password policy
+    WeakPassword(Vec<PolicyViolation>),
+    /// Too many failed logins, the client has to wait this many seconds
+    TooManyAttempts(u64),
+    /// An OAuth 2.0/OpenID Connect request failed, these use the error
+    /// format of RFC 6749 instead of [ErrorReturn] so that any OIDC
+    /// library can understand them
+    OAuth(OAuthError, &'static str),
     /// Something unexpected happened (i.e. database connection failed)
     UnknownError(Report),
 }
 
-/// Every [ApiError] except the `UnknownError` returns the JSON
+/// Error codes defined by RFC 6749 and RFC 6750
+#[allow(clippy::missing_docs_in_private_items)]
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum OAuthError {
+    InvalidRequest,
+    InvalidClient,
+    InvalidGrant,
+    UnsupportedGrantType,
+    InvalidToken,
+}
+
+/// The body of an [ApiError::OAuth]
+#[derive(Serialize)]
+struct OAuthErrorReturn {
+    /// Machine readable error code
+    error: OAuthError,
+    /// Same purpose as [ErrorReturn::reason]
+    error_description: &'static str,
+}
+
+/// Every [ApiError] except the `UnknownError` and `OAuth` returns the JSON
 /// serialized version of this struct.
 #[derive(Serialize)]
 struct ErrorReturn {
@@ -92,6 +288,9 @@ struct ErrorReturn {
     /// but should not require knowledge about implementation details, that is
     /// (at the moment) the job of the frontend.
     reason: String,
+    /// Only for [ApiError::WeakPassword]: Every rule the password breaks
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    violations: Vec<PolicyViolation>,
 }
 
 impl IntoResponse for ApiError {
@@ -99,6 +298,10 @@ impl IntoResponse for ApiError {
         let (status, reason) = match self {
             ApiError::UserNotFound => (StatusCode::NOT_FOUND, "User not found".to_owned()),
             ApiError::TokenNotFound => (StatusCode::NOT_FOUND, "Token not found".to_owned()),
+            ApiError::TokenExpired => (
+                StatusCode::GONE,
+                "Token expired, request a new one".to_owned(),
+            ),
             ApiError::WrongCredentials => {
                 (StatusCode::UNAUTHORIZED, "Wrong Credentials".to_owned())
             }
@@ -106,14 +309,148 @@ impl IntoResponse for ApiError {
                 StatusCode::UNAUTHORIZED,
                 "Invalid/expired Session".to_owned(),
             ),
+            ApiError::SessionNotFound => (StatusCode::NOT_FOUND, "Session not found".to_owned()),
             ApiError::NotLoggedIn => (
                 StatusCode::FORBIDDEN,
                 "You have to be logged in to access this part of the api".to_owned(),
             ),
+            ApiError::ChallengeNotFound => (
+                StatusCode::GONE,
+                "Login challenge expired, log in again".to_owned(),
+            ),
+            ApiError::InvalidSecondFactor => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "Wrong second factor".to_owned(),
+            ),
+            ApiError::TotpAlreadyEnabled => (
+                StatusCode::CONFLICT,
+                "TOTP is already enabled, disable it first".to_owned(),
+            ),
+            ApiError::TotpNotEnrolled => (
+                StatusCode::PRECONDITION_FAILED,
+                "TOTP is not set up, start the enrolment first".to_owned(),
+            ),
+            ApiError::NoPasskeys => (
+                StatusCode::NOT_FOUND,
+                "The user has no passkeys, log in with a password".to_owned(),
+            ),
+            ApiError::NoSecondFactor => (
+                StatusCode::PRECONDITION_FAILED,
+                "Recovery codes require a second factor, set one up first".to_owned(),
+            ),
+            ApiError::CredentialNotFound => {
+                (StatusCode::NOT_FOUND, "Credential not found".to_owned())
+            }
+            ApiError::CeremonyNotFound => (
+                StatusCode::GONE,
+                "WebAuthn ceremony timed out, start again".to_owned(),
+            ),
+            ApiError::PasskeyRejected(error) => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                format!("The authenticator response was rejected: {error}"),
+            ),
+            ApiError::RegistrationDisabled => (
+                StatusCode::FORBIDDEN,
+                "Registration is disabled, ask an administrator for an account".to_owned(),
+            ),
+            ApiError::InvalidInvite => (
+                StatusCode::FORBIDDEN,
+                "Registration requires an invite, the given one is invalid or expired".to_owned(),
+            ),
+            ApiError::EmailTaken => (
+                StatusCode::CONFLICT,
+                "There already is an account with this email, log in or reset the password"
+                    .to_owned(),
+            ),
+            ApiError::EmailNotVerified => (
+                StatusCode::FORBIDDEN,
+                "The email address is not verified, follow the link in the verification mail"
+                    .to_owned(),
+            ),
+            ApiError::UserDisabled => (
+                StatusCode::FORBIDDEN,
+                "The account is disabled, contact an administrator".to_owned(),
+            ),
+            ApiError::NotAdmin => (
+                StatusCode::FORBIDDEN,
+                "You have to be an admin to access this part of the api".to_owned(),
+            ),
+            ApiError::OwnAccount => (
+                StatusCode::CONFLICT,
+                "Admins can't disable, delete or demote their own account, ask another admin"
+                    .to_owned(),
+            ),
+            ApiError::MissingPermission(permission) => (
+                StatusCode::FORBIDDEN,
+                format!("You need the permission '{permission}' to access this part of the api"),
+            ),
+            ApiError::RoleNotFound => (StatusCode::NOT_FOUND, "Role not found".to_owned()),
+            ApiError::PermissionNotFound => {
+                (StatusCode::NOT_FOUND, "Permission not found".to_owned())
+            }
+            ApiError::NameTaken => (
+                StatusCode::CONFLICT,
+                "A role or permission with this name already exists".to_owned(),
+            ),
+            ApiError::OrganizationNotFound => {
+                (StatusCode::NOT_FOUND, "Organization not found".to_owned())
+            }
+            ApiError::MemberNotFound => (
+                StatusCode::NOT_FOUND,
+                "The user is not a member of the organization".to_owned(),
+            ),
+            ApiError::NotOrganizationAdmin => (
+                StatusCode::FORBIDDEN,
+                "You have to be an admin of the organization to manage it".to_owned(),
+            ),
+            ApiError::InviteForOtherEmail => (
+                StatusCode::FORBIDDEN,
+                "The invite was sent to another email, log in with that account".to_owned(),
+            ),
             ApiError::MisformedAuth(error) => (
                 StatusCode::BAD_REQUEST,
                 format!("The Auth header did not follow 'Bearer [session_uuid]', getting error: {error}")
             ),
+            ApiError::TooManyAttempts(retry_after) => {
+                let reason = "Too many failed logins, try again later".to_owned();
+
+                return (
+                    StatusCode::TOO_MANY_REQUESTS,
+                    [(RETRY_AFTER, retry_after.to_string())],
+                    Json(ErrorReturn {
+                        reason,
+                        violations: Vec::new(),
+                    }),
+                )
+                    .into_response();
+            }
+            ApiError::WeakPassword(violations) => {
+                let reason =
+                    "The password does not follow the password policy, see violations".to_owned();
+
+                return (
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    Json(ErrorReturn { reason, violations }),
+                )
+                    .into_response();
+            }
+            ApiError::OAuth(error, error_description) => {
+                let status = match error {
+                    OAuthError::InvalidClient | OAuthError::InvalidToken => {
+                        StatusCode::UNAUTHORIZED
+                    }
+                    _ => StatusCode::BAD_REQUEST,
+                };
+
+                return (
+                    status,
+                    Json(OAuthErrorReturn {
+                        error,
+                        error_description,
+                    }),
+                )
+                    .into_response();
+            }
             ApiError::UnknownError(r) => {
                 let error = format!("{r:?}");
 
@@ -126,6 +463,13 @@ impl IntoResponse for ApiError {
             }
         };
 
-        (status, Json(ErrorReturn { reason })).into_response()
+        (
+            status,
+            Json(ErrorReturn {
+                reason,
+                violations: Vec::new(),
+            }),
+        )
+            .into_response()
     }
 }
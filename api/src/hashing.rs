@@ -0,0 +1,284 @@
+//! Hashing and verifying passwords and client secrets
+//!
+//! New hashes always use Argon2id with the parameters from
+//! [HashingConfig]. Verifying works with any Argon2 hash, since the
+//! parameters are part of the stored PHC string. Hashes with weaker
+//! parameters are replaced on the next successful login, see
+//! [Hasher::needs_rehash].
+//!
+//! Hashes of imported users can also be bcrypt (`$2a$`, `$2b$` or `$2y$`)
+//! or PHC strings of PBKDF2 and scrypt. These are replaced by Argon2id
+//! on the next successful login as well.
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+use argon2::{
+    password_hash::{self, rand_core::OsRng, SaltString},
+    Algorithm, Argon2, Params, PasswordHash, PasswordHasher, Version,
+};
+use color_eyre::Report;
+use pbkdf2::Pbkdf2;
+use scrypt::Scrypt;
+use serde::Serialize;
+use tokio::sync::Semaphore;
+use tracing::debug;
+use uuid::Uuid;
+
+use crate::settings::HashingConfig;
+
+/// Prefixes of the bcrypt versions in modular crypt format
+const BCRYPT_PREFIXES: &[&str] = &["$2a$", "$2b$", "$2y$"];
+
+/// Algorithms of PHC strings which can be verified
+const PHC_ALGORITHMS: &[&str] = &[
+    "argon2id",
+    "argon2i",
+    "argon2d",
+    "pbkdf2",
+    "pbkdf2-sha256",
+    "pbkdf2-sha512",
+    "scrypt",
+];
+
+/// Whether `hash` is in the modular crypt format of bcrypt
+fn is_bcrypt(hash: &str) -> bool {
+    BCRYPT_PREFIXES
+        .iter()
+        .any(|prefix| hash.starts_with(prefix))
+}
+
+/// Whether `hash` is in a format [Hasher::verify] understands
+pub(crate) fn is_supported(hash: &str) -> bool {
+    if is_bcrypt(hash) {
+        return true;
+    }
+
+    PasswordHash::new(hash).is_ok_and(|hash| PHC_ALGORITHMS.contains(&hash.algorithm.as_str()))
+}
+
+/// Hashes `password` using `argon2`, blocks for a while
+fn hash_blocking(argon2: &Argon2<'_>, password: &str) -> Result<String, Report> {
+    let salt = SaltString::generate(&mut OsRng);
+
+    Ok(argon2
+        .hash_password(password.as_bytes(), &salt)?
+        .to_string())
+}
+
+/// Verifies `password` against `hash`, blocks for a while
+fn verify_blocking(argon2: &Argon2<'_>, password: &str, hash: &str) -> Result<bool, Report> {
+    if is_bcrypt(hash) {
+        return Ok(bcrypt::verify(password, hash)?);
+    }
+
+    let hash = PasswordHash::new(hash)?;
+
+    match hash.verify_password(&[argon2, &Pbkdf2, &Scrypt], password) {
+        Ok(()) => Ok(true),
+        Err(password_hash::Error::Password) => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Counters of the hashing queue, since startup
+#[derive(Default)]
+struct QueueCounters {
+    /// Jobs currently waiting for a permit
+    waiting: AtomicU64,
+    /// Jobs which got a permit
+    started: AtomicU64,
+    /// Sum of the time jobs waited, in microseconds
+    total_wait_micros: AtomicU64,
+    /// Longest time a job waited, in microseconds
+    max_wait_micros: AtomicU64,
+}
+
+/// Counts a job as waiting until dropped, even if the request is
+/// cancelled while waiting
+struct Waiting<'a>(&'a AtomicU64);
+
+impl<'a> Waiting<'a> {
+    /// Starts counting
+    fn new(waiting: &'a AtomicU64) -> Self {
+        waiting.fetch_add(1, Ordering::Relaxed);
+        Self(waiting)
+    }
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Load of the hashing queue, as shown to admins
+#[derive(Debug, Serialize)]
+pub(crate) struct HashingStats {
+    /// See [HashingConfig::max_concurrent]
+    max_concurrent: usize,
+    /// Jobs hashing right now
+    running: usize,
+    /// Jobs waiting for one of the running ones to finish
+    waiting: u64,
+    /// Jobs since startup
+    started: u64,
+    /// Average time a job waited before it started, in microseconds
+    average_wait_micros: u64,
+    /// Longest time a job waited before it started, in microseconds
+    max_wait_micros: u64,
+}
+
+/// Hashes with the configured parameters
+///
+/// Hashing takes long enough to block a Tokio worker thread for a
+/// noticeable time, so every hash runs on the blocking pool. At most
+/// [HashingConfig::max_concurrent] run at once, further ones wait, so a
+/// burst of logins can't take all CPUs from the other requests.
+pub(crate) struct Hasher {
+    /// Argon2id with [HashingConfig]
+    argon2: Argon2<'static>,
+    /// Copy of the parameters of [Hasher::argon2], to compare stored
+    /// hashes against
+    params: Params,
+    /// Hash of a password nobody knows, see [Hasher::verify_dummy]
+    dummy_hash: String,
+    /// One permit per concurrent job
+    permits: Arc<Semaphore>,
+    /// See [HashingConfig::max_concurrent]
+    max_concurrent: usize,
+    /// Source of [HashingStats]
+    counters: QueueCounters,
+}
+
+impl Hasher {
+    /// Checks the parameters and computes the dummy hash
+    ///
+    /// Blocks while hashing, which is fine at startup.
+    pub(crate) fn new(config: &HashingConfig) -> Result<Self, Report> {
+        let params = Params::new(
+            config.memory_cost,
+            config.time_cost,
+            config.parallelism,
+            None,
+        )?;
+        let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, params.clone());
+        let dummy_hash = hash_blocking(&argon2, &Uuid::new_v4().to_string())?;
+        let max_concurrent = config.max_concurrent.max(1);
+
+        Ok(Self {
+            argon2,
+            params,
+            dummy_hash,
+            permits: Arc::new(Semaphore::new(max_concurrent)),
+            max_concurrent,
+            counters: QueueCounters::default(),
+        })
+    }
+
+    /// Runs `job` on the blocking pool, once a permit is available
+    async fn run<T: Send + 'static>(
+        &self,
+        job: impl FnOnce(Argon2<'static>) -> Result<T, Report> + Send + 'static,
+    ) -> Result<T, Report> {
+        let queued_at = Instant::now();
+        let permit = {
+            let _waiting = Waiting::new(&self.counters.waiting);
+            Arc::clone(&self.permits).acquire_owned().await?
+        };
+
+        let wait_micros = u64::try_from(queued_at.elapsed().as_micros()).unwrap_or(u64::MAX);
+        self.counters.started.fetch_add(1, Ordering::Relaxed);
+        self.counters
+            .total_wait_micros
+            .fetch_add(wait_micros, Ordering::Relaxed);
+        self.counters
+            .max_wait_micros
+            .fetch_max(wait_micros, Ordering::Relaxed);
+        debug!(wait_micros, "Hashing");
+
+        let argon2 = self.argon2.clone();
+        tokio::task::spawn_blocking(move || {
+            // Keeps the permit until hashing is done, even if the
+            // request is cancelled in the meantime
+            let _permit = permit;
+            job(argon2)
+        })
+        .await?
+    }
+
+    /// Hashes a password (or secret) for storing it
+    pub(crate) async fn hash(&self, password: &str) -> Result<String, Report> {
+        let password = password.to_owned();
+
+        self.run(move |argon2| hash_blocking(&argon2, &password))
+            .await
+    }
+
+    /// Whether `password` matches the stored `hash`, see
+    /// [is_supported] for the formats
+    ///
+    /// Returns an error if `hash` is malformed. Hashes of unsupported
+    /// algorithms never match.
+    pub(crate) async fn verify(&self, password: &str, hash: &str) -> Result<bool, Report> {
+        let (password, hash) = (password.to_owned(), hash.to_owned());
+
+        self.run(move |argon2| verify_blocking(&argon2, &password, &hash))
+            .await
+    }
+
+    /// Takes as long as [Hasher::verify] would, the result is irrelevant
+    ///
+    /// Used for unknown users, so they can't be told apart from users
+    /// with a wrong password by timing.
+    pub(crate) async fn verify_dummy(&self, password: &str) -> Result<(), Report> {
+        self.verify(password, &self.dummy_hash).await?;
+
+        Ok(())
+    }
+
+    /// The current load of the queue
+    pub(crate) fn stats(&self) -> HashingStats {
+        let started = self.counters.started.load(Ordering::Relaxed);
+        let total_wait_micros = self.counters.total_wait_micros.load(Ordering::Relaxed);
+
+        HashingStats {
+            max_concurrent: self.max_concurrent,
+            running: self
+                .max_concurrent
+                .saturating_sub(self.permits.available_permits()),
+            waiting: self.counters.waiting.load(Ordering::Relaxed),
+            started,
+            average_wait_micros: total_wait_micros.checked_div(started).unwrap_or(0),
+            max_wait_micros: self.counters.max_wait_micros.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Whether `hash` uses another algorithm or weaker parameters than
+    /// configured
+    ///
+    /// Hashes with stronger parameters are kept, so lowering the
+    /// parameters does not rehash every password.
+    pub(crate) fn needs_rehash(&self, hash: &str) -> bool {
+        let Ok(hash) = PasswordHash::new(hash) else {
+            return true;
+        };
+        if hash.algorithm != Algorithm::Argon2id.ident()
+            || hash.version != Some(u32::from(Version::V0x13))
+        {
+            return true;
+        }
+        let Ok(params) = Params::try_from(&hash) else {
+            return true;
+        };
+
+        params.m_cost() < self.params.m_cost()
+            || params.t_cost() < self.params.t_cost()
+            || params.p_cost() < self.params.p_cost()
+    }
+}
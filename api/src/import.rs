@@ -0,0 +1,47 @@
+//! The `import-users` command
+//!
+//! Reads users from a CSV file with a header line or from a JSON array
+//! of objects, depending on the file extension. Both have the fields
+//! `name`, `email`, `password_hash` and optionally `locale` and
+//! `email_verified` (defaults to false), see [ImportedUser].
+
+use std::{ffi::OsStr, fs::File, io::BufReader, path::Path};
+
+use color_eyre::{
+    eyre::{eyre, Context},
+    Report,
+};
+use tracing::info;
+
+use crate::{
+    database::{
+        self,
+        import::{self, ImportedUser},
+    },
+    settings::Config,
+};
+
+/// Reads the users of `path` and imports them
+pub(crate) async fn import_users(config: &Config, path: &str) -> Result<(), Report> {
+    let users = read_users(Path::new(path)).wrap_err_with(|| format!("Reading {path}"))?;
+    let pool = database::connect(&config.database).await?;
+
+    let summary = import::import_users(&pool, &users).await?;
+    info!(
+        "Imported {} users, skipped {} whose email is already taken",
+        summary.imported, summary.skipped
+    );
+
+    Ok(())
+}
+
+/// Parses a `.csv` or `.json` file
+fn read_users(path: &Path) -> Result<Vec<ImportedUser>, Report> {
+    match path.extension().and_then(OsStr::to_str) {
+        Some("csv") => Ok(csv::Reader::from_path(path)?
+            .deserialize()
+            .collect::<Result<_, _>>()?),
+        Some("json") => Ok(serde_json::from_reader(BufReader::new(File::open(path)?))?),
+        _ => Err(eyre!("Unknown file type, expected .csv or .json")),
+    }
+}
@@ -46,11 +46,11 @@ use axum::{
         header::{AUTHORIZATION, CONTENT_TYPE},
         Method,
     },
-    routing::{get, patch, post},
+    routing::{delete, get, patch, post, put},
     Extension, Router, Server, ServiceExt,
 };
 
-use color_eyre::Report;
+use color_eyre::{eyre::eyre, Report};
 use settings::{read_config, Config};
 use tower::ServiceBuilder;
 use tower_http::{
@@ -60,20 +60,47 @@ use tower_http::{
     ServiceBuilderExt,
 };
 use tracing::info;
+use webauthn_rs::{prelude::Url, WebauthnBuilder};
 
 use crate::{
     database::{auth::Credentials, create_admin_if_no_user_exist},
+    hashing::Hasher,
+    mail::Mailer,
+    oidc::SigningKey,
+    password_policy::PasswordPolicy,
     routes::{
-        login::{logout, test_login},
+        admin,
+        email_change::{confirm_email_change, revert_email_change},
+        login::{
+            begin_login_webauthn, begin_second_factor_webauthn, finish_login_webauthn,
+            finish_second_factor_webauthn, login_magic_link, login_recovery_code, login_totp,
+            logout, request_magic_link, test_login,
+        },
+        oidc::{authorize, authorize_session, discovery, jwks, token, userinfo},
+        organizations,
+        recovery_codes::{get_recovery_codes, regenerate_recovery_codes},
+        registration::{create_invite, register},
         reset::{request_reset, reset_password, test_reset_token},
-        user::{get_user, patch_user},
+        roles,
+        sessions::{list_sessions, revoke_other_sessions, revoke_session},
+        totp::{begin_totp, confirm_totp, disable_totp},
+        user::{change_password, get_user, patch_user},
+        verification::{request_verification, verify_email},
+        webauthn::{begin_registration, finish_registration, list_credentials, remove_credential},
     },
     types::{EMail, Password},
 };
 
 mod database;
 mod error_handling;
+mod hashing;
+mod import;
+mod mail;
+mod maintenance;
 mod middlewares;
+mod oidc;
+mod otp;
+mod password_policy;
 mod routes;
 mod settings;
 mod trace;
@@ -85,6 +112,9 @@ use routes::login::login;
 ///
 /// Only public function at the moment - should be changed to accept the
 /// settings to move setting loading into the application
+///
+/// Without arguments the server is started, `import-users <file>`
+/// imports users from another system instead.
 pub async fn run() -> Result<(), Report> {
     dotenv::dotenv()?;
 
@@ -93,7 +123,17 @@ pub async fn run() -> Result<(), Report> {
 
     let config = read_config()?;
 
-    run_server(config).await?;
+    let mut args = std::env::args().skip(1);
+    match args.next().as_deref() {
+        None => run_server(config).await?,
+        Some("import-users") => {
+            let path = args
+                .next()
+                .ok_or_else(|| eyre!("Usage: hausmeister import-users <file.csv|file.json>"))?;
+            import::import_users(&config, &path).await?;
+        }
+        Some(command) => return Err(eyre!("Unknown command {command}")),
+    }
 
     Ok(())
 }
@@ -103,12 +143,25 @@ async fn run_server(config: Config) -> Result<(), Report> {
     let addr: SocketAddr = "[::1]:3779".parse()?;
     info!("Listening on http://{}", addr);
 
+    let config = Arc::new(config);
     let pool = database::connect(&config.database).await?;
 
     let redis_client = redis::Client::open("redis://localhost")?;
 
+    let webauthn = WebauthnBuilder::new(
+        &config.webauthn.rp_id,
+        &Url::parse(&config.webauthn.rp_origin)?,
+    )?
+    .rp_name(&config.webauthn.rp_name)
+    .build()?;
+
+    let mailer = Mailer::new(&config.mail)?;
+    let password_policy = PasswordPolicy::load(&config.password_policy)?;
+    let hasher = Hasher::new(&config.hashing)?;
+
     create_admin_if_no_user_exist(
         &pool,
+        &hasher,
         &Credentials {
             email: EMail("admin@example.com".to_owned()),
             password: Password("password".to_owned()),
@@ -116,26 +169,140 @@ async fn run_server(config: Config) -> Result<(), Report> {
     )
     .await?;
 
-    let app = Router::new()
+    maintenance::spawn(&pool, &config);
+
+    let mut app = Router::new()
         .route("/test_login", get(test_login))
         .route("/login", post(login))
+        .route("/login/totp", post(login_totp))
+        .route("/login/recovery-code", post(login_recovery_code))
+        .route("/login/webauthn/start", post(begin_login_webauthn))
+        .route("/login/webauthn/finish", post(finish_login_webauthn))
+        .route(
+            "/login/webauthn/second-factor/start",
+            post(begin_second_factor_webauthn),
+        )
+        .route(
+            "/login/webauthn/second-factor/finish",
+            post(finish_second_factor_webauthn),
+        )
         .route("/logout", post(logout))
+        .route("/register", post(register))
+        .route("/invites", post(create_invite))
         .route("/request-reset", post(request_reset))
         .route("/reset", post(reset_password))
+        .route("/verify-email", post(verify_email))
+        .route("/request-verification", post(request_verification))
+        .route("/confirm-email-change", post(confirm_email_change))
+        .route("/revert-email-change", post(revert_email_change))
+        .route("/sessions", get(list_sessions))
+        .route("/sessions", delete(revoke_other_sessions))
+        .route("/sessions/:id", delete(revoke_session))
+        .route(
+            "/session/organization",
+            put(organizations::switch_organization),
+        )
         .route("/user", get(get_user))
         .route("/user", patch(patch_user))
+        .route("/user/password", post(change_password))
+        .route("/user/totp", post(begin_totp))
+        .route("/user/totp", delete(disable_totp))
+        .route("/user/totp/confirm", post(confirm_totp))
+        .route("/user/recovery-codes", get(get_recovery_codes))
+        .route("/user/recovery-codes", post(regenerate_recovery_codes))
+        .route("/user/webauthn", get(list_credentials))
+        .route("/user/webauthn/:id", delete(remove_credential))
+        .route("/user/webauthn/register/start", post(begin_registration))
+        .route("/user/webauthn/register/finish", post(finish_registration))
+        .route("/admin/users", get(admin::list_users))
+        .route("/admin/users", post(admin::create_user))
+        .route("/admin/users/:id", get(admin::get_user))
+        .route("/admin/users/:id", patch(admin::update_user))
+        .route("/admin/users/:id", delete(admin::delete_user))
+        .route("/admin/users/:id/disable", post(admin::disable_user))
+        .route("/admin/users/:id/enable", post(admin::enable_user))
+        .route(
+            "/admin/users/:id/force-reset",
+            post(admin::force_password_reset),
+        )
+        .route("/admin/users/:id/unlock", post(admin::unlock_user))
+        .route("/admin/hashing", get(admin::hashing_stats))
+        .route("/admin/users/:id/roles", get(roles::get_user_roles))
+        .route("/admin/users/:id/roles", put(roles::set_user_roles))
+        .route("/admin/roles", get(roles::list_roles))
+        .route("/admin/roles", post(roles::create_role))
+        .route("/admin/roles/:name", patch(roles::update_role))
+        .route("/admin/roles/:name", delete(roles::delete_role))
+        .route("/admin/permissions", get(roles::list_permissions))
+        .route("/admin/permissions", post(roles::create_permission))
+        .route("/admin/permissions/:name", delete(roles::delete_permission))
+        .route(
+            "/admin/organizations",
+            get(organizations::list_organizations),
+        )
+        .route(
+            "/admin/organizations",
+            post(organizations::create_organization),
+        )
+        .route(
+            "/admin/organizations/:id",
+            delete(organizations::delete_organization),
+        )
+        .route("/organizations", get(organizations::list_memberships))
+        .route(
+            "/organizations/:id/members",
+            get(organizations::list_members),
+        )
+        .route(
+            "/organizations/:id/members/:user_id",
+            patch(organizations::update_member),
+        )
+        .route(
+            "/organizations/:id/members/:user_id",
+            delete(organizations::remove_member),
+        )
+        .route(
+            "/organizations/:id/invites",
+            post(organizations::invite_member),
+        )
+        .route("/accept-invite", post(organizations::accept_invite))
         .route("/test_reset_token", post(test_reset_token));
 
+    if config.magic_link.enabled {
+        app = app
+            .route("/login/magic-link", post(request_magic_link))
+            .route("/login/magic-link/redeem", post(login_magic_link));
+    }
+
+    if let Some(oidc_config) = &config.oidc {
+        let signing_key = SigningKey::load(oidc_config)?;
+
+        app = app
+            .route("/.well-known/openid-configuration", get(discovery))
+            .route("/jwks", get(jwks))
+            .route("/authorize", get(authorize))
+            .route("/authorize", post(authorize_session))
+            .route("/token", post(token))
+            .route("/userinfo", get(userinfo))
+            .layer(Extension(Arc::new(signing_key)));
+    }
+
     let svc = ServiceBuilder::new()
         .layer(
             TraceLayer::new_for_http()
                 .make_span_with(DefaultMakeSpan::new().include_headers(true))
                 .on_response(DefaultOnResponse::new().include_headers(true)),
         )
-        .layer(Extension(Arc::new(config)))
+        .layer(Extension(config))
         .layer(
             CorsLayer::new()
-                .allow_methods([Method::GET, Method::POST, Method::PATCH])
+                .allow_methods([
+                    Method::GET,
+                    Method::POST,
+                    Method::PUT,
+                    Method::PATCH,
+                    Method::DELETE,
+                ])
                 .allow_origin(AllowOrigin::predicate(|header, request| {
                     let Ok(origin) = header.to_str() else {
                         // We don't allow non utf-origins at the moment
@@ -159,11 +326,17 @@ async fn run_server(config: Config) -> Result<(), Report> {
         )
         .layer(Extension(pool))
         .layer(Extension(Arc::new(redis_client)))
+        .layer(Extension(Arc::new(webauthn)))
+        .layer(Extension(Arc::new(mailer)))
+        .layer(Extension(Arc::new(password_policy)))
+        .layer(Extension(Arc::new(hasher)))
         .set_x_request_id(MakeRequestUuid)
         .propagate_x_request_id()
         .service(app);
 
-    Server::bind(&addr).serve(svc.into_make_service()).await?;
+    Server::bind(&addr)
+        .serve(svc.into_make_service_with_connect_info::<SocketAddr>())
+        .await?;
 
     Ok(())
 }
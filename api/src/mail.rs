@@ -0,0 +1,286 @@
+//! Sending emails
+//!
+//! [Mailer] hides which transport is configured (see
+//! [MailTransport](crate::settings::MailTransport)), so routes only
+//! have to decide what to send. The content of every mail comes
+//! from [templates].
+
+pub(crate) mod templates;
+
+use std::{io::Write, path::PathBuf};
+
+use color_eyre::{
+    eyre::{eyre, Context},
+    Report,
+};
+use lettre::{
+    message::{Mailbox, MultiPart},
+    transport::smtp::authentication::Credentials,
+    AsyncFileTransport, AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use tera::Context as TemplateContext;
+use tracing::info;
+use url::Url;
+use uuid::Uuid;
+
+use crate::{
+    database::User,
+    settings::{MailConfig, MailTransport, SmtpConfig, SmtpTls},
+    types::EMail,
+};
+
+use self::templates::Templates;
+
+/// The configured way of delivering mails
+enum Transport {
+    /// See [MailTransport::Stdout]
+    Stdout,
+    /// See [MailTransport::File]
+    File(AsyncFileTransport<Tokio1Executor>),
+    /// See [MailTransport::Smtp]
+    Smtp(AsyncSmtpTransport<Tokio1Executor>),
+}
+
+/// Sends mails using the configured transport
+pub(crate) struct Mailer {
+    /// Sender of all mails
+    from: Mailbox,
+    /// Where mails go
+    transport: Transport,
+    /// Content of the mails
+    templates: Templates,
+}
+
+/// Connects to the SMTP server described by `config`
+///
+/// Connections are only opened once a mail is sent.
+fn smtp_transport(config: &SmtpConfig) -> Result<AsyncSmtpTransport<Tokio1Executor>, Report> {
+    let mut builder = match config.tls {
+        SmtpTls::Starttls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.host)?,
+        SmtpTls::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&config.host)?,
+        SmtpTls::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&config.host),
+    };
+
+    if let Some(port) = config.port {
+        builder = builder.port(port);
+    }
+    if let Some(username) = &config.username {
+        builder = builder.credentials(Credentials::new(
+            username.clone(),
+            config.password.clone().unwrap_or_default(),
+        ));
+    }
+
+    Ok(builder.build())
+}
+
+impl Mailer {
+    /// Sets up the transport described by `config`
+    pub(crate) fn new(config: &MailConfig) -> Result<Self, Report> {
+        let from = config
+            .from
+            .parse()
+            .wrap_err_with(|| format!("Invalid mail sender {}", config.from))?;
+
+        let transport =
+            match config.transport {
+                MailTransport::Stdout => Transport::Stdout,
+                MailTransport::File => {
+                    let directory =
+                        PathBuf::from(config.directory.as_ref().ok_or_else(|| {
+                            eyre!("The file mail transport requires a directory")
+                        })?);
+                    std::fs::create_dir_all(&directory)
+                        .wrap_err_with(|| format!("Creating mail directory {directory:?}"))?;
+
+                    Transport::File(AsyncFileTransport::new(directory))
+                }
+                MailTransport::Smtp => {
+                    Transport::Smtp(smtp_transport(config.smtp.as_ref().ok_or_else(|| {
+                        eyre!("The smtp mail transport requires [mail.smtp]")
+                    })?)?)
+                }
+            };
+
+        let templates = Templates::load(config.templates.as_deref(), &config.default_locale)?;
+
+        Ok(Self {
+            from,
+            transport,
+            templates,
+        })
+    }
+
+    /// Delivers a single mail
+    #[tracing::instrument(skip_all)]
+    async fn send(&self, message: Message) -> Result<(), Report> {
+        match &self.transport {
+            Transport::Stdout => {
+                let mut stdout = std::io::stdout().lock();
+                stdout.write_all(&message.formatted())?;
+                stdout.write_all(b"\n")?;
+            }
+            Transport::File(transport) => {
+                let id = transport.send(message).await?;
+                info!("Wrote mail {id}");
+            }
+            Transport::Smtp(transport) => {
+                transport.send(message).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The mailbox of `user`
+    fn mailbox(user: &User) -> Result<Mailbox, Report> {
+        Ok(Mailbox::new(Some(user.name.clone()), user.email.0.parse()?))
+    }
+
+    /// Renders `mail` in the locale of `user` and sends it to `to`
+    ///
+    /// `user` is available to all templates, `context` adds the values
+    /// specific to `mail`.
+    async fn send_template(
+        &self,
+        to: Mailbox,
+        user: &User,
+        mail: &str,
+        mut context: TemplateContext,
+    ) -> Result<(), Report> {
+        context.insert("user", user);
+        let rendered = self
+            .templates
+            .render(mail, user.locale.as_deref(), &context)?;
+
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(to)
+            .subject(rendered.subject)
+            .multipart(MultiPart::alternative_plain_html(
+                rendered.text,
+                rendered.html,
+            ))?;
+
+        self.send(message).await
+    }
+
+    /// Sends the link to reset the password
+    #[tracing::instrument(skip(self, token, link))]
+    pub(crate) async fn send_password_reset(
+        &self,
+        user: &User,
+        token: &Uuid,
+        link: &Url,
+    ) -> Result<(), Report> {
+        let mut context = TemplateContext::new();
+        context.insert("token", token);
+        context.insert("link", link.as_str());
+
+        self.send_template(Self::mailbox(user)?, user, "password_reset", context)
+            .await
+    }
+
+    /// Sends the link logging the user in without a password
+    #[tracing::instrument(skip(self, link))]
+    pub(crate) async fn send_magic_link(&self, user: &User, link: &Url) -> Result<(), Report> {
+        let mut context = TemplateContext::new();
+        context.insert("link", link.as_str());
+
+        self.send_template(Self::mailbox(user)?, user, "magic_link", context)
+            .await
+    }
+
+    /// Sends the link verifying that `email` belongs to `user`
+    ///
+    /// `email` is passed separately since it may not be the current
+    /// email of the user yet.
+    #[tracing::instrument(skip(self, token, link))]
+    pub(crate) async fn send_email_verification(
+        &self,
+        user: &User,
+        email: &EMail,
+        token: &Uuid,
+        link: &Url,
+    ) -> Result<(), Report> {
+        let mut context = TemplateContext::new();
+        context.insert("email", &email.0);
+        context.insert("token", token);
+        context.insert("link", link.as_str());
+
+        let to = Mailbox::new(Some(user.name.clone()), email.0.parse()?);
+        self.send_template(to, user, "verify_email", context).await
+    }
+
+    /// Sends the link confirming an email change to the new address
+    #[tracing::instrument(skip(self, link))]
+    pub(crate) async fn send_email_change_confirmation(
+        &self,
+        user: &User,
+        new_email: &EMail,
+        link: &Url,
+    ) -> Result<(), Report> {
+        let mut context = TemplateContext::new();
+        context.insert("new_email", &new_email.0);
+        context.insert("link", link.as_str());
+
+        let to = Mailbox::new(Some(user.name.clone()), new_email.0.parse()?);
+        self.send_template(to, user, "confirm_email_change", context)
+            .await
+    }
+
+    /// Warns the old address about an email change, `link` reverts it
+    #[tracing::instrument(skip(self, link))]
+    pub(crate) async fn send_email_change_notice(
+        &self,
+        user: &User,
+        new_email: &EMail,
+        link: &Url,
+    ) -> Result<(), Report> {
+        let mut context = TemplateContext::new();
+        context.insert("new_email", &new_email.0);
+        context.insert("link", link.as_str());
+
+        self.send_template(Self::mailbox(user)?, user, "email_changed", context)
+            .await
+    }
+
+    /// Invites `email` to an organization, `link` accepts the invite
+    ///
+    /// The recipient may not have an account yet, so the mail uses the
+    /// locale of `inviter`, who is `user` in the templates.
+    #[tracing::instrument(skip(self, link))]
+    pub(crate) async fn send_organization_invite(
+        &self,
+        inviter: &User,
+        email: &EMail,
+        organization: &str,
+        link: &Url,
+    ) -> Result<(), Report> {
+        let mut context = TemplateContext::new();
+        context.insert("email", &email.0);
+        context.insert("organization", organization);
+        context.insert("link", link.as_str());
+
+        let to = Mailbox::new(None, email.0.parse()?);
+        self.send_template(to, inviter, "organization_invite", context)
+            .await
+    }
+
+    /// Tells the user that a recovery code was used to log in
+    ///
+    /// If this was not them, their password and the codes are known to
+    /// somebody else.
+    #[tracing::instrument(skip(self))]
+    pub(crate) async fn send_recovery_code_used(
+        &self,
+        user: &User,
+        remaining: i64,
+    ) -> Result<(), Report> {
+        let mut context = TemplateContext::new();
+        context.insert("remaining", &remaining);
+
+        self.send_template(Self::mailbox(user)?, user, "recovery_code_used", context)
+            .await
+    }
+}
@@ -0,0 +1,274 @@
+//! Templates of all mails
+//!
+//! Every mail consists of three [Tera] templates, stored per locale:
+//! `<locale>/<mail>.subject`, `<locale>/<mail>.txt` and `<locale>/<mail>.html`.
+//! HTML templates can extend `layout.html`, the place for branding.
+//!
+//! The built-in templates (see `api/templates`) are always available,
+//! templates in the configured directory replace them by name, so a
+//! custom directory can also just add a locale or change the layout.
+
+use color_eyre::{eyre::eyre, Report};
+use tera::{Context, Tera};
+
+/// The built-in templates, as (name, content)
+const BUILT_IN: [(&str, &str); 43] = [
+    ("layout.html", include_str!("../../templates/layout.html")),
+    (
+        "en/password_reset.subject",
+        include_str!("../../templates/en/password_reset.subject"),
+    ),
+    (
+        "en/password_reset.txt",
+        include_str!("../../templates/en/password_reset.txt"),
+    ),
+    (
+        "en/password_reset.html",
+        include_str!("../../templates/en/password_reset.html"),
+    ),
+    (
+        "en/verify_email.subject",
+        include_str!("../../templates/en/verify_email.subject"),
+    ),
+    (
+        "en/verify_email.txt",
+        include_str!("../../templates/en/verify_email.txt"),
+    ),
+    (
+        "en/verify_email.html",
+        include_str!("../../templates/en/verify_email.html"),
+    ),
+    (
+        "en/email_changed.subject",
+        include_str!("../../templates/en/email_changed.subject"),
+    ),
+    (
+        "en/email_changed.txt",
+        include_str!("../../templates/en/email_changed.txt"),
+    ),
+    (
+        "en/email_changed.html",
+        include_str!("../../templates/en/email_changed.html"),
+    ),
+    (
+        "en/confirm_email_change.subject",
+        include_str!("../../templates/en/confirm_email_change.subject"),
+    ),
+    (
+        "en/confirm_email_change.txt",
+        include_str!("../../templates/en/confirm_email_change.txt"),
+    ),
+    (
+        "en/confirm_email_change.html",
+        include_str!("../../templates/en/confirm_email_change.html"),
+    ),
+    (
+        "en/organization_invite.subject",
+        include_str!("../../templates/en/organization_invite.subject"),
+    ),
+    (
+        "en/organization_invite.txt",
+        include_str!("../../templates/en/organization_invite.txt"),
+    ),
+    (
+        "en/organization_invite.html",
+        include_str!("../../templates/en/organization_invite.html"),
+    ),
+    (
+        "en/recovery_code_used.subject",
+        include_str!("../../templates/en/recovery_code_used.subject"),
+    ),
+    (
+        "en/recovery_code_used.txt",
+        include_str!("../../templates/en/recovery_code_used.txt"),
+    ),
+    (
+        "en/recovery_code_used.html",
+        include_str!("../../templates/en/recovery_code_used.html"),
+    ),
+    (
+        "en/magic_link.subject",
+        include_str!("../../templates/en/magic_link.subject"),
+    ),
+    (
+        "en/magic_link.txt",
+        include_str!("../../templates/en/magic_link.txt"),
+    ),
+    (
+        "en/magic_link.html",
+        include_str!("../../templates/en/magic_link.html"),
+    ),
+    (
+        "de/password_reset.subject",
+        include_str!("../../templates/de/password_reset.subject"),
+    ),
+    (
+        "de/password_reset.txt",
+        include_str!("../../templates/de/password_reset.txt"),
+    ),
+    (
+        "de/password_reset.html",
+        include_str!("../../templates/de/password_reset.html"),
+    ),
+    (
+        "de/verify_email.subject",
+        include_str!("../../templates/de/verify_email.subject"),
+    ),
+    (
+        "de/verify_email.txt",
+        include_str!("../../templates/de/verify_email.txt"),
+    ),
+    (
+        "de/verify_email.html",
+        include_str!("../../templates/de/verify_email.html"),
+    ),
+    (
+        "de/email_changed.subject",
+        include_str!("../../templates/de/email_changed.subject"),
+    ),
+    (
+        "de/email_changed.txt",
+        include_str!("../../templates/de/email_changed.txt"),
+    ),
+    (
+        "de/email_changed.html",
+        include_str!("../../templates/de/email_changed.html"),
+    ),
+    (
+        "de/confirm_email_change.subject",
+        include_str!("../../templates/de/confirm_email_change.subject"),
+    ),
+    (
+        "de/confirm_email_change.txt",
+        include_str!("../../templates/de/confirm_email_change.txt"),
+    ),
+    (
+        "de/confirm_email_change.html",
+        include_str!("../../templates/de/confirm_email_change.html"),
+    ),
+    (
+        "de/organization_invite.subject",
+        include_str!("../../templates/de/organization_invite.subject"),
+    ),
+    (
+        "de/organization_invite.txt",
+        include_str!("../../templates/de/organization_invite.txt"),
+    ),
+    (
+        "de/organization_invite.html",
+        include_str!("../../templates/de/organization_invite.html"),
+    ),
+    (
+        "de/recovery_code_used.subject",
+        include_str!("../../templates/de/recovery_code_used.subject"),
+    ),
+    (
+        "de/recovery_code_used.txt",
+        include_str!("../../templates/de/recovery_code_used.txt"),
+    ),
+    (
+        "de/recovery_code_used.html",
+        include_str!("../../templates/de/recovery_code_used.html"),
+    ),
+    (
+        "de/magic_link.subject",
+        include_str!("../../templates/de/magic_link.subject"),
+    ),
+    (
+        "de/magic_link.txt",
+        include_str!("../../templates/de/magic_link.txt"),
+    ),
+    (
+        "de/magic_link.html",
+        include_str!("../../templates/de/magic_link.html"),
+    ),
+];
+
+/// A mail ready to be sent
+pub(crate) struct RenderedMail {
+    /// Single line subject
+    pub(crate) subject: String,
+    /// The plain text part
+    pub(crate) text: String,
+    /// The HTML part
+    pub(crate) html: String,
+}
+
+/// All loaded templates
+pub(crate) struct Templates {
+    /// Built-in and custom templates
+    tera: Tera,
+    /// Used if there are no templates for the requested locale
+    default_locale: String,
+}
+
+impl Templates {
+    /// Loads the templates from `directory`, falling back to the built-in ones
+    pub(crate) fn load(directory: Option<&str>, default_locale: &str) -> Result<Self, Report> {
+        let mut tera = match directory {
+            Some(directory) => Tera::new(&format!("{directory}/**/*"))?,
+            None => Tera::default(),
+        };
+
+        let mut built_in = Tera::default();
+        built_in.add_raw_templates(BUILT_IN)?;
+        // Templates which already exist are not replaced
+        tera.extend(&built_in)?;
+
+        let templates = Self {
+            tera,
+            default_locale: default_locale.to_owned(),
+        };
+        if !templates.has_mail(default_locale, "password_reset") {
+            return Err(eyre!(
+                "There are no templates for the default locale {default_locale}"
+            ));
+        }
+
+        Ok(templates)
+    }
+
+    /// Whether all three templates of `mail` exist in `locale`
+    fn has_mail(&self, locale: &str, mail: &str) -> bool {
+        ["subject", "txt", "html"].iter().all(|extension| {
+            let name = format!("{locale}/{mail}.{extension}");
+            self.tera
+                .get_template_names()
+                .any(|existing| existing == name)
+        })
+    }
+
+    /// Chooses the locale to render `mail` in
+    ///
+    /// Tries the full tag (`de-AT`), then the language (`de`) and
+    /// finally the default locale.
+    fn locale_for<'a>(&'a self, locale: Option<&'a str>, mail: &str) -> &'a str {
+        let language = locale.and_then(|locale| locale.split(['-', '_']).next());
+
+        [locale, language]
+            .into_iter()
+            .flatten()
+            .find(|candidate| self.has_mail(candidate, mail))
+            .unwrap_or(self.default_locale.as_str())
+    }
+
+    /// Renders `mail` in the best matching locale
+    pub(crate) fn render(
+        &self,
+        mail: &str,
+        locale: Option<&str>,
+        context: &Context,
+    ) -> Result<RenderedMail, Report> {
+        let locale = self.locale_for(locale, mail);
+        let render = |extension| {
+            self.tera
+                .render(&format!("{locale}/{mail}.{extension}"), context)
+        };
+
+        Ok(RenderedMail {
+            subject: render("subject")?.trim().to_owned(),
+            text: render("txt")?,
+            html: render("html")?,
+        })
+    }
+}
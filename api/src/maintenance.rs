@@ -0,0 +1,105 @@
+//! Periodic clean up jobs
+//!
+//! Expired rows are rejected when they are used anyway, these jobs
+//! just keep the tables from growing forever.
+
+use std::{future::Future, sync::Arc, time::Duration};
+
+use color_eyre::Report;
+use sqlx::PgPool;
+use tracing::{error, info_span, Instrument};
+
+use crate::{
+    database::{
+        auth::purge_expired_login_challenges, email_change::purge_expired_email_changes,
+        magic_link::purge_expired_magic_links, oidc::purge_expired_authorization_codes,
+        purge_expired_reset_requests, sessions::purge_expired_sessions,
+        verification::purge_expired_verifications,
+    },
+    settings::Config,
+};
+
+/// Runs `purge` every `interval` seconds in the background, logging
+/// failures
+fn spawn_purge<F, Fut>(
+    name: &'static str,
+    interval: u64,
+    pool: &PgPool,
+    config: &Arc<Config>,
+    purge: F,
+) where
+    F: Fn(PgPool, Arc<Config>) -> Fut + Send + 'static,
+    Fut: Future<Output = Result<(), Report>> + Send + 'static,
+{
+    let pool = pool.clone();
+    let config = Arc::clone(config);
+    tokio::spawn(
+        async move {
+            // tokio panics on a period of 0
+            let period = Duration::from_secs(interval.max(1));
+            let mut interval = tokio::time::interval(period);
+            loop {
+                interval.tick().await;
+                if let Err(e) = purge(pool.clone(), Arc::clone(&config)).await {
+                    error!("Clean up job {name} failed: {e:?}");
+                }
+            }
+        }
+        .instrument(info_span!("Clean up", job = name)),
+    );
+}
+
+/// Starts all clean up jobs
+pub(crate) fn spawn(pool: &PgPool, config: &Arc<Config>) {
+    spawn_purge(
+        "expired sessions",
+        config.session.cleanup_interval,
+        pool,
+        config,
+        |pool, config| async move { purge_expired_sessions(&pool, &config.session).await },
+    );
+    spawn_purge(
+        "expired login challenges",
+        config.session.cleanup_interval,
+        pool,
+        config,
+        |pool, _| async move { purge_expired_login_challenges(&pool).await },
+    );
+    spawn_purge(
+        "expired reset requests",
+        config.reset.cleanup_interval,
+        pool,
+        config,
+        |pool, config| async move { purge_expired_reset_requests(&pool, &config.reset).await },
+    );
+    spawn_purge(
+        "expired email verifications",
+        config.verification.cleanup_interval,
+        pool,
+        config,
+        |pool, config| async move { purge_expired_verifications(&pool, &config.verification).await },
+    );
+    spawn_purge(
+        "expired email changes",
+        config.verification.cleanup_interval,
+        pool,
+        config,
+        |pool, config| async move { purge_expired_email_changes(&pool, &config.verification).await },
+    );
+    spawn_purge(
+        "expired magic links",
+        config.magic_link.cleanup_interval,
+        pool,
+        config,
+        |pool, config| async move { purge_expired_magic_links(&pool, &config.magic_link).await },
+    );
+    if let Some(oidc) = &config.oidc {
+        spawn_purge(
+            "expired authorization codes",
+            oidc.cleanup_interval,
+            pool,
+            config,
+            |pool, _| async move { purge_expired_authorization_codes(&pool).await },
+        );
+    }
+}
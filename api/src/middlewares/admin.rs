@@ -0,0 +1,45 @@
+//! Guarding the admin routes
+
+use async_trait::async_trait;
+use axum::{extract::FromRequestParts, http::request::Parts};
+use sqlx::PgPool;
+
+use crate::{
+    database::{admin::get_admin_from_session, User},
+    error_handling::ApiError,
+};
+
+use super::session::AuthenticatedSession;
+
+/// Extractor requiring the client to be logged in as an admin
+///
+/// Does everything [AuthenticatedSession] does, then checks the admin
+/// flag. Returns 403 for users who are not admins.
+#[derive(Debug)]
+pub(crate) struct AuthenticatedAdmin(
+    /// The admin
+    pub(crate) User,
+);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for AuthenticatedAdmin
+where
+    S: Sync + Send,
+{
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let AuthenticatedSession(session_id) =
+            AuthenticatedSession::from_request_parts(parts, state).await?;
+
+        let pool = parts
+            .extensions
+            .get::<PgPool>()
+            .expect("Missing PgPool from Extensions");
+
+        get_admin_from_session(pool, &session_id)
+            .await?
+            .map(AuthenticatedAdmin)
+            .ok_or(ApiError::NotAdmin)
+    }
+}
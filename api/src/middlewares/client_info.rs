@@ -0,0 +1,48 @@
+//! Information about the device a request comes from
+
+use std::{convert::Infallible, net::SocketAddr};
+
+use async_trait::async_trait;
+use axum::{
+    extract::{ConnectInfo, FromRequestParts},
+    http::{header::USER_AGENT, request::Parts},
+};
+
+/// Extractor for the user agent and IP address of the client
+///
+/// Both are purely informational (i.e. to let a user recognize
+/// their sessions) and must not be trusted for anything security
+/// relevant, the user agent is completely client controlled.
+#[derive(Clone, Debug)]
+pub(crate) struct ClientInfo {
+    /// The `User-Agent` header, if it is valid UTF-8
+    pub(crate) user_agent: Option<String>,
+    /// The address of the peer, this is the address of the reverse
+    /// proxy if there is one
+    pub(crate) ip_address: Option<String>,
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for ClientInfo
+where
+    S: Sync + Send,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let user_agent = parts
+            .headers
+            .get(USER_AGENT)
+            .and_then(|header| header.to_str().ok())
+            .map(str::to_owned);
+        let ip_address = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip().to_string());
+
+        Ok(ClientInfo {
+            user_agent,
+            ip_address,
+        })
+    }
+}
@@ -1,3 +1,6 @@
 //! Middlewares & Extractors
 //!
+pub(crate) mod admin;
+pub(crate) mod client_info;
+pub(crate) mod permission;
 pub(crate) mod session;
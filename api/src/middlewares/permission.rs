@@ -0,0 +1,80 @@
+//! Guarding routes by permission
+
+use std::marker::PhantomData;
+
+use async_trait::async_trait;
+use axum::{extract::FromRequestParts, http::request::Parts};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::{database::roles::get_user_with_permission, error_handling::ApiError};
+
+use super::session::AuthenticatedSession;
+
+/// A permission checked by Hausmeister itself
+///
+/// Implemented by marker types, so routes can require them by type:
+/// `RequirePermission<ReadUsers>`. The permission has to be inserted by
+/// a migration as well.
+pub(crate) trait Permission {
+    /// The name stored in the database
+    const NAME: &'static str;
+    /// Whether roles within the active organization grant the permission
+    ///
+    /// Only for permissions limited to that organization, others need a
+    /// global role, see [get_user_with_permission].
+    const ORGANIZATION_SCOPED: bool;
+}
+
+/// Listing and viewing all users
+#[derive(Debug)]
+pub(crate) struct ReadUsers;
+
+impl Permission for ReadUsers {
+    const NAME: &'static str = "users:read";
+    // Covers the users of all organizations
+    const ORGANIZATION_SCOPED: bool = false;
+}
+
+/// Extractor requiring the client to be logged in as a user with the
+/// permission `P`
+///
+/// Does everything [AuthenticatedSession] does, then checks whether one
+/// of the roles of the user grants the permission. Admins always pass.
+/// Returns 403 otherwise.
+#[derive(Debug)]
+pub(crate) struct RequirePermission<P>(
+    /// The checked permission
+    PhantomData<P>,
+);
+
+impl<P: Permission> RequirePermission<P> {
+    /// Checks the permission for an already authenticated session
+    pub(crate) async fn check(pool: &PgPool, session_id: &Uuid) -> Result<Self, ApiError> {
+        get_user_with_permission(pool, session_id, P::NAME, P::ORGANIZATION_SCOPED)
+            .await?
+            .map(|_| RequirePermission(PhantomData))
+            .ok_or(ApiError::MissingPermission(P::NAME))
+    }
+}
+
+#[async_trait]
+impl<S, P> FromRequestParts<S> for RequirePermission<P>
+where
+    S: Sync + Send,
+    P: Permission,
+{
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let AuthenticatedSession(session_id) =
+            AuthenticatedSession::from_request_parts(parts, state).await?;
+
+        let pool = parts
+            .extensions
+            .get::<PgPool>()
+            .expect("Missing PgPool from Extensions");
+
+        Self::check(pool, &session_id).await
+    }
+}
@@ -1,6 +1,6 @@
 //! Session extraction & Routeguarding
 
-use std::sync::Arc;
+use std::{cmp::min, sync::Arc, time::Duration};
 
 use async_trait::async_trait;
 use axum::{
@@ -13,7 +13,18 @@ use redis::AsyncCommands;
 use tracing::debug;
 use uuid::Uuid;
 
-use crate::error_handling::ApiError;
+use crate::{
+    database::sessions::{touch_session, SessionState},
+    error_handling::ApiError,
+    settings::Config,
+};
+
+/// How long a session is cached in redis at most
+///
+/// Every time the cache entry expires the session is checked against
+/// the database again, which also refreshes its last-seen timestamp, so
+/// this is the precision of the idle timeout.
+const CACHE_DURATION: Duration = Duration::from_secs(60);
 
 /// Extractor requiring the client to be logged in.
 ///
@@ -76,22 +87,35 @@ where
             .extensions
             .get::<sqlx::PgPool>()
             .expect("Missing PgPool from Extensions");
+        let config = parts
+            .extensions
+            .get::<Arc<Config>>()
+            .expect("Config is missing from extensions");
 
-        match sqlx::query!("SELECT * FROM sessions WHERE id = $1", session_id)
-            .fetch_optional(pool)
+        match touch_session(pool, &config.session, &session_id)
             .await
             .wrap_err("Retrieving session from DB")?
         {
-            Some(record) => {
-                let user_uuid = record.user_id;
+            SessionState::Valid {
+                user_id,
+                remaining_lifetime,
+            } => {
                 debug!("Caching session {}", session_id);
+                // Never cache a session longer than it lives, redis
+                // does not accept a TTL of 0
+                let ttl = min(CACHE_DURATION, remaining_lifetime).as_secs().max(1);
                 redis_connection
-                    .set::<_, _, ()>(session_id.to_string(), user_uuid.to_string())
+                    .set_ex::<_, _, ()>(
+                        session_id.to_string(),
+                        user_id.to_string(),
+                        usize::try_from(ttl).wrap_err("Session TTL out of range")?,
+                    )
                     .await
-                    .wrap_err("Retrieving session from Redis")?;
+                    .wrap_err("Caching session in Redis")?;
                 Ok(AuthenticatedSession(session_id))
             }
-            None => Err(ApiError::NotLoggedIn),
+            SessionState::Expired => Err(ApiError::InvalidSession),
+            SessionState::NotFound => Err(ApiError::NotLoggedIn),
         }
     }
 }
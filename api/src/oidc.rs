@@ -0,0 +1,256 @@
+//! Signing and verifying the tokens of the OpenID Connect provider
+//!
+//! Only RS256 is supported, since it is the one algorithm every
+//! relying party has to understand. The flow itself lives in
+//! [routes::oidc](crate::routes::oidc).
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use color_eyre::{eyre::Context, Report};
+use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use rsa::{pkcs8::DecodePrivateKey, PublicKeyParts, RsaPrivateKey};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+use crate::{database::User, settings::OidcConfig};
+
+/// The key all tokens are signed with
+pub(crate) struct SigningKey {
+    /// Published as `kid`
+    key_id: String,
+    /// Private part, for signing
+    encoding: EncodingKey,
+    /// Public part, for verifying our own access tokens
+    decoding: DecodingKey,
+    /// Base64url encoded modulus, for the JWKS
+    modulus: String,
+    /// Base64url encoded public exponent, for the JWKS
+    exponent: String,
+}
+
+impl SigningKey {
+    /// Reads the key configured in [OidcConfig]
+    pub(crate) fn load(config: &OidcConfig) -> Result<Self, Report> {
+        let pem = std::fs::read_to_string(&config.signing_key)
+            .wrap_err_with(|| format!("Reading OIDC signing key {}", config.signing_key))?;
+
+        Self::from_pem(&pem, &config.key_id)
+    }
+
+    /// Parses a PKCS#8 PEM encoded RSA key
+    pub(crate) fn from_pem(pem: &str, key_id: &str) -> Result<Self, Report> {
+        let private_key =
+            RsaPrivateKey::from_pkcs8_pem(pem).wrap_err("Parsing OIDC signing key")?;
+
+        let modulus = URL_SAFE_NO_PAD.encode(private_key.n().to_bytes_be());
+        let exponent = URL_SAFE_NO_PAD.encode(private_key.e().to_bytes_be());
+
+        Ok(Self {
+            key_id: key_id.to_owned(),
+            encoding: EncodingKey::from_rsa_pem(pem.as_bytes())?,
+            decoding: DecodingKey::from_rsa_components(&modulus, &exponent)?,
+            modulus,
+            exponent,
+        })
+    }
+
+    /// The public key as JSON Web Key Set
+    pub(crate) fn jwks(&self) -> JwkSet {
+        JwkSet {
+            keys: vec![Jwk {
+                kty: "RSA",
+                key_use: "sig",
+                alg: "RS256",
+                kid: self.key_id.clone(),
+                n: self.modulus.clone(),
+                e: self.exponent.clone(),
+            }],
+        }
+    }
+
+    /// Signs the claims
+    fn sign<T: Serialize>(&self, claims: &T) -> Result<String, Report> {
+        let header = Header {
+            kid: Some(self.key_id.clone()),
+            ..Header::new(Algorithm::RS256)
+        };
+
+        Ok(encode(&header, claims, &self.encoding)?)
+    }
+}
+
+/// A single public key, see RFC 7517
+#[derive(Debug, Serialize)]
+pub(crate) struct Jwk {
+    /// Key type
+    kty: &'static str,
+    /// What the key is used for, always signatures
+    #[serde(rename = "use")]
+    key_use: &'static str,
+    /// Signature algorithm
+    alg: &'static str,
+    /// Key id, referenced by the header of a token
+    kid: String,
+    /// RSA modulus
+    n: String,
+    /// RSA public exponent
+    e: String,
+}
+
+/// The document served as `jwks_uri`
+#[derive(Debug, Serialize)]
+pub(crate) struct JwkSet {
+    /// All currently valid keys
+    keys: Vec<Jwk>,
+}
+
+/// Whether the requested scopes include `scope`
+pub(crate) fn has_scope(scopes: &str, scope: &str) -> bool {
+    scopes.split_ascii_whitespace().any(|s| s == scope)
+}
+
+/// Claims describing the user, used in the id token and by `/userinfo`
+///
+/// Which claims are included depends on the granted scopes, except for
+/// `roles`, which downstream services always need for authorization.
+#[derive(Debug, Serialize)]
+pub(crate) struct UserClaims {
+    /// The stable user id
+    sub: Uuid,
+    /// Only with `profile` scope
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    /// Only with `email` scope
+    #[serde(skip_serializing_if = "Option::is_none")]
+    email: Option<String>,
+    /// Names of the roles of the user
+    roles: Vec<String>,
+}
+
+impl UserClaims {
+    /// Selects the claims of `user` which are covered by `scopes`
+    pub(crate) fn new(user: &User, roles: Vec<String>, scopes: &str) -> Self {
+        Self {
+            sub: user.id,
+            name: has_scope(scopes, "profile").then(|| user.name.clone()),
+            email: has_scope(scopes, "email").then(|| user.email.0.clone()),
+            roles,
+        }
+    }
+}
+
+/// The claims of an id token
+#[derive(Debug, Serialize)]
+struct IdTokenClaims {
+    /// Issuer
+    iss: String,
+    /// The client the token was issued to
+    aud: String,
+    /// Issued at
+    iat: i64,
+    /// Expiration
+    exp: i64,
+    /// Has to be returned if it was part of the authorization request
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nonce: Option<String>,
+    /// Claims about the user
+    #[serde(flatten)]
+    user: UserClaims,
+}
+
+/// The claims of an access token
+///
+/// Access tokens are only meant for our own `/userinfo`, but they
+/// are JWTs anyway, so they can be verified without a database.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct AccessTokenClaims {
+    /// Issuer
+    iss: String,
+    /// Always the issuer, since `/userinfo` is the only resource
+    aud: String,
+    /// The user id
+    pub(crate) sub: Uuid,
+    /// The client the token was issued to
+    pub(crate) client_id: String,
+    /// The granted scopes, space separated
+    pub(crate) scope: String,
+    /// Names of the roles of the user when the token was issued
+    #[serde(default)]
+    pub(crate) roles: Vec<String>,
+    /// Issued at
+    iat: i64,
+    /// Expiration
+    exp: i64,
+}
+
+/// Tokens returned by the token endpoint
+pub(crate) struct IssuedTokens {
+    /// Signed [IdTokenClaims]
+    pub(crate) id_token: String,
+    /// Signed [AccessTokenClaims]
+    pub(crate) access_token: String,
+}
+
+/// Issues id and access token after a successful code exchange
+pub(crate) fn issue_tokens(
+    config: &OidcConfig,
+    key: &SigningKey,
+    user: &User,
+    roles: Vec<String>,
+    client_id: &str,
+    scope: &str,
+    nonce: Option<String>,
+) -> Result<IssuedTokens, Report> {
+    let iat = OffsetDateTime::now_utc().unix_timestamp();
+    let exp = iat + config.token_lifetime;
+
+    let id_token = key.sign(&IdTokenClaims {
+        iss: config.issuer.clone(),
+        aud: client_id.to_owned(),
+        iat,
+        exp,
+        nonce,
+        user: UserClaims::new(user, roles.clone(), scope),
+    })?;
+
+    let access_token = key.sign(&AccessTokenClaims {
+        iss: config.issuer.clone(),
+        aud: config.issuer.clone(),
+        sub: user.id,
+        client_id: client_id.to_owned(),
+        scope: scope.to_owned(),
+        roles,
+        iat,
+        exp,
+    })?;
+
+    Ok(IssuedTokens {
+        id_token,
+        access_token,
+    })
+}
+
+/// Verifies an access token issued by [issue_tokens]
+///
+/// Returns `None` for any invalid, expired or foreign token.
+pub(crate) fn verify_access_token(
+    config: &OidcConfig,
+    key: &SigningKey,
+    token: &str,
+) -> Option<AccessTokenClaims> {
+    let mut validation = Validation::new(Algorithm::RS256);
+    validation.set_issuer(&[&config.issuer]);
+    validation.set_audience(&[&config.issuer]);
+
+    decode::<AccessTokenClaims>(token, &key.decoding, &validation)
+        .ok()
+        .map(|data| data.claims)
+}
+
+/// Checks the PKCE `code_verifier` against the stored `code_challenge`
+///
+/// Only the `S256` method is supported, see RFC 7636 Section 4.6
+pub(crate) fn verify_pkce(code_verifier: &str, code_challenge: &str) -> bool {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes())) == code_challenge
+}
@@ -0,0 +1,156 @@
+//! Time-based one-time passwords (RFC 6238)
+//!
+//! Only the parameters every authenticator app understands are
+//! supported: HMAC-SHA1, 6 digits and a period of 30 seconds.
+//! Storing secrets and remembering used codes is the job of
+//! [database::totp](crate::database::totp).
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use color_eyre::Report;
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use url::Url;
+
+/// Length of a generated secret in bytes, 160 bit as recommended by RFC 4226
+const SECRET_LENGTH: usize = 20;
+/// Length of a single time step in seconds
+const PERIOD: u64 = 30;
+/// Number of digits of a code
+const DIGITS: usize = 6;
+/// `10^DIGITS`, used to cut the truncated HMAC down to [DIGITS] digits
+const MODULUS: u32 = 1_000_000;
+/// How many steps before and after the current one are still accepted,
+/// compensates for clocks that are slightly off
+const ALLOWED_DRIFT: u64 = 1;
+
+/// Generates a new random secret
+pub(crate) fn generate_secret() -> Vec<u8> {
+    let mut secret = vec![0; SECRET_LENGTH];
+    OsRng.fill_bytes(&mut secret);
+    secret
+}
+
+/// Encodes the secret the way authenticator apps expect it for manual entry
+pub(crate) fn encode_secret(secret: &[u8]) -> String {
+    base32::encode(base32::Alphabet::RFC4648 { padding: false }, secret)
+}
+
+/// Builds the `otpauth://` URI which is usually shown as QR code
+///
+/// See <https://github.com/google/google-authenticator/wiki/Key-Uri-Format>
+pub(crate) fn provisioning_uri(secret: &[u8], issuer: &str, account: &str) -> Result<Url, Report> {
+    let mut uri = Url::parse("otpauth://totp/")?;
+    uri.set_path(&format!("{issuer}:{account}"));
+    uri.query_pairs_mut()
+        .append_pair("secret", &encode_secret(secret))
+        .append_pair("issuer", issuer)
+        .append_pair("algorithm", "SHA1")
+        .append_pair("digits", &DIGITS.to_string())
+        .append_pair("period", &PERIOD.to_string());
+
+    Ok(uri)
+}
+
+/// The time step we are currently in
+fn current_step() -> Result<u64, Report> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() / PERIOD)
+}
+
+/// Calculates the code for the given time step (RFC 4226, Section 5.3)
+fn code_at(secret: &[u8], step: u64) -> String {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC can take a key of any size");
+    mac.update(&step.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+
+    let offset = usize::from(hash[hash.len() - 1] & 0x0f);
+    let truncated = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+
+    format!("{:0DIGITS$}", truncated % MODULUS)
+}
+
+/// Checks `code` against the secret
+///
+/// Returns the time step the code belongs to if it is valid. Codes of
+/// steps up to and including `last_used_step` are rejected, so every
+/// code can only be used once.
+pub(crate) fn verify(
+    secret: &[u8],
+    code: &str,
+    last_used_step: Option<u64>,
+) -> Result<Option<u64>, Report> {
+    let code = code.trim();
+
+    let current = current_step()?;
+    let earliest = current
+        .saturating_sub(ALLOWED_DRIFT)
+        .max(last_used_step.map_or(0, |step| step + 1));
+
+    Ok((earliest..=current + ALLOWED_DRIFT).find(|&step| code_at(secret, step) == code))
+}
+
+#[cfg(test)]
+mod tests {
+    //! Checks against the test vectors of RFC 4226 and RFC 6238
+
+    use color_eyre::Report;
+
+    use super::{code_at, current_step, encode_secret, verify, PERIOD};
+
+    /// The secret of all test vectors, the ASCII digits `1234567890` twice
+    const SECRET: &[u8] = b"12345678901234567890";
+
+    /// RFC 4226, Appendix D, counters 0 to 9
+    #[test]
+    fn hotp_test_vectors() {
+        let expected = [
+            "755224", "287082", "359152", "969429", "338314", "254676", "287922", "162583",
+            "399871", "520489",
+        ];
+
+        for (counter, code) in (0..).zip(expected) {
+            assert_eq!(code_at(SECRET, counter), code, "counter {counter}");
+        }
+    }
+
+    /// RFC 6238, Appendix B, SHA-1 only and cut down to 6 digits
+    #[test]
+    fn totp_test_vectors() {
+        let expected = [
+            (59, "287082"),
+            (1_111_111_109, "081804"),
+            (1_111_111_111, "050471"),
+            (1_234_567_890, "005924"),
+            (2_000_000_000, "279037"),
+            (20_000_000_000, "353130"),
+        ];
+
+        for (time, code) in expected {
+            assert_eq!(code_at(SECRET, time / PERIOD), code, "time {time}");
+        }
+    }
+
+    /// The current code is accepted, but only once
+    #[test]
+    fn verify_accepts_current_code_once() -> Result<(), Report> {
+        let current = current_step()?;
+        let code = code_at(SECRET, current);
+
+        assert_eq!(verify(SECRET, &format!(" {code}\n"), None)?, Some(current));
+        assert_eq!(verify(SECRET, &code, Some(current))?, None);
+
+        Ok(())
+    }
+
+    /// Secrets are shown as unpadded base32
+    #[test]
+    fn secret_encoding() {
+        assert_eq!(encode_secret(SECRET), "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ");
+    }
+}
@@ -0,0 +1,307 @@
+//! Rules for new passwords
+//!
+//! Checked whenever a user chooses a password, i.e. on registration,
+//! reset and change. Existing passwords are never checked, so tightening the policy
+//! does not lock anybody out.
+
+use color_eyre::Report;
+use serde::Serialize;
+
+use crate::{error_handling::ApiError, settings::PasswordPolicyConfig, types::Password};
+
+use self::breached::BreachedPasswords;
+
+pub(crate) mod breached;
+
+/// Personal information shorter than this is not checked, otherwise a
+/// name like "Al" would forbid lots of passwords
+const MIN_PERSONAL_INFO_LENGTH: usize = 3;
+
+/// A rule of the [PasswordPolicyConfig] a password breaks
+///
+/// Serialized with a `code` field, so the frontend can show its own
+/// (translated) message.
+#[derive(Debug, Serialize, PartialEq, Eq)]
+#[serde(tag = "code", rename_all = "snake_case")]
+pub(crate) enum PolicyViolation {
+    /// Has less than `min_length` characters
+    TooShort {
+        /// See [PasswordPolicyConfig::min_length]
+        min_length: usize,
+    },
+    /// Has more than `max_length` characters
+    TooLong {
+        /// See [PasswordPolicyConfig::max_length]
+        max_length: usize,
+    },
+    /// Contains no lowercase letter
+    MissingLowercase,
+    /// Contains no uppercase letter
+    MissingUppercase,
+    /// Contains no digit
+    MissingDigit,
+    /// Contains nothing but letters and digits
+    MissingSymbol,
+    /// Contains the name or email of the user
+    ContainsPersonalInfo,
+    /// Is on the list of breached passwords
+    Breached,
+}
+
+/// The [PasswordPolicyConfig] with its list of breached passwords loaded
+///
+/// Created once at startup, since loading the list can take a while.
+pub(crate) struct PasswordPolicy {
+    /// The configured rules
+    rules: PasswordPolicyConfig,
+    /// See [PasswordPolicyConfig::breached_passwords]
+    breached: Option<BreachedPasswords>,
+}
+
+impl PasswordPolicy {
+    /// Loads the list of breached passwords, if one is configured
+    pub(crate) fn load(config: &PasswordPolicyConfig) -> Result<Self, Report> {
+        Ok(Self {
+            rules: config.clone(),
+            breached: BreachedPasswords::load(config)?,
+        })
+    }
+
+    /// Returns all rules `password` breaks
+    ///
+    /// `personal_info` are the name and email of the user, see [check].
+    pub(crate) async fn check(
+        &self,
+        password: &Password,
+        personal_info: &[&str],
+    ) -> Result<Vec<PolicyViolation>, Report> {
+        let mut violations = check(&self.rules, password, personal_info);
+
+        if let Some(breached) = &self.breached {
+            if breached.contains(password).await? {
+                violations.push(PolicyViolation::Breached);
+            }
+        }
+
+        Ok(violations)
+    }
+
+    /// Like [PasswordPolicy::check], but returns 422 with all violations
+    /// if there are any
+    pub(crate) async fn enforce(
+        &self,
+        password: &Password,
+        personal_info: &[&str],
+    ) -> Result<(), ApiError> {
+        let violations = self.check(password, personal_info).await?;
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(ApiError::WeakPassword(violations))
+        }
+    }
+}
+
+/// Returns all rules of `config` that `password` breaks, except for
+/// the list of breached passwords
+///
+/// `personal_info` are the name and email of the user, each is also
+/// checked by its words and the email by its local part.
+fn check(
+    config: &PasswordPolicyConfig,
+    password: &Password,
+    personal_info: &[&str],
+) -> Vec<PolicyViolation> {
+    let password = &password.0;
+    let mut violations = Vec::new();
+
+    let length = password.chars().count();
+    if length < config.min_length {
+        violations.push(PolicyViolation::TooShort {
+            min_length: config.min_length,
+        });
+    }
+    if length > config.max_length {
+        violations.push(PolicyViolation::TooLong {
+            max_length: config.max_length,
+        });
+    }
+
+    if config.require_lowercase && !password.chars().any(char::is_lowercase) {
+        violations.push(PolicyViolation::MissingLowercase);
+    }
+    if config.require_uppercase && !password.chars().any(char::is_uppercase) {
+        violations.push(PolicyViolation::MissingUppercase);
+    }
+    if config.require_digit && !password.chars().any(|c| c.is_ascii_digit()) {
+        violations.push(PolicyViolation::MissingDigit);
+    }
+    if config.require_symbol && password.chars().all(char::is_alphanumeric) {
+        violations.push(PolicyViolation::MissingSymbol);
+    }
+
+    if config.forbid_personal_info {
+        let lowercase = password.to_lowercase();
+        let contains_personal_info = personal_info
+            .iter()
+            .flat_map(|info| {
+                // "Alice Smith" is checked as "alice" and "smith" as well,
+                // "alice@example.com" as "alice"
+                let local_part = info.split_once('@').map(|(local_part, _)| local_part);
+                std::iter::once(*info)
+                    .chain(local_part)
+                    .chain(info.split_whitespace())
+            })
+            .filter(|info| info.chars().count() >= MIN_PERSONAL_INFO_LENGTH)
+            .any(|info| lowercase.contains(&info.to_lowercase()));
+        if contains_personal_info {
+            violations.push(PolicyViolation::ContainsPersonalInfo);
+        }
+    }
+
+    violations
+}
+
+#[cfg(test)]
+mod tests {
+    //! The rules except for the list of breached passwords, see
+    //! [breached](super::breached) for that
+
+    use super::{check, PolicyViolation};
+    use crate::{settings::PasswordPolicyConfig, types::Password};
+
+    /// Personal info of the test user
+    const ALICE: &[&str] = &["Alice Smith", "alice@example.com"];
+
+    /// Checks `password` with the personal info of [ALICE]
+    fn violations(config: &PasswordPolicyConfig, password: &str) -> Vec<PolicyViolation> {
+        check(config, &Password(password.to_owned()), ALICE)
+    }
+
+    /// Requires all character classes, but nothing else
+    fn all_classes() -> PasswordPolicyConfig {
+        PasswordPolicyConfig {
+            require_lowercase: true,
+            require_uppercase: true,
+            require_digit: true,
+            require_symbol: true,
+            forbid_personal_info: false,
+            ..PasswordPolicyConfig::default()
+        }
+    }
+
+    /// The defaults accept a long enough password
+    #[test]
+    fn default_accepts_long_password() {
+        assert_eq!(
+            violations(&PasswordPolicyConfig::default(), "correct horse"),
+            []
+        );
+    }
+
+    /// Minimum and maximum count characters, not bytes
+    #[test]
+    fn length() {
+        let config = PasswordPolicyConfig {
+            min_length: 4,
+            max_length: 6,
+            ..PasswordPolicyConfig::default()
+        };
+
+        assert_eq!(
+            violations(&config, "abc"),
+            [PolicyViolation::TooShort { min_length: 4 }]
+        );
+        assert_eq!(violations(&config, "abcd"), []);
+        assert_eq!(violations(&config, "äöüäöü"), []);
+        assert_eq!(
+            violations(&config, "abcdefg"),
+            [PolicyViolation::TooLong { max_length: 6 }]
+        );
+    }
+
+    /// Every missing class is reported on its own
+    #[test]
+    fn character_classes() {
+        let config = all_classes();
+
+        assert_eq!(violations(&config, "Abcdef1!"), []);
+        assert_eq!(
+            violations(&config, "ABCDEF1!"),
+            [PolicyViolation::MissingLowercase]
+        );
+        assert_eq!(
+            violations(&config, "abcdef1!"),
+            [PolicyViolation::MissingUppercase]
+        );
+        assert_eq!(
+            violations(&config, "Abcdefg!"),
+            [PolicyViolation::MissingDigit]
+        );
+        assert_eq!(
+            violations(&config, "Abcdefg1"),
+            [PolicyViolation::MissingSymbol]
+        );
+        assert_eq!(
+            violations(&config, "        "),
+            [
+                PolicyViolation::MissingLowercase,
+                PolicyViolation::MissingUppercase,
+                PolicyViolation::MissingDigit,
+            ]
+        );
+    }
+
+    /// Classes are not required unless configured
+    #[test]
+    fn character_classes_optional() {
+        assert_eq!(violations(&PasswordPolicyConfig::default(), "abcdefgh"), []);
+    }
+
+    /// The full name, its words and the local part of the email are
+    /// forbidden, ignoring case
+    #[test]
+    fn personal_info() {
+        let config = PasswordPolicyConfig::default();
+
+        for password in [
+            "xAlice Smithx",
+            "my name is alice",
+            "SMITH1234",
+            "xalice@example.comx",
+        ] {
+            assert_eq!(
+                violations(&config, password),
+                [PolicyViolation::ContainsPersonalInfo],
+                "{password}"
+            );
+        }
+        assert_eq!(violations(&config, "example passwords"), []);
+    }
+
+    /// Short parts of the personal info are ignored
+    #[test]
+    fn short_personal_info() {
+        let config = PasswordPolicyConfig::default();
+
+        assert_eq!(
+            check(&config, &Password("alpaca farm".to_owned()), &["Al Pa"]),
+            []
+        );
+        assert_eq!(
+            check(&config, &Password("alpaca farm".to_owned()), &["Alp"]),
+            [PolicyViolation::ContainsPersonalInfo]
+        );
+    }
+
+    /// Personal info is allowed unless configured
+    #[test]
+    fn personal_info_optional() {
+        let config = PasswordPolicyConfig {
+            forbid_personal_info: false,
+            ..PasswordPolicyConfig::default()
+        };
+
+        assert_eq!(violations(&config, "my name is alice"), []);
+    }
+}
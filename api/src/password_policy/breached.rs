@@ -0,0 +1,261 @@
+//! Offline check against passwords from known breaches
+//!
+//! The list is a local copy of the SHA-1 hashes published by Have I Been
+//! Pwned, no request ever leaves the server. Two formats are supported:
+//!
+//! - `text`: The downloadable format, one `HASH:COUNT` per line (the count
+//!   is optional). It is read into memory at startup, so only use it for
+//!   a subset of the list, i.e. the most common passwords.
+//! - `binary`: The raw 20 byte hashes, sorted, without any separators.
+//!   Looked up on disk using binary search, so the full list works as
+//!   well. Convert the ordered-by-hash download using
+//!   `cut -d: -f1 pwned-passwords-sha1.txt | xxd -r -p > pwned-passwords.bin`.
+
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+use color_eyre::{
+    eyre::{eyre, Context},
+    Report,
+};
+use sha1::{Digest, Sha1};
+use tracing::info;
+
+use crate::{
+    settings::{BreachListFormat, PasswordPolicyConfig},
+    types::Password,
+};
+
+/// Length of a SHA-1 hash in bytes
+const HASH_LENGTH: u64 = 20;
+
+/// A SHA-1 hash
+type Hash = [u8; 20];
+
+/// The loaded list of breached password hashes
+pub(crate) enum BreachedPasswords {
+    /// Read from a `text` list, sorted
+    InMemory(Vec<Hash>),
+    /// A `binary` list, read on every lookup
+    SortedFile {
+        /// Where the list is
+        path: PathBuf,
+        /// Number of hashes in the file
+        count: u64,
+    },
+}
+
+impl BreachedPasswords {
+    /// Loads the list configured in [PasswordPolicyConfig], if any
+    pub(crate) fn load(config: &PasswordPolicyConfig) -> Result<Option<Self>, Report> {
+        let Some(path) = &config.breached_passwords else {
+            return Ok(None);
+        };
+
+        let list = match config.breached_passwords_format {
+            BreachListFormat::Text => Self::load_text(path),
+            BreachListFormat::Binary => Self::open_binary(path),
+        }
+        .wrap_err_with(|| format!("Loading breached passwords from {path}"))?;
+
+        Ok(Some(list))
+    }
+
+    /// Reads and sorts all hashes of a `text` list
+    fn load_text(path: &str) -> Result<Self, Report> {
+        let mut hashes = Vec::new();
+        for (number, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+            let line = line?;
+            let hex = line.split(':').next().unwrap_or_default().trim();
+            if hex.is_empty() {
+                continue;
+            }
+            hashes
+                .push(parse_hash(hex).ok_or_else(|| eyre!("Invalid hash in line {}", number + 1))?);
+        }
+        hashes.sort_unstable();
+        hashes.dedup();
+
+        info!("Loaded {} breached password hashes", hashes.len());
+        Ok(Self::InMemory(hashes))
+    }
+
+    /// Checks the size of a `binary` list, the hashes stay on disk
+    fn open_binary(path: &str) -> Result<Self, Report> {
+        let size = std::fs::metadata(path)?.len();
+        if size % HASH_LENGTH != 0 {
+            return Err(eyre!("The size is not a multiple of {HASH_LENGTH} bytes"));
+        }
+
+        info!("Using {} breached password hashes", size / HASH_LENGTH);
+        Ok(Self::SortedFile {
+            path: PathBuf::from(path),
+            count: size / HASH_LENGTH,
+        })
+    }
+
+    /// Whether `password` is on the list
+    ///
+    /// Lookups in a `binary` list read about 30 hashes from disk, so
+    /// they run on the blocking thread pool.
+    pub(crate) async fn contains(&self, password: &Password) -> Result<bool, Report> {
+        let mut hash: Hash = [0; 20];
+        hash.copy_from_slice(&Sha1::digest(password.0.as_bytes()));
+
+        match self {
+            Self::InMemory(hashes) => Ok(hashes.binary_search(&hash).is_ok()),
+            Self::SortedFile { path, count } => {
+                let (path, count) = (path.clone(), *count);
+                tokio::task::spawn_blocking(move || search_file(&path, count, &hash)).await?
+            }
+        }
+    }
+}
+
+/// Binary search for `hash` in a `binary` list of `count` hashes
+fn search_file(path: &Path, count: u64, hash: &Hash) -> Result<bool, Report> {
+    let mut file = File::open(path)?;
+    let mut entry = [0; 20];
+    let (mut low, mut high) = (0, count);
+    while low < high {
+        let middle = low + (high - low) / 2;
+        file.seek(SeekFrom::Start(middle * HASH_LENGTH))?;
+        file.read_exact(&mut entry)?;
+        match entry.cmp(hash) {
+            std::cmp::Ordering::Less => low = middle + 1,
+            std::cmp::Ordering::Greater => high = middle,
+            std::cmp::Ordering::Equal => return Ok(true),
+        }
+    }
+
+    Ok(false)
+}
+
+/// Parses 40 hex digits (in any case)
+fn parse_hash(hex: &str) -> Option<Hash> {
+    // from_str_radix would also take a sign
+    if hex.len() != 40 || !hex.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    let mut hash = [0; 20];
+    for (byte, digits) in hash.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(digits).ok()?, 16).ok()?;
+    }
+
+    Some(hash)
+}
+
+#[cfg(test)]
+mod tests {
+    //! Both formats against the same list in `fixtures`
+
+    use color_eyre::{eyre::eyre, Report};
+
+    use super::{parse_hash, BreachedPasswords, Hash};
+    use crate::{
+        settings::{BreachListFormat, PasswordPolicyConfig},
+        types::Password,
+    };
+
+    /// The SHA-1 hash of "password"
+    const PASSWORD_HASH: Hash = [
+        0x5b, 0xaa, 0x61, 0xe4, 0xc9, 0xb9, 0x3f, 0x3f, 0x06, 0x82, 0x25, 0x0b, 0x6c, 0xf8, 0x33,
+        0x1b, 0x7e, 0xe6, 0x8f, 0xd8,
+    ];
+
+    /// Loads the fixture of the given format
+    fn load(format: BreachListFormat, file: &str) -> Result<BreachedPasswords, Report> {
+        let config = PasswordPolicyConfig {
+            breached_passwords: Some(format!(
+                "{}/src/password_policy/fixtures/{file}",
+                env!("CARGO_MANIFEST_DIR")
+            )),
+            breached_passwords_format: format,
+            ..PasswordPolicyConfig::default()
+        };
+
+        BreachedPasswords::load(&config)?.ok_or_else(|| eyre!("No list loaded"))
+    }
+
+    /// The first, a middle and the last entry are found, passwords
+    /// before, between and after the entries are not
+    async fn check_list(list: &BreachedPasswords) -> Result<(), Report> {
+        for (password, expected) in [
+            // Hash 5BAA61…, the first entry
+            ("password", true),
+            // Hash AF8978…
+            ("dragon", true),
+            // Hash F3BBBD…, the last entry and without count
+            ("hunter2", true),
+            // Hash 042DC4…, before the first entry
+            ("i", false),
+            // Hash ABF7AA…, between two entries
+            ("correct horse battery staple", false),
+            // Hash FFF0B4…, after the last entry
+            ("x57", false),
+        ] {
+            assert_eq!(
+                list.contains(&Password(password.to_owned())).await?,
+                expected,
+                "{password}"
+            );
+        }
+
+        Ok(())
+    }
+
+    /// The in-memory list of the downloadable format
+    #[tokio::test]
+    async fn text_list() -> Result<(), Report> {
+        check_list(&load(BreachListFormat::Text, "breached.txt")?).await
+    }
+
+    /// The binary search on disk
+    #[tokio::test]
+    async fn binary_list() -> Result<(), Report> {
+        let list = load(BreachListFormat::Binary, "breached.bin")?;
+        assert!(matches!(
+            list,
+            BreachedPasswords::SortedFile { count: 7, .. }
+        ));
+
+        check_list(&list).await
+    }
+
+    /// A file cut off in the middle of a hash is rejected
+    #[test]
+    fn truncated_binary_list() {
+        assert!(load(BreachListFormat::Binary, "breached.txt").is_err());
+    }
+
+    /// Hex digits are accepted in any case
+    #[test]
+    fn parse_hash_case() {
+        for hex in [
+            "5BAA61E4C9B93F3F0682250B6CF8331B7EE68FD8",
+            "5baa61e4c9b93f3f0682250b6cf8331b7ee68fd8",
+            "5bAa61E4c9B93f3F0682250b6Cf8331b7eE68Fd8",
+        ] {
+            assert_eq!(parse_hash(hex), Some(PASSWORD_HASH), "{hex}");
+        }
+    }
+
+    /// Anything but exactly 40 hex digits is rejected
+    #[test]
+    fn parse_invalid_hash() {
+        for hex in [
+            "",
+            "5BAA61E4C9B93F3F0682250B6CF8331B7EE68FD",
+            "5BAA61E4C9B93F3F0682250B6CF8331B7EE68FD80",
+            "5BAA61E4C9B93F3F0682250B6CF8331B7EE68FDG",
+            "5BAA61E4C9B93F3F0682250B6CF8331B7EE68Fä",
+            "+BAA61E4C9B93F3F0682250B6CF8331B7EE68FD8",
+        ] {
+            assert_eq!(parse_hash(hex), None, "{hex}");
+        }
+    }
+}
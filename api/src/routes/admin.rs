@@ -0,0 +1,255 @@
+//! User management, only for admins
+//!
+//! All routes require [AuthenticatedAdmin], except for viewing users,
+//! which is also allowed with the [ReadUsers] permission. Admins can't
+//! disable, delete or demote themselves, so there is always at least one
+//! admin left.
+
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, Query},
+    http::StatusCode,
+    Extension, Json,
+};
+use color_eyre::eyre::Context;
+use serde::Deserialize;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::{
+    database::{
+        admin::{self, AdminUserInfo, AdminUserUpdate, NewUser},
+        get_user_by_id,
+        lockout::unlock_account,
+        new_reset_request,
+    },
+    error_handling::ApiError,
+    hashing::{Hasher, HashingStats},
+    mail::Mailer,
+    middlewares::{
+        admin::AuthenticatedAdmin,
+        permission::{ReadUsers, RequirePermission},
+    },
+    password_policy::PasswordPolicy,
+    settings::Config,
+};
+
+/// How many users are listed if the client does not ask for a limit
+const DEFAULT_LIMIT: i64 = 50;
+
+/// The most users listed at once
+const MAX_LIMIT: i64 = 200;
+
+/// Query parameters for listing users
+#[derive(Debug, Deserialize)]
+pub(crate) struct UserSearch {
+    /// Only list users whose name or email contains this
+    search: Option<String>,
+    /// Page size, defaults to [DEFAULT_LIMIT], at most [MAX_LIMIT]
+    limit: Option<i64>,
+    /// Number of users to skip
+    offset: Option<i64>,
+}
+
+/// Lists (or searches) all users, ordered by name
+#[tracing::instrument(skip(pool))]
+pub(crate) async fn list_users(
+    Extension(pool): Extension<PgPool>,
+    _: RequirePermission<ReadUsers>,
+    Query(UserSearch {
+        search,
+        limit,
+        offset,
+    }): Query<UserSearch>,
+) -> Result<Json<Vec<AdminUserInfo>>, ApiError> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    let offset = offset.unwrap_or(0).max(0);
+
+    Ok(Json(
+        admin::list_users(&pool, search.as_deref(), limit, offset).await?,
+    ))
+}
+
+/// Returns a single user, 404 if they don't exist
+#[tracing::instrument(skip(pool))]
+pub(crate) async fn get_user(
+    Extension(pool): Extension<PgPool>,
+    _: RequirePermission<ReadUsers>,
+    Path(user_id): Path<Uuid>,
+) -> Result<Json<AdminUserInfo>, ApiError> {
+    Ok(Json(
+        admin::get_user_info(&pool, &user_id)
+            .await?
+            .ok_or(ApiError::UserNotFound)?,
+    ))
+}
+
+/// Creates a user, no matter whether registration is enabled
+///
+/// Returns 409 if the email is taken and 422 if the password does not
+/// follow the password policy.
+#[tracing::instrument(skip_all)]
+pub(crate) async fn create_user(
+    Extension(pool): Extension<PgPool>,
+    Extension(password_policy): Extension<Arc<PasswordPolicy>>,
+    Extension(hasher): Extension<Arc<Hasher>>,
+    _: AuthenticatedAdmin,
+    Json(user): Json<NewUser>,
+) -> Result<(StatusCode, Json<AdminUserInfo>), ApiError> {
+    password_policy
+        .enforce(&user.password, &[&user.name, &user.email.0])
+        .await?;
+
+    Ok((
+        StatusCode::CREATED,
+        Json(admin::create_user(&pool, &hasher, &user).await??),
+    ))
+}
+
+/// Updates name, email, locale or the admin flag of a user
+///
+/// Unlike a user changing their own email this does not require
+/// any confirmation. Returns 409 if the email is taken.
+#[tracing::instrument(skip(pool))]
+pub(crate) async fn update_user(
+    Extension(pool): Extension<PgPool>,
+    AuthenticatedAdmin(current_admin): AuthenticatedAdmin,
+    Path(user_id): Path<Uuid>,
+    Json(update): Json<AdminUserUpdate>,
+) -> Result<Json<AdminUserInfo>, ApiError> {
+    if user_id == current_admin.id && update.is_admin == Some(false) {
+        return Err(ApiError::OwnAccount);
+    }
+
+    Ok(Json(admin::update_user(&pool, &user_id, &update).await??))
+}
+
+/// Disables a user, they are logged out everywhere and can't log in anymore
+#[tracing::instrument(skip(pool, redis_client))]
+pub(crate) async fn disable_user(
+    Extension(pool): Extension<PgPool>,
+    Extension(redis_client): Extension<Arc<redis::Client>>,
+    AuthenticatedAdmin(current_admin): AuthenticatedAdmin,
+    Path(user_id): Path<Uuid>,
+) -> Result<(), ApiError> {
+    if user_id == current_admin.id {
+        return Err(ApiError::OwnAccount);
+    }
+
+    let mut redis_connection = redis_client
+        .get_async_connection()
+        .await
+        .wrap_err("Redis error")?;
+    if !admin::set_disabled(&pool, &mut redis_connection, &user_id, true).await? {
+        return Err(ApiError::UserNotFound);
+    }
+
+    Ok(())
+}
+
+/// Enables a disabled user again
+#[tracing::instrument(skip(pool, redis_client))]
+pub(crate) async fn enable_user(
+    Extension(pool): Extension<PgPool>,
+    Extension(redis_client): Extension<Arc<redis::Client>>,
+    _: AuthenticatedAdmin,
+    Path(user_id): Path<Uuid>,
+) -> Result<(), ApiError> {
+    let mut redis_connection = redis_client
+        .get_async_connection()
+        .await
+        .wrap_err("Redis error")?;
+    if !admin::set_disabled(&pool, &mut redis_connection, &user_id, false).await? {
+        return Err(ApiError::UserNotFound);
+    }
+
+    Ok(())
+}
+
+/// Deletes a user with all their sessions, credentials etc.
+#[tracing::instrument(skip(pool, redis_client))]
+pub(crate) async fn delete_user(
+    Extension(pool): Extension<PgPool>,
+    Extension(redis_client): Extension<Arc<redis::Client>>,
+    AuthenticatedAdmin(current_admin): AuthenticatedAdmin,
+    Path(user_id): Path<Uuid>,
+) -> Result<StatusCode, ApiError> {
+    if user_id == current_admin.id {
+        return Err(ApiError::OwnAccount);
+    }
+
+    let mut redis_connection = redis_client
+        .get_async_connection()
+        .await
+        .wrap_err("Redis error")?;
+    if !admin::delete_user(&pool, &mut redis_connection, &user_id).await? {
+        return Err(ApiError::UserNotFound);
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Lifts a lockout after too many failed logins of the user
+#[tracing::instrument(skip(pool, redis_client))]
+pub(crate) async fn unlock_user(
+    Extension(pool): Extension<PgPool>,
+    Extension(redis_client): Extension<Arc<redis::Client>>,
+    _: AuthenticatedAdmin,
+    Path(user_id): Path<Uuid>,
+) -> Result<(), ApiError> {
+    let user = get_user_by_id(&pool, &user_id)
+        .await?
+        .ok_or(ApiError::UserNotFound)?;
+
+    let mut redis_connection = redis_client
+        .get_async_connection()
+        .await
+        .wrap_err("Redis error")?;
+    unlock_account(&mut redis_connection, &user.email).await?;
+
+    Ok(())
+}
+
+/// Forces a user to choose a new password
+///
+/// The current password stops working, all sessions are revoked and
+/// the user gets a password reset mail.
+#[tracing::instrument(skip(pool, redis_client, config, mailer, hasher))]
+pub(crate) async fn force_password_reset(
+    Extension(pool): Extension<PgPool>,
+    Extension(redis_client): Extension<Arc<redis::Client>>,
+    Extension(config): Extension<Arc<Config>>,
+    Extension(mailer): Extension<Arc<Mailer>>,
+    Extension(hasher): Extension<Arc<Hasher>>,
+    _: AuthenticatedAdmin,
+    Path(user_id): Path<Uuid>,
+) -> Result<(), ApiError> {
+    let mut redis_connection = redis_client
+        .get_async_connection()
+        .await
+        .wrap_err("Redis error")?;
+    let user = admin::invalidate_password(&pool, &mut redis_connection, &hasher, &user_id)
+        .await?
+        .ok_or(ApiError::UserNotFound)?;
+
+    let reset_id = new_reset_request(&pool, &user.id).await?;
+    let link = config
+        .app
+        .frontend_link("reset", &[("token", &reset_id.to_string())])?;
+    mailer.send_password_reset(&user, &reset_id, &link).await?;
+
+    Ok(())
+}
+
+/// Load of the password hashing queue, see [Hasher]
+///
+/// A growing `waiting` or `average_wait_micros` means logins wait for
+/// hashing, raise `hashing.max_concurrent` if there are CPUs to spare.
+#[tracing::instrument(skip(hasher))]
+pub(crate) async fn hashing_stats(
+    Extension(hasher): Extension<Arc<Hasher>>,
+    _: AuthenticatedAdmin,
+) -> Json<HashingStats> {
+    Json(hasher.stats())
+}
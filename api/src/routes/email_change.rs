@@ -0,0 +1,97 @@
+//! Confirming and reverting email changes
+//!
+//! Changes are requested using [patch_user](super::user::patch_user),
+//! see [email_change](crate::database::email_change) for the rationale.
+
+use std::sync::Arc;
+
+use axum::{response::IntoResponse, Extension, Json};
+use color_eyre::eyre::Context;
+use serde::Deserialize;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::{
+    database::{
+        email_change::{self, request_email_change},
+        User,
+    },
+    error_handling::ApiError,
+    mail::Mailer,
+    settings::Config,
+    types::EMail,
+};
+
+/// Starts changing the email of `user` and sends the mails to the new
+/// and the old address
+pub(super) async fn start_email_change(
+    pool: &PgPool,
+    config: &Config,
+    mailer: &Mailer,
+    user: &User,
+    new_email: &EMail,
+) -> Result<(), ApiError> {
+    let change = request_email_change(pool, user, new_email).await??;
+
+    let confirm_link = config.app.frontend_link(
+        "confirm-email-change",
+        &[("token", &change.confirm_token.to_string())],
+    )?;
+    let revert_link = config.app.frontend_link(
+        "revert-email-change",
+        &[("token", &change.revert_token.to_string())],
+    )?;
+
+    mailer
+        .send_email_change_confirmation(user, new_email, &confirm_link)
+        .await?;
+    mailer
+        .send_email_change_notice(user, new_email, &revert_link)
+        .await?;
+
+    Ok(())
+}
+
+/// JSON containing the token of a confirmation or revert link
+#[derive(Debug, Deserialize)]
+pub(crate) struct EmailChangeToken {
+    /// The token sent by mail
+    token: Uuid,
+}
+
+/// Commits a pending email change, called from the link sent to the new address
+///
+/// Returns 404 if the token does not exist, 410 if it expired and 409
+/// if another account took the email in the meantime.
+#[tracing::instrument(skip(pool, config))]
+pub(crate) async fn confirm_email_change(
+    Extension(pool): Extension<PgPool>,
+    Extension(config): Extension<Arc<Config>>,
+    Json(EmailChangeToken { token }): Json<EmailChangeToken>,
+) -> Result<impl IntoResponse, ApiError> {
+    email_change::confirm_email_change(&pool, &config.verification, &token).await??;
+
+    Ok("Email was changed")
+}
+
+/// Restores the old email, called from the link sent to the old address
+///
+/// Logs the user out everywhere. Returns 404 if the token does not
+/// exist and 410 if it expired.
+#[tracing::instrument(skip(pool, redis_client, config))]
+pub(crate) async fn revert_email_change(
+    Extension(pool): Extension<PgPool>,
+    Extension(redis_client): Extension<Arc<redis::Client>>,
+    Extension(config): Extension<Arc<Config>>,
+    Json(EmailChangeToken { token }): Json<EmailChangeToken>,
+) -> Result<impl IntoResponse, ApiError> {
+    let mut redis_connection = redis_client
+        .get_async_connection()
+        .await
+        .wrap_err("Redis error")?;
+
+    email_change::revert_email_change(&pool, &mut redis_connection, &config.verification, &token)
+        .await??;
+
+    Ok("Email change was reverted")
+}
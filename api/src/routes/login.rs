@@ -6,15 +6,32 @@
 use std::sync::Arc;
 
 use axum::{Extension, Json};
+use color_eyre::Report;
+use serde::Deserialize;
 use sqlx::PgPool;
+use tracing::{error, Instrument, Span};
+use uuid::Uuid;
+use webauthn_rs::prelude::{PublicKeyCredential, RequestChallengeResponse, Webauthn};
 
 use crate::{
     database::{
-        auth::{login_user, Credentials, LoginError, Session},
-        remove_session,
+        auth::{
+            begin_passkey_login, begin_webauthn_second_factor, complete_login_with_recovery_code,
+            complete_login_with_totp, complete_login_with_webauthn, complete_passkey_login,
+            login_user, login_with_magic_link, Credentials, LoginError, LoginOutcome,
+            PasskeyChallenge, Session,
+        },
+        get_user_by_email,
+        lockout::LoginAttempt,
+        magic_link::new_magic_link,
+        recovery_codes, remove_session, User,
     },
     error_handling::ApiError,
-    middlewares::session::AuthenticatedSession,
+    hashing::Hasher,
+    mail::Mailer,
+    middlewares::{client_info::ClientInfo, session::AuthenticatedSession},
+    settings::Config,
+    types::EMail,
 };
 use color_eyre::eyre::Context;
 
@@ -45,18 +62,392 @@ pub(crate) async fn logout(
 /// Tries to log the user in
 ///
 /// Checks whether the credentials are valid (otherwise returns either 404
-/// if the user cannot be found or 401 if the password is wrong) and if so
+/// if the user cannot be found or 401 if the password is wrong, 401 in both
+/// cases if [prevent_user_enumeration](crate::settings::AppConfig::prevent_user_enumeration)
+/// is set) and if so
 /// returns the [Session] containing the session id and user object.
-#[tracing::instrument(skip(pool))]
+///
+/// If the user has a second factor set up, a pending login is returned
+/// instead, see [LoginOutcome]. If logins require a verified email,
+/// unverified users get a 403.
+///
+/// After too many failures for the account or the IP address logins are
+/// refused with 429 for a while, see [lockout](crate::database::lockout).
+#[tracing::instrument(skip(pool, redis_client, config, hasher))]
 pub(crate) async fn login(
     Extension(pool): Extension<PgPool>,
+    Extension(redis_client): Extension<Arc<redis::Client>>,
+    Extension(config): Extension<Arc<Config>>,
+    Extension(hasher): Extension<Arc<Hasher>>,
+    client: ClientInfo,
     Json(credentials): Json<Credentials>,
+) -> Result<Json<LoginOutcome>, ApiError> {
+    let mut redis_connection = redis_client
+        .get_async_connection()
+        .await
+        .wrap_err("Redis error")?;
+
+    let mut attempt = LoginAttempt::new(
+        &config.lockout,
+        &credentials.email,
+        client.ip_address.as_deref(),
+    );
+    if let Some(retry_after) = attempt
+        .reserve(&mut redis_connection, &config.lockout)
+        .await?
+    {
+        return Err(ApiError::TooManyAttempts(retry_after));
+    }
+
+    match login_user(&pool, &config, &hasher, credentials, &client).await? {
+        Ok(outcome) => {
+            attempt
+                .succeeded(&mut redis_connection, &config.lockout)
+                .await?;
+            Ok(Json(outcome))
+        }
+        Err(err @ (LoginError::InvalidCredentials | LoginError::UserNotFound)) => {
+            attempt
+                .failed(&mut redis_connection, &config.lockout)
+                .await?;
+            Err(err.into())
+        }
+        Err(err) => {
+            attempt
+                .cancelled(&mut redis_connection, &config.lockout)
+                .await?;
+            Err(err.into())
+        }
+    }
+}
+
+/// JSON for completing a login with a TOTP code
+#[derive(Debug, Deserialize)]
+pub(crate) struct TotpLogin {
+    /// The challenge returned by [login]
+    challenge_id: Uuid,
+    /// The current code of the authenticator app
+    code: String,
+}
+
+/// Second login step for users with TOTP
+///
+/// Returns 410 if the challenge expired (the password has to be entered
+/// again) and 422 if the code is wrong. After too many wrong codes for
+/// the user, across all challenges, 429 is returned for a while.
+#[tracing::instrument(skip(pool, redis_client, config, code))]
+pub(crate) async fn login_totp(
+    Extension(pool): Extension<PgPool>,
+    Extension(redis_client): Extension<Arc<redis::Client>>,
+    Extension(config): Extension<Arc<Config>>,
+    client: ClientInfo,
+    Json(TotpLogin { challenge_id, code }): Json<TotpLogin>,
+) -> Result<Json<Session>, ApiError> {
+    let mut redis_connection = redis_client
+        .get_async_connection()
+        .await
+        .wrap_err("Redis error")?;
+
+    Ok(Json(
+        complete_login_with_totp(
+            &pool,
+            &mut redis_connection,
+            &config.lockout,
+            &challenge_id,
+            &code,
+            &client,
+        )
+        .await??,
+    ))
+}
+
+/// JSON for completing a login with a recovery code
+#[derive(Debug, Deserialize)]
+pub(crate) struct RecoveryCodeLogin {
+    /// The challenge returned by [login]
+    challenge_id: Uuid,
+    /// One of the recovery codes, case and dashes don't matter
+    code: String,
+}
+
+/// Tells the user how many recovery codes are left after using one
+async fn notify_recovery_code_used(
+    pool: &PgPool,
+    mailer: &Mailer,
+    user: &User,
+) -> Result<(), Report> {
+    let remaining = recovery_codes::remaining(pool, &user.id).await?;
+    mailer.send_recovery_code_used(user, remaining).await
+}
+
+/// Second login step for users who lost their second factor
+///
+/// The code can't be used again and the user is notified by mail in the
+/// background, so a failing mail doesn't cost the code without a session.
+/// Returns 410 if the challenge expired and 422 if the code is wrong
+/// or already used. Wrong codes count towards the same limit as wrong
+/// TOTP codes, see [login_totp].
+#[tracing::instrument(skip(pool, redis_client, config, mailer, code))]
+pub(crate) async fn login_recovery_code(
+    Extension(pool): Extension<PgPool>,
+    Extension(redis_client): Extension<Arc<redis::Client>>,
+    Extension(config): Extension<Arc<Config>>,
+    Extension(mailer): Extension<Arc<Mailer>>,
+    client: ClientInfo,
+    Json(RecoveryCodeLogin { challenge_id, code }): Json<RecoveryCodeLogin>,
+) -> Result<Json<Session>, ApiError> {
+    let mut redis_connection = redis_client
+        .get_async_connection()
+        .await
+        .wrap_err("Redis error")?;
+
+    let (session, user) = complete_login_with_recovery_code(
+        &pool,
+        &mut redis_connection,
+        &config.lockout,
+        &challenge_id,
+        &code,
+        &client,
+    )
+    .await??;
+
+    tokio::spawn(
+        async move {
+            if let Err(e) = notify_recovery_code_used(&pool, &mailer, &user).await {
+                error!("Sending recovery code notification failed: {e:?}");
+            }
+        }
+        .instrument(Span::current()),
+    );
+
+    Ok(Json(session))
+}
+
+/// JSON for starting a passwordless login
+#[derive(Debug, Deserialize)]
+pub(crate) struct PasskeyLoginStart {
+    /// The email of the account to log into
+    email: EMail,
+}
+
+/// Starts a login with a passkey instead of a password
+///
+/// Returns 404 if the user does not exist or has no passkeys, unless
+/// [prevent_user_enumeration](crate::settings::AppConfig::prevent_user_enumeration)
+/// is set, then such a login fails only in [finish_login_webauthn].
+#[tracing::instrument(skip(pool, config, redis_client, webauthn))]
+pub(crate) async fn begin_login_webauthn(
+    Extension(pool): Extension<PgPool>,
+    Extension(config): Extension<Arc<Config>>,
+    Extension(redis_client): Extension<Arc<redis::Client>>,
+    Extension(webauthn): Extension<Arc<Webauthn>>,
+    Json(PasskeyLoginStart { email }): Json<PasskeyLoginStart>,
+) -> Result<Json<PasskeyChallenge>, ApiError> {
+    let mut redis_connection = redis_client
+        .get_async_connection()
+        .await
+        .wrap_err("Redis error")?;
+
+    Ok(Json(
+        begin_passkey_login(&pool, &config, &mut redis_connection, &webauthn, &email).await??,
+    ))
+}
+
+/// JSON for finishing a passwordless login
+#[derive(Debug, Deserialize)]
+pub(crate) struct PasskeyLoginFinish {
+    /// The ceremony returned by [begin_login_webauthn]
+    ceremony_id: Uuid,
+    /// The result of `navigator.credentials.get()`
+    credential: PublicKeyCredential,
+}
+
+/// Completes a login with a passkey
+///
+/// Returns the same [Session] as [login], 410 if the ceremony timed out
+/// and 401 if the passkey was rejected.
+#[tracing::instrument(skip(pool, config, redis_client, webauthn, credential))]
+pub(crate) async fn finish_login_webauthn(
+    Extension(pool): Extension<PgPool>,
+    Extension(config): Extension<Arc<Config>>,
+    Extension(redis_client): Extension<Arc<redis::Client>>,
+    Extension(webauthn): Extension<Arc<Webauthn>>,
+    client: ClientInfo,
+    Json(PasskeyLoginFinish {
+        ceremony_id,
+        credential,
+    }): Json<PasskeyLoginFinish>,
 ) -> Result<Json<Session>, ApiError> {
-    match login_user(&pool, credentials).await? {
-        Ok(session) => Ok(Json(session)),
-        Err(e) => Err(match e {
-            LoginError::UserNotFound => ApiError::UserNotFound,
-            LoginError::InvalidCredentials => ApiError::WrongCredentials,
-        }),
+    let mut redis_connection = redis_client
+        .get_async_connection()
+        .await
+        .wrap_err("Redis error")?;
+
+    Ok(Json(
+        complete_passkey_login(
+            &pool,
+            &config.verification,
+            &mut redis_connection,
+            &webauthn,
+            &ceremony_id,
+            &credential,
+            &client,
+        )
+        .await??,
+    ))
+}
+
+/// JSON for requesting a magic link
+#[derive(Debug, Deserialize)]
+pub(crate) struct MagicLinkRequest {
+    /// The email of the account to log into
+    email: EMail,
+}
+
+/// Creates a new magic link for the user and mails it
+async fn send_magic_link(
+    pool: &PgPool,
+    config: &Config,
+    mailer: &Mailer,
+    user: &User,
+) -> Result<(), Report> {
+    let token = new_magic_link(pool, &user.id).await?;
+
+    let link = config
+        .app
+        .frontend_link("magic-link", &[("token", &token.to_string())])?;
+    mailer.send_magic_link(user, &link).await
+}
+
+/// Sends a link to log in without a password
+///
+/// Returns 404 if the user does not exist, unless
+/// [prevent_user_enumeration](crate::settings::AppConfig::prevent_user_enumeration)
+/// is set. Then the link is sent in the background, so known and unknown
+/// emails take the same time to answer and mail errors are only logged.
+/// Requesting a new link invalidates the previous one. Only available if
+/// [magic links](crate::settings::MagicLinkConfig) are enabled.
+#[tracing::instrument(skip(pool, config, mailer))]
+pub(crate) async fn request_magic_link(
+    Extension(pool): Extension<PgPool>,
+    Extension(config): Extension<Arc<Config>>,
+    Extension(mailer): Extension<Arc<Mailer>>,
+    Json(MagicLinkRequest { email }): Json<MagicLinkRequest>,
+) -> Result<(), ApiError> {
+    let Some(user) = get_user_by_email(&pool, &email).await? else {
+        if config.app.prevent_user_enumeration {
+            return Ok(());
+        }
+        return Err(ApiError::UserNotFound);
+    };
+
+    if config.app.prevent_user_enumeration {
+        tokio::spawn(
+            async move {
+                if let Err(e) = send_magic_link(&pool, &config, &mailer, &user).await {
+                    error!("Sending magic link failed: {e:?}");
+                }
+            }
+            .instrument(Span::current()),
+        );
+        return Ok(());
     }
+
+    send_magic_link(&pool, &config, &mailer, &user).await?;
+
+    Ok(())
+}
+
+/// JSON for logging in with a magic link
+#[derive(Debug, Deserialize)]
+pub(crate) struct MagicLinkLogin {
+    /// The token of the link sent by [request_magic_link]
+    token: Uuid,
+}
+
+/// Logs in with the token of a magic link
+///
+/// Returns the same [LoginOutcome] as [login], so users with a second
+/// factor still have to provide it. The token can only be used once,
+/// returns 404 for unknown or used tokens and 410 for expired ones.
+#[tracing::instrument(skip(pool, config, token))]
+pub(crate) async fn login_magic_link(
+    Extension(pool): Extension<PgPool>,
+    Extension(config): Extension<Arc<Config>>,
+    client: ClientInfo,
+    Json(MagicLinkLogin { token }): Json<MagicLinkLogin>,
+) -> Result<Json<LoginOutcome>, ApiError> {
+    Ok(Json(
+        login_with_magic_link(&pool, &config, &token, &client).await??,
+    ))
+}
+
+/// JSON for starting WebAuthn as second factor
+#[derive(Debug, Deserialize)]
+pub(crate) struct WebauthnSecondFactorStart {
+    /// The challenge returned by [login]
+    challenge_id: Uuid,
+}
+
+/// Second login step for users with a security key, part one
+///
+/// Returns the options for `navigator.credentials.get()`.
+#[tracing::instrument(skip(pool, redis_client, webauthn))]
+pub(crate) async fn begin_second_factor_webauthn(
+    Extension(pool): Extension<PgPool>,
+    Extension(redis_client): Extension<Arc<redis::Client>>,
+    Extension(webauthn): Extension<Arc<Webauthn>>,
+    Json(WebauthnSecondFactorStart { challenge_id }): Json<WebauthnSecondFactorStart>,
+) -> Result<Json<RequestChallengeResponse>, ApiError> {
+    let mut redis_connection = redis_client
+        .get_async_connection()
+        .await
+        .wrap_err("Redis error")?;
+
+    Ok(Json(
+        begin_webauthn_second_factor(&pool, &mut redis_connection, &webauthn, &challenge_id)
+            .await??,
+    ))
+}
+
+/// JSON for completing a login with a security key
+#[derive(Debug, Deserialize)]
+pub(crate) struct WebauthnSecondFactorFinish {
+    /// The challenge returned by [login]
+    challenge_id: Uuid,
+    /// The result of `navigator.credentials.get()`
+    credential: PublicKeyCredential,
+}
+
+/// Second login step for users with a security key, part two
+///
+/// Returns 410 if the challenge expired and 422 if the
+/// assertion was rejected.
+#[tracing::instrument(skip(pool, redis_client, webauthn, credential))]
+pub(crate) async fn finish_second_factor_webauthn(
+    Extension(pool): Extension<PgPool>,
+    Extension(redis_client): Extension<Arc<redis::Client>>,
+    Extension(webauthn): Extension<Arc<Webauthn>>,
+    client: ClientInfo,
+    Json(WebauthnSecondFactorFinish {
+        challenge_id,
+        credential,
+    }): Json<WebauthnSecondFactorFinish>,
+) -> Result<Json<Session>, ApiError> {
+    let mut redis_connection = redis_client
+        .get_async_connection()
+        .await
+        .wrap_err("Redis error")?;
+
+    Ok(Json(
+        complete_login_with_webauthn(
+            &pool,
+            &mut redis_connection,
+            &webauthn,
+            &challenge_id,
+            &credential,
+            &client,
+        )
+        .await??,
+    ))
 }
@@ -1,6 +1,17 @@
 //! All final request handlers
 //!
 //! These handlers return the actual responses, semantically grouped
+pub(crate) mod admin;
+pub(crate) mod email_change;
 pub(crate) mod login;
+pub(crate) mod oidc;
+pub(crate) mod organizations;
+pub(crate) mod recovery_codes;
+pub(crate) mod registration;
 pub(crate) mod reset;
+pub(crate) mod roles;
+pub(crate) mod sessions;
+pub(crate) mod totp;
 pub(crate) mod user;
+pub(crate) mod verification;
+pub(crate) mod webauthn;
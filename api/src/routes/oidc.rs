@@ -0,0 +1,449 @@
+//! OpenID Connect provider endpoints
+//!
+//! Implements the authorization code flow, PKCE (S256) is mandatory
+//! for all clients. Clients are registered in the `oidc_clients` table.
+
+use std::sync::Arc;
+
+use axum::{
+    extract::{Query, RawQuery},
+    http::{
+        header::{HeaderName, AUTHORIZATION, CACHE_CONTROL},
+        HeaderMap,
+    },
+    response::Redirect,
+    Extension, Form, Json,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use color_eyre::eyre::eyre;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sqlx::PgPool;
+use url::Url;
+use uuid::Uuid;
+
+use crate::{
+    database::{
+        get_user_from_session,
+        oidc::{
+            create_authorization_code, get_active_user, get_client, redeem_authorization_code,
+            AuthorizationGrant,
+        },
+        roles::user_roles,
+    },
+    error_handling::{ApiError, OAuthError},
+    hashing::Hasher,
+    middlewares::session::AuthenticatedSession,
+    oidc::{
+        has_scope, issue_tokens, verify_access_token, verify_pkce, JwkSet, SigningKey, UserClaims,
+    },
+    settings::{Config, OidcConfig},
+};
+
+/// The scopes we know about, others are silently dropped
+const SUPPORTED_SCOPES: [&str; 3] = ["openid", "profile", "email"];
+
+/// Returns the OIDC config
+///
+/// The routes are only registered if it exists, so a missing
+/// config is a bug.
+fn oidc_config(config: &Config) -> Result<&OidcConfig, ApiError> {
+    config
+        .oidc
+        .as_ref()
+        .ok_or_else(|| eyre!("OIDC route called without OIDC config").into())
+}
+
+/// The discovery document (OpenID Connect Discovery 1.0)
+#[tracing::instrument(skip(config))]
+pub(crate) async fn discovery(
+    Extension(config): Extension<Arc<Config>>,
+) -> Result<Json<Value>, ApiError> {
+    let issuer = &oidc_config(&config)?.issuer;
+
+    Ok(Json(json!({
+        "issuer": issuer,
+        "authorization_endpoint": format!("{issuer}/authorize"),
+        "token_endpoint": format!("{issuer}/token"),
+        "userinfo_endpoint": format!("{issuer}/userinfo"),
+        "jwks_uri": format!("{issuer}/jwks"),
+        "response_types_supported": ["code"],
+        "grant_types_supported": ["authorization_code"],
+        "subject_types_supported": ["public"],
+        "id_token_signing_alg_values_supported": ["RS256"],
+        "scopes_supported": SUPPORTED_SCOPES,
+        "claims_supported": ["sub", "iss", "aud", "exp", "iat", "nonce", "name", "email", "roles"],
+        "token_endpoint_auth_methods_supported": ["client_secret_basic", "client_secret_post", "none"],
+        "code_challenge_methods_supported": ["S256"],
+    })))
+}
+
+/// The public keys tokens can be verified with
+#[tracing::instrument(skip(key))]
+pub(crate) async fn jwks(Extension(key): Extension<Arc<SigningKey>>) -> Json<JwkSet> {
+    Json(key.jwks())
+}
+
+/// Query parameters of the authorization endpoint
+#[allow(clippy::missing_docs_in_private_items)]
+#[derive(Debug, Deserialize)]
+pub(crate) struct AuthorizationRequest {
+    response_type: String,
+    client_id: String,
+    redirect_uri: String,
+    #[serde(default)]
+    scope: String,
+    state: Option<String>,
+    nonce: Option<String>,
+    code_challenge: Option<String>,
+    code_challenge_method: Option<String>,
+    prompt: Option<String>,
+}
+
+/// Builds the redirect back to the client with the given query
+/// parameters, adding `state` if the client sent one
+fn client_redirect(
+    redirect_uri: &str,
+    state: Option<&str>,
+    params: &[(&str, &str)],
+) -> Result<Url, ApiError> {
+    let mut url = Url::parse(redirect_uri).map_err(|e| ApiError::UnknownError(e.into()))?;
+    {
+        let mut query = url.query_pairs_mut();
+        query.extend_pairs(params);
+        if let Some(state) = state {
+            query.append_pair("state", state);
+        }
+    }
+
+    Ok(url)
+}
+
+/// Builds the error redirect to the client, see RFC 6749, Section 4.1.2.1
+fn error_redirect(
+    request: &AuthorizationRequest,
+    error: &str,
+    description: &str,
+) -> Result<Url, ApiError> {
+    client_redirect(
+        &request.redirect_uri,
+        request.state.as_deref(),
+        &[("error", error), ("error_description", description)],
+    )
+}
+
+/// Checks an authorization request
+///
+/// Unknown clients and unregistered redirect URIs are answered directly,
+/// all other errors are reported to the client by the returned redirect,
+/// as required by RFC 6749. Returns the PKCE code challenge of a valid
+/// request.
+async fn check_authorization_request<'r>(
+    pool: &PgPool,
+    request: &'r AuthorizationRequest,
+) -> Result<Result<&'r str, Url>, ApiError> {
+    let client = get_client(pool, &request.client_id)
+        .await?
+        .ok_or(ApiError::OAuth(OAuthError::InvalidClient, "Unknown client"))?;
+    if !client.redirect_uris.contains(&request.redirect_uri) {
+        return Err(ApiError::OAuth(
+            OAuthError::InvalidRequest,
+            "redirect_uri is not registered for this client",
+        ));
+    }
+
+    if request.response_type != "code" {
+        return Ok(Err(error_redirect(
+            request,
+            "unsupported_response_type",
+            "Only the authorization code flow is supported",
+        )?));
+    }
+    if !has_scope(&request.scope, "openid") {
+        return Ok(Err(error_redirect(
+            request,
+            "invalid_scope",
+            "The openid scope is required",
+        )?));
+    }
+    let (Some(code_challenge), Some("S256")) = (
+        request.code_challenge.as_deref(),
+        request.code_challenge_method.as_deref(),
+    ) else {
+        return Ok(Err(error_redirect(
+            request,
+            "invalid_request",
+            "PKCE with S256 is required",
+        )?));
+    };
+
+    Ok(Ok(code_challenge))
+}
+
+/// The authorization endpoint
+///
+/// Browsers don't send the session of the frontend to the API, so after
+/// checking the request the user is always sent to the
+/// [login page](OidcConfig::login_url) of the frontend, which gets the
+/// request in `authorization_request`. Once the user is logged in the
+/// frontend passes it on to [authorize_session].
+#[tracing::instrument(skip(pool, config))]
+pub(crate) async fn authorize(
+    Extension(pool): Extension<PgPool>,
+    Extension(config): Extension<Arc<Config>>,
+    RawQuery(raw_query): RawQuery,
+    Query(request): Query<AuthorizationRequest>,
+) -> Result<Redirect, ApiError> {
+    let oidc = oidc_config(&config)?;
+
+    if let Err(redirect) = check_authorization_request(&pool, &request).await? {
+        return Ok(Redirect::to(redirect.as_str()));
+    }
+
+    let mut login_url =
+        Url::parse(&oidc.login_url).map_err(|e| ApiError::UnknownError(e.into()))?;
+    login_url
+        .query_pairs_mut()
+        .append_pair("authorization_request", &raw_query.unwrap_or_default());
+
+    Ok(Redirect::to(login_url.as_str()))
+}
+
+/// Where the frontend has to send the browser
+#[derive(Debug, Serialize)]
+pub(crate) struct AuthorizationRedirect {
+    /// The redirect URI of the client with either a code or an error
+    pub(crate) redirect_to: String,
+}
+
+/// Completes an authorization request for the current user
+///
+/// Called by the frontend with the session of the user and the query
+/// it got from [authorize]. Without a session 401 is returned, unless
+/// the client asked for `prompt=none`, then the client is told that the
+/// user has to log in.
+#[tracing::instrument(skip(pool))]
+pub(crate) async fn authorize_session(
+    Extension(pool): Extension<PgPool>,
+    session: Option<AuthenticatedSession>,
+    Query(request): Query<AuthorizationRequest>,
+) -> Result<Json<AuthorizationRedirect>, ApiError> {
+    let code_challenge = match check_authorization_request(&pool, &request).await? {
+        Ok(code_challenge) => code_challenge,
+        Err(redirect) => {
+            return Ok(Json(AuthorizationRedirect {
+                redirect_to: redirect.into(),
+            }))
+        }
+    };
+
+    let user = match session {
+        Some(AuthenticatedSession(session_id)) => get_user_from_session(&pool, &session_id).await?,
+        None => None,
+    };
+    let Some(user) = user else {
+        if request.prompt.as_deref() == Some("none") {
+            return Ok(Json(AuthorizationRedirect {
+                redirect_to: error_redirect(
+                    &request,
+                    "login_required",
+                    "The user is not logged in",
+                )?
+                .into(),
+            }));
+        }
+        return Err(ApiError::NotLoggedIn);
+    };
+
+    let scope = request
+        .scope
+        .split_ascii_whitespace()
+        .filter(|scope| SUPPORTED_SCOPES.contains(scope))
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    let code = create_authorization_code(
+        &pool,
+        &user.id,
+        &AuthorizationGrant {
+            client_id: request.client_id.clone(),
+            redirect_uri: request.redirect_uri.clone(),
+            scope,
+            nonce: request.nonce.clone(),
+            code_challenge: code_challenge.to_owned(),
+        },
+    )
+    .await?;
+
+    Ok(Json(AuthorizationRedirect {
+        redirect_to: client_redirect(
+            &request.redirect_uri,
+            request.state.as_deref(),
+            &[("code", &code.to_string())],
+        )?
+        .into(),
+    }))
+}
+
+/// Form body of the token endpoint
+#[allow(clippy::missing_docs_in_private_items)]
+#[derive(Deserialize)]
+pub(crate) struct TokenRequest {
+    grant_type: String,
+    code: String,
+    redirect_uri: String,
+    code_verifier: String,
+    /// Only for `client_secret_post` and public clients
+    client_id: Option<String>,
+    /// Only for `client_secret_post`
+    client_secret: Option<String>,
+}
+
+/// Successful response of the token endpoint
+#[allow(clippy::missing_docs_in_private_items)]
+#[derive(Serialize)]
+pub(crate) struct TokenResponse {
+    pub(crate) access_token: String,
+    pub(crate) token_type: &'static str,
+    pub(crate) expires_in: i64,
+    pub(crate) id_token: String,
+    pub(crate) scope: String,
+}
+
+/// A [TokenResponse] with the `Cache-Control: no-store` header tokens
+/// require (RFC 6749, Section 5.1)
+pub(crate) type TokenReply = ([(HeaderName, &'static str); 1], Json<TokenResponse>);
+
+/// Reads client credentials sent via `client_secret_basic`
+fn basic_auth(headers: &HeaderMap) -> Option<(String, String)> {
+    let encoded = headers
+        .get(AUTHORIZATION)?
+        .as_bytes()
+        .strip_prefix(b"Basic ")?;
+    let decoded = String::from_utf8(STANDARD.decode(encoded).ok()?).ok()?;
+    let (client_id, client_secret) = decoded.split_once(':')?;
+
+    Some((client_id.to_owned(), client_secret.to_owned()))
+}
+
+/// The token endpoint
+///
+/// Exchanges an authorization code for an id and access token. Codes of
+/// users that were disabled in the meantime are rejected.
+#[tracing::instrument(skip_all)]
+pub(crate) async fn token(
+    Extension(pool): Extension<PgPool>,
+    Extension(config): Extension<Arc<Config>>,
+    Extension(key): Extension<Arc<SigningKey>>,
+    Extension(hasher): Extension<Arc<Hasher>>,
+    headers: HeaderMap,
+    Form(request): Form<TokenRequest>,
+) -> Result<TokenReply, ApiError> {
+    let oidc = oidc_config(&config)?;
+
+    if request.grant_type != "authorization_code" {
+        return Err(ApiError::OAuth(
+            OAuthError::UnsupportedGrantType,
+            "Only authorization_code is supported",
+        ));
+    }
+
+    let (client_id, client_secret) = match (basic_auth(&headers), request.client_id) {
+        (Some((id, secret)), None) => (id, Some(secret)),
+        (Some((id, secret)), Some(form_id)) if id == form_id => (id, Some(secret)),
+        (None, Some(id)) => (id, request.client_secret),
+        _ => {
+            return Err(ApiError::OAuth(
+                OAuthError::InvalidClient,
+                "Missing or conflicting client authentication",
+            ))
+        }
+    };
+
+    let client = get_client(&pool, &client_id)
+        .await?
+        .ok_or(ApiError::OAuth(OAuthError::InvalidClient, "Unknown client"))?;
+    if client.is_confidential() {
+        let authenticated = match client_secret {
+            Some(secret) => client.verify_secret(&hasher, &secret).await?,
+            None => false,
+        };
+        if !authenticated {
+            return Err(ApiError::OAuth(
+                OAuthError::InvalidClient,
+                "Client authentication failed",
+            ));
+        }
+    }
+
+    let invalid_grant = ApiError::OAuth(
+        OAuthError::InvalidGrant,
+        "The code is invalid, expired or was issued to another client",
+    );
+    let Ok(code) = Uuid::try_parse(&request.code) else {
+        return Err(invalid_grant);
+    };
+    let Some((grant, user)) = redeem_authorization_code(&pool, &code).await? else {
+        return Err(invalid_grant);
+    };
+    if grant.client_id != client.id
+        || grant.redirect_uri != request.redirect_uri
+        || !verify_pkce(&request.code_verifier, &grant.code_challenge)
+    {
+        return Err(invalid_grant);
+    }
+
+    let roles = user_roles(&pool, &user.id).await?;
+    let tokens = issue_tokens(
+        oidc,
+        &key,
+        &user,
+        roles,
+        &client.id,
+        &grant.scope,
+        grant.nonce,
+    )?;
+
+    Ok((
+        [(CACHE_CONTROL, "no-store")],
+        Json(TokenResponse {
+            access_token: tokens.access_token,
+            token_type: "Bearer",
+            expires_in: oidc.token_lifetime,
+            id_token: tokens.id_token,
+            scope: grant.scope,
+        }),
+    ))
+}
+
+/// The userinfo endpoint
+///
+/// Requires an access token issued by [token] instead of a session.
+/// Tokens of deleted or disabled users are rejected, even if they have
+/// not expired yet.
+#[tracing::instrument(skip_all)]
+pub(crate) async fn userinfo(
+    Extension(pool): Extension<PgPool>,
+    Extension(config): Extension<Arc<Config>>,
+    Extension(key): Extension<Arc<SigningKey>>,
+    headers: HeaderMap,
+) -> Result<Json<UserClaims>, ApiError> {
+    let oidc = oidc_config(&config)?;
+
+    let invalid_token = || ApiError::OAuth(OAuthError::InvalidToken, "Invalid access token");
+    let token = headers
+        .get(AUTHORIZATION)
+        .and_then(|header| header.to_str().ok())
+        .and_then(|header| header.strip_prefix("Bearer "))
+        .ok_or_else(invalid_token)?;
+    let claims = verify_access_token(oidc, &key, token).ok_or_else(invalid_token)?;
+
+    let user = get_active_user(&pool, &claims.sub)
+        .await?
+        .ok_or_else(invalid_token)?;
+
+    // Roles may have changed since the token was issued
+    let roles = user_roles(&pool, &user.id).await?;
+
+    Ok(Json(UserClaims::new(&user, roles, &claims.scope)))
+}
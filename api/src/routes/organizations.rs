@@ -0,0 +1,237 @@
+//! Organizations, their members and the active organization of a session
+//!
+//! See [organizations](crate::database::organizations) for the concepts.
+
+use std::sync::Arc;
+
+use axum::{extract::Path, http::StatusCode, Extension, Json};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::{
+    database::{
+        get_user_from_session,
+        organizations::{
+            self, get_organization, get_organization_admin, Member, MemberUpdate, Membership,
+            NewInvite, Organization,
+        },
+        User,
+    },
+    error_handling::ApiError,
+    mail::Mailer,
+    middlewares::{admin::AuthenticatedAdmin, session::AuthenticatedSession},
+    settings::Config,
+};
+
+/// Returns the user of the session if they may manage the organization
+async fn organization_admin(
+    pool: &PgPool,
+    session_id: &Uuid,
+    organization_id: &Uuid,
+) -> Result<User, ApiError> {
+    get_organization_admin(pool, session_id, organization_id)
+        .await?
+        .ok_or(ApiError::NotOrganizationAdmin)
+}
+
+/// Lists the organizations of the current user
+#[tracing::instrument(skip(pool))]
+pub(crate) async fn list_memberships(
+    Extension(pool): Extension<PgPool>,
+    AuthenticatedSession(session_id): AuthenticatedSession,
+) -> Result<Json<Vec<Membership>>, ApiError> {
+    let user = get_user_from_session(&pool, &session_id)
+        .await?
+        .ok_or(ApiError::InvalidSession)?;
+
+    Ok(Json(organizations::memberships(&pool, &user.id).await?))
+}
+
+/// Lists all organizations, only for admins
+#[tracing::instrument(skip(pool))]
+pub(crate) async fn list_organizations(
+    Extension(pool): Extension<PgPool>,
+    _: AuthenticatedAdmin,
+) -> Result<Json<Vec<Organization>>, ApiError> {
+    Ok(Json(organizations::list_organizations(&pool).await?))
+}
+
+/// JSON for creating an organization
+#[derive(Debug, Deserialize)]
+pub(crate) struct NewOrganization {
+    /// Display name, does not have to be unique
+    name: String,
+}
+
+/// Creates an organization, only for admins
+///
+/// The organization has no members, invite its first admin using
+/// [invite_member].
+#[tracing::instrument(skip(pool))]
+pub(crate) async fn create_organization(
+    Extension(pool): Extension<PgPool>,
+    _: AuthenticatedAdmin,
+    Json(NewOrganization { name }): Json<NewOrganization>,
+) -> Result<(StatusCode, Json<Organization>), ApiError> {
+    Ok((
+        StatusCode::CREATED,
+        Json(organizations::create_organization(&pool, &name).await?),
+    ))
+}
+
+/// Deletes an organization with all memberships, only for admins
+#[tracing::instrument(skip(pool))]
+pub(crate) async fn delete_organization(
+    Extension(pool): Extension<PgPool>,
+    _: AuthenticatedAdmin,
+    Path(organization_id): Path<Uuid>,
+) -> Result<StatusCode, ApiError> {
+    if !organizations::delete_organization(&pool, &organization_id).await? {
+        return Err(ApiError::OrganizationNotFound);
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Lists all members of an organization, only for its admins
+#[tracing::instrument(skip(pool))]
+pub(crate) async fn list_members(
+    Extension(pool): Extension<PgPool>,
+    AuthenticatedSession(session_id): AuthenticatedSession,
+    Path(organization_id): Path<Uuid>,
+) -> Result<Json<Vec<Member>>, ApiError> {
+    organization_admin(&pool, &session_id, &organization_id).await?;
+
+    Ok(Json(
+        organizations::list_members(&pool, &organization_id).await?,
+    ))
+}
+
+/// Changes the admin flag and/or the roles of a member, only for
+/// admins of the organization
+#[tracing::instrument(skip(pool))]
+pub(crate) async fn update_member(
+    Extension(pool): Extension<PgPool>,
+    AuthenticatedSession(session_id): AuthenticatedSession,
+    Path((organization_id, user_id)): Path<(Uuid, Uuid)>,
+    Json(update): Json<MemberUpdate>,
+) -> Result<(), ApiError> {
+    organization_admin(&pool, &session_id, &organization_id).await?;
+
+    organizations::update_member(&pool, &organization_id, &user_id, &update).await??;
+
+    Ok(())
+}
+
+/// Removes a member from an organization
+///
+/// Allowed for admins of the organization and for members leaving it.
+#[tracing::instrument(skip(pool))]
+pub(crate) async fn remove_member(
+    Extension(pool): Extension<PgPool>,
+    AuthenticatedSession(session_id): AuthenticatedSession,
+    Path((organization_id, user_id)): Path<(Uuid, Uuid)>,
+) -> Result<StatusCode, ApiError> {
+    let user = get_user_from_session(&pool, &session_id)
+        .await?
+        .ok_or(ApiError::InvalidSession)?;
+    if user.id != user_id {
+        organization_admin(&pool, &session_id, &organization_id).await?;
+    }
+
+    if !organizations::remove_member(&pool, &organization_id, &user_id).await? {
+        return Err(ApiError::MemberNotFound);
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// A freshly sent organization invite
+#[derive(Debug, Serialize)]
+pub(crate) struct SentInvite {
+    /// The token of the link in the mail
+    invite: Uuid,
+}
+
+/// Invites somebody by email, only for admins of the organization
+///
+/// The mail links to the frontend, which passes the token to
+/// [accept_invite]. Returns 404 if one of the roles does not exist.
+#[tracing::instrument(skip(pool, config, mailer))]
+pub(crate) async fn invite_member(
+    Extension(pool): Extension<PgPool>,
+    Extension(config): Extension<Arc<Config>>,
+    Extension(mailer): Extension<Arc<Mailer>>,
+    AuthenticatedSession(session_id): AuthenticatedSession,
+    Path(organization_id): Path<Uuid>,
+    Json(invite): Json<NewInvite>,
+) -> Result<(StatusCode, Json<SentInvite>), ApiError> {
+    let inviter = organization_admin(&pool, &session_id, &organization_id).await?;
+    let organization = get_organization(&pool, &organization_id)
+        .await?
+        .ok_or(ApiError::OrganizationNotFound)?;
+
+    let token =
+        organizations::create_invite(&pool, &organization_id, &inviter.id, &invite).await??;
+
+    let link = config
+        .app
+        .frontend_link("accept-invite", &[("token", &token.to_string())])?;
+    mailer
+        .send_organization_invite(&inviter, &invite.email, &organization.name, &link)
+        .await?;
+
+    Ok((StatusCode::CREATED, Json(SentInvite { invite: token })))
+}
+
+/// JSON containing the token of an organization invite
+#[derive(Debug, Deserialize)]
+pub(crate) struct InviteToken {
+    /// The token sent by mail
+    token: Uuid,
+}
+
+/// Joins an organization using an invite
+///
+/// The current user has to have the email the invite was sent to,
+/// otherwise returns 403. Returns 404 if the token does not exist and
+/// 410 if it expired.
+#[tracing::instrument(skip(pool, config))]
+pub(crate) async fn accept_invite(
+    Extension(pool): Extension<PgPool>,
+    Extension(config): Extension<Arc<Config>>,
+    AuthenticatedSession(session_id): AuthenticatedSession,
+    Json(InviteToken { token }): Json<InviteToken>,
+) -> Result<Json<Vec<Membership>>, ApiError> {
+    let user = get_user_from_session(&pool, &session_id)
+        .await?
+        .ok_or(ApiError::InvalidSession)?;
+
+    organizations::accept_invite(&pool, config.registration.invite_lifetime, &token, &user)
+        .await??;
+
+    Ok(Json(organizations::memberships(&pool, &user.id).await?))
+}
+
+/// JSON for switching the active organization
+#[derive(Debug, Deserialize)]
+pub(crate) struct ActiveOrganization {
+    /// `null` to not act on behalf of any organization
+    organization: Option<Uuid>,
+}
+
+/// Switches the active organization of the current session
+///
+/// Roles within the active organization count for permission checks.
+/// Returns 404 if the user is not a member of the organization.
+#[tracing::instrument(skip(pool))]
+pub(crate) async fn switch_organization(
+    Extension(pool): Extension<PgPool>,
+    AuthenticatedSession(session_id): AuthenticatedSession,
+    Json(ActiveOrganization { organization }): Json<ActiveOrganization>,
+) -> Result<(), ApiError> {
+    organizations::set_active_organization(&pool, &session_id, organization).await??;
+
+    Ok(())
+}
@@ -0,0 +1,64 @@
+//! Managing the recovery codes of the current user
+//!
+//! Logging in with a code is part of [login](super::login).
+
+use axum::{Extension, Json};
+use serde::Serialize;
+use sqlx::PgPool;
+
+use crate::{
+    database::{auth::second_factors, get_user_from_session, recovery_codes},
+    error_handling::ApiError,
+    middlewares::session::AuthenticatedSession,
+};
+
+/// How many recovery codes are left
+#[derive(Debug, Serialize)]
+pub(crate) struct RemainingCodes {
+    /// Unused codes, the codes themselves can't be shown again
+    remaining: i64,
+}
+
+/// Returns how many unused recovery codes the current user has
+#[tracing::instrument(skip(pool))]
+pub(crate) async fn get_recovery_codes(
+    Extension(pool): Extension<PgPool>,
+    AuthenticatedSession(session_id): AuthenticatedSession,
+) -> Result<Json<RemainingCodes>, ApiError> {
+    let user = get_user_from_session(&pool, &session_id)
+        .await?
+        .ok_or(ApiError::InvalidSession)?;
+
+    Ok(Json(RemainingCodes {
+        remaining: recovery_codes::remaining(&pool, &user.id).await?,
+    }))
+}
+
+/// Freshly generated recovery codes
+#[derive(Debug, Serialize)]
+pub(crate) struct NewCodes {
+    /// Have to be shown to the user, they can't be retrieved later
+    codes: Vec<String>,
+}
+
+/// Generates new recovery codes for the current user
+///
+/// All previous codes stop working. Returns 412 if the user has no
+/// second factor, since there would be nothing to recover.
+#[tracing::instrument(skip(pool))]
+pub(crate) async fn regenerate_recovery_codes(
+    Extension(pool): Extension<PgPool>,
+    AuthenticatedSession(session_id): AuthenticatedSession,
+) -> Result<Json<NewCodes>, ApiError> {
+    let user = get_user_from_session(&pool, &session_id)
+        .await?
+        .ok_or(ApiError::InvalidSession)?;
+
+    if second_factors(&pool, &user.id).await?.is_empty() {
+        return Err(ApiError::NoSecondFactor);
+    }
+
+    Ok(Json(NewCodes {
+        codes: recovery_codes::regenerate(&pool, &user.id).await?,
+    }))
+}
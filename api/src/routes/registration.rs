@@ -0,0 +1,79 @@
+//! Creating accounts
+//!
+//! Who may register is configured in [RegistrationConfig](crate::settings::RegistrationConfig).
+
+use std::sync::Arc;
+
+use axum::{http::StatusCode, Extension, Json};
+use serde::Serialize;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::{
+    database::{
+        registration::{self, register_user, Registration},
+        User,
+    },
+    error_handling::ApiError,
+    hashing::Hasher,
+    mail::Mailer,
+    middlewares::admin::AuthenticatedAdmin,
+    password_policy::PasswordPolicy,
+    settings::{Config, RegistrationMode},
+};
+
+use super::verification::send_verification;
+
+/// Creates a new account
+///
+/// Returns 403 if registration is disabled or the invite is invalid
+/// and 409 if the email is already taken, 422 if the password does not
+/// follow the password policy. The new user has to log in afterwards, a
+/// mail to verify the email is sent.
+#[tracing::instrument(skip_all)]
+pub(crate) async fn register(
+    Extension(pool): Extension<PgPool>,
+    Extension(config): Extension<Arc<Config>>,
+    Extension(mailer): Extension<Arc<Mailer>>,
+    Extension(password_policy): Extension<Arc<PasswordPolicy>>,
+    Extension(hasher): Extension<Arc<Hasher>>,
+    Json(registration): Json<Registration>,
+) -> Result<(StatusCode, Json<User>), ApiError> {
+    let user = register_user(
+        &pool,
+        &config.registration,
+        &password_policy,
+        &hasher,
+        &registration,
+    )
+    .await??;
+    send_verification(&pool, &config, &mailer, &user).await?;
+
+    Ok((StatusCode::CREATED, Json(user)))
+}
+
+/// A freshly created invite
+#[derive(Debug, Serialize)]
+pub(crate) struct Invite {
+    /// Passed as `invite` to [register]
+    invite: Uuid,
+}
+
+/// Invites somebody to register
+///
+/// Only admins can invite, and only if registration is invite-only,
+/// otherwise returns 403.
+#[tracing::instrument(skip(pool, config))]
+pub(crate) async fn create_invite(
+    Extension(pool): Extension<PgPool>,
+    Extension(config): Extension<Arc<Config>>,
+    AuthenticatedAdmin(user): AuthenticatedAdmin,
+) -> Result<Json<Invite>, ApiError> {
+    if config.registration.mode != RegistrationMode::InviteOnly {
+        return Err(ApiError::RegistrationDisabled);
+    }
+
+    Ok(Json(Invite {
+        invite: registration::create_invite(&pool, &user.id).await?,
+    }))
+}
@@ -2,18 +2,40 @@
 //!
 //!
 
+use std::sync::Arc;
+
 use axum::{response::IntoResponse, Extension, Json};
+use color_eyre::Report;
 use serde::Deserialize;
 use sqlx::PgPool;
-use tracing::debug;
+use tracing::{error, Instrument, Span};
 use uuid::Uuid;
 
 use crate::{
-    database::{self, get_user_by_email, new_reset_request, ResetError},
+    database::{self, get_user_by_email, new_reset_request, ResetError, User},
     error_handling::ApiError,
+    hashing::Hasher,
+    mail::Mailer,
+    password_policy::PasswordPolicy,
+    settings::Config,
     types::{EMail, Password},
 };
 
+/// Creates a new reset request for the user and mails the link
+async fn send_reset(
+    pool: &PgPool,
+    config: &Config,
+    mailer: &Mailer,
+    user: &User,
+) -> Result<(), Report> {
+    let reset_id = new_reset_request(pool, &user.id).await?;
+
+    let link = config
+        .app
+        .frontend_link("reset", &[("token", &reset_id.to_string())])?;
+    mailer.send_password_reset(user, &reset_id, &link).await
+}
+
 /// JSON for requesting a password reset
 #[derive(Debug, Deserialize)]
 pub(crate) struct ResetRequest {
@@ -26,19 +48,38 @@ pub(crate) struct ResetRequest {
 /// This checks whether an account exists (otherwise return 404) and
 /// if so create a new request to reset the password of the account
 ///
-/// At the moment this just logs the reset id, email infrastructure is planned.
-#[tracing::instrument(skip(pool))]
+/// The link to the reset page of the frontend is sent to the user by mail.
+/// With [prevent_user_enumeration](crate::settings::AppConfig::prevent_user_enumeration)
+/// unknown emails succeed as well, without sending a mail. Known emails
+/// then get their mail in the background, so both take the same time
+/// to answer and mail errors are only logged.
+#[tracing::instrument(skip(pool, config, mailer))]
 pub(crate) async fn request_reset(
     Extension(pool): Extension<PgPool>,
+    Extension(config): Extension<Arc<Config>>,
+    Extension(mailer): Extension<Arc<Mailer>>,
     Json(ResetRequest { email }): Json<ResetRequest>,
 ) -> Result<impl IntoResponse, ApiError> {
-    let user = get_user_by_email(&pool, &email)
-        .await?
-        .ok_or(ApiError::UserNotFound)?;
+    let Some(user) = get_user_by_email(&pool, &email).await? else {
+        if config.app.prevent_user_enumeration {
+            return Ok(());
+        }
+        return Err(ApiError::UserNotFound);
+    };
 
-    let reset_id = new_reset_request(&pool, &user.id).await?;
+    if config.app.prevent_user_enumeration {
+        tokio::spawn(
+            async move {
+                if let Err(e) = send_reset(&pool, &config, &mailer, &user).await {
+                    error!("Sending password reset failed: {e:?}");
+                }
+            }
+            .instrument(Span::current()),
+        );
+        return Ok(());
+    }
 
-    debug!("Reset ID for {email:#?}: {reset_id}");
+    send_reset(&pool, &config, &mailer, &user).await?;
 
     Ok(())
 }
@@ -56,19 +97,37 @@ pub(crate) struct PasswordReset {
 ///
 /// Checks whether the `reset_token` exists, otherwise returns 404,
 /// then deletes the token (invalidating it) and set's the new password.
+/// Expired tokens are deleted as well, but return 410.
+///
+/// If the new password does not follow the password policy 422 is
+/// returned and the token stays valid.
 ///
 /// This function works atomically so if an error is returned it is guarenteed
 /// that the reset did not happen.
-#[tracing::instrument(skip(pool))]
+#[tracing::instrument(skip(pool, config, password_policy, hasher))]
 pub(crate) async fn reset_password(
     Extension(pool): Extension<PgPool>,
+    Extension(config): Extension<Arc<Config>>,
+    Extension(password_policy): Extension<Arc<PasswordPolicy>>,
+    Extension(hasher): Extension<Arc<Hasher>>,
     Json(PasswordReset {
         reset_token,
         new_password,
     }): Json<PasswordReset>,
 ) -> Result<impl IntoResponse, ApiError> {
-    match database::reset_password(&pool, &reset_token, &new_password).await? {
+    match database::reset_password(
+        &pool,
+        &config.reset,
+        &password_policy,
+        &hasher,
+        &reset_token,
+        &new_password,
+    )
+    .await?
+    {
         Err(ResetError::TokenNotFound) => Err(ApiError::TokenNotFound),
+        Err(ResetError::TokenExpired) => Err(ApiError::TokenExpired),
+        Err(ResetError::WeakPassword(violations)) => Err(ApiError::WeakPassword(violations)),
         Ok(()) => Ok("Password was reset"),
     }
 }
@@ -81,13 +140,14 @@ pub struct TokenCheck {
 }
 /// Check whether a `reset_token` is valid
 ///
-/// Usefull for UX purposes
-#[tracing::instrument(skip(pool))]
+/// Usefull for UX purposes, expired tokens are considered invalid
+#[tracing::instrument(skip(pool, config))]
 pub(crate) async fn test_reset_token(
     Extension(pool): Extension<PgPool>,
+    Extension(config): Extension<Arc<Config>>,
     Json(TokenCheck { reset_token }): Json<TokenCheck>,
 ) -> Result<Json<bool>, ApiError> {
     Ok(Json(
-        database::reset_token_is_valid(&pool, &reset_token).await?,
+        database::reset_token_is_valid(&pool, &config.reset, &reset_token).await?,
     ))
 }
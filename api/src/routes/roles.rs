@@ -0,0 +1,127 @@
+//! Managing roles and permissions, only for admins
+//!
+//! See [roles](crate::database::roles) for how they are used.
+
+use axum::{extract::Path, http::StatusCode, Extension, Json};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::{
+    database::roles::{self, Permission, Role, RoleUpdate},
+    error_handling::ApiError,
+    middlewares::{
+        admin::AuthenticatedAdmin,
+        permission::{ReadUsers, RequirePermission},
+    },
+};
+
+/// Lists all permissions
+#[tracing::instrument(skip(pool))]
+pub(crate) async fn list_permissions(
+    Extension(pool): Extension<PgPool>,
+    _: AuthenticatedAdmin,
+) -> Result<Json<Vec<Permission>>, ApiError> {
+    Ok(Json(roles::list_permissions(&pool).await?))
+}
+
+/// Creates a permission, returns 409 if the name is taken
+#[tracing::instrument(skip(pool))]
+pub(crate) async fn create_permission(
+    Extension(pool): Extension<PgPool>,
+    _: AuthenticatedAdmin,
+    Json(permission): Json<Permission>,
+) -> Result<StatusCode, ApiError> {
+    roles::create_permission(&pool, &permission).await??;
+
+    Ok(StatusCode::CREATED)
+}
+
+/// Deletes a permission and removes it from all roles
+#[tracing::instrument(skip(pool))]
+pub(crate) async fn delete_permission(
+    Extension(pool): Extension<PgPool>,
+    _: AuthenticatedAdmin,
+    Path(name): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    if !roles::delete_permission(&pool, &name).await? {
+        return Err(ApiError::PermissionNotFound);
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Lists all roles with their permissions
+#[tracing::instrument(skip(pool))]
+pub(crate) async fn list_roles(
+    Extension(pool): Extension<PgPool>,
+    _: AuthenticatedAdmin,
+) -> Result<Json<Vec<Role>>, ApiError> {
+    Ok(Json(roles::list_roles(&pool).await?))
+}
+
+/// Creates a role
+///
+/// Returns 409 if the name is taken and 404 if one of the permissions
+/// does not exist.
+#[tracing::instrument(skip(pool))]
+pub(crate) async fn create_role(
+    Extension(pool): Extension<PgPool>,
+    _: AuthenticatedAdmin,
+    Json(role): Json<Role>,
+) -> Result<StatusCode, ApiError> {
+    roles::create_role(&pool, &role).await??;
+
+    Ok(StatusCode::CREATED)
+}
+
+/// Updates the description and/or replaces the permissions of a role
+#[tracing::instrument(skip(pool))]
+pub(crate) async fn update_role(
+    Extension(pool): Extension<PgPool>,
+    _: AuthenticatedAdmin,
+    Path(name): Path<String>,
+    Json(update): Json<RoleUpdate>,
+) -> Result<(), ApiError> {
+    roles::update_role(&pool, &name, &update).await??;
+
+    Ok(())
+}
+
+/// Deletes a role and removes it from all users
+#[tracing::instrument(skip(pool))]
+pub(crate) async fn delete_role(
+    Extension(pool): Extension<PgPool>,
+    _: AuthenticatedAdmin,
+    Path(name): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    if !roles::delete_role(&pool, &name).await? {
+        return Err(ApiError::RoleNotFound);
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Returns the roles of a user
+#[tracing::instrument(skip(pool))]
+pub(crate) async fn get_user_roles(
+    Extension(pool): Extension<PgPool>,
+    _: RequirePermission<ReadUsers>,
+    Path(user_id): Path<Uuid>,
+) -> Result<Json<Vec<String>>, ApiError> {
+    Ok(Json(roles::user_roles(&pool, &user_id).await?))
+}
+
+/// Replaces all roles of a user
+///
+/// Returns 404 if the user or one of the roles does not exist.
+#[tracing::instrument(skip(pool))]
+pub(crate) async fn set_user_roles(
+    Extension(pool): Extension<PgPool>,
+    _: AuthenticatedAdmin,
+    Path(user_id): Path<Uuid>,
+    Json(role_names): Json<Vec<String>>,
+) -> Result<(), ApiError> {
+    roles::set_user_roles(&pool, &user_id, &role_names).await??;
+
+    Ok(())
+}
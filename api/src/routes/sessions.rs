@@ -0,0 +1,73 @@
+//! Listing and revoking the sessions of the current user
+
+use std::sync::Arc;
+
+use axum::{extract::Path, Extension, Json};
+use color_eyre::eyre::Context;
+use serde::Serialize;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::{
+    database::sessions::{self, SessionInfo},
+    error_handling::ApiError,
+    middlewares::session::AuthenticatedSession,
+};
+
+/// Lists all sessions (devices) of the current user
+///
+/// The session of the request is marked with `current`.
+#[tracing::instrument(skip(pool))]
+pub(crate) async fn list_sessions(
+    Extension(pool): Extension<PgPool>,
+    AuthenticatedSession(session_id): AuthenticatedSession,
+) -> Result<Json<Vec<SessionInfo>>, ApiError> {
+    Ok(Json(sessions::list_sessions(&pool, &session_id).await?))
+}
+
+/// Revokes a single session of the current user
+///
+/// Returns 404 if the user has no session with this id.
+#[tracing::instrument(skip(pool, redis_client))]
+pub(crate) async fn revoke_session(
+    Extension(pool): Extension<PgPool>,
+    Extension(redis_client): Extension<Arc<redis::Client>>,
+    AuthenticatedSession(session_id): AuthenticatedSession,
+    Path(to_revoke): Path<Uuid>,
+) -> Result<(), ApiError> {
+    let mut redis_connection = redis_client
+        .get_async_connection()
+        .await
+        .wrap_err("Redis error")?;
+
+    if sessions::revoke_session(&pool, &mut redis_connection, &session_id, &to_revoke).await? {
+        Ok(())
+    } else {
+        Err(ApiError::SessionNotFound)
+    }
+}
+
+/// Result of [revoke_other_sessions]
+#[derive(Debug, Serialize)]
+pub(crate) struct RevokedSessions {
+    /// How many sessions were revoked
+    revoked: usize,
+}
+
+/// Logs the current user out everywhere else
+#[tracing::instrument(skip(pool, redis_client))]
+pub(crate) async fn revoke_other_sessions(
+    Extension(pool): Extension<PgPool>,
+    Extension(redis_client): Extension<Arc<redis::Client>>,
+    AuthenticatedSession(session_id): AuthenticatedSession,
+) -> Result<Json<RevokedSessions>, ApiError> {
+    let mut redis_connection = redis_client
+        .get_async_connection()
+        .await
+        .wrap_err("Redis error")?;
+
+    let revoked =
+        sessions::revoke_other_sessions(&pool, &mut redis_connection, &session_id).await?;
+
+    Ok(Json(RevokedSessions { revoked }))
+}
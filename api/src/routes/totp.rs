@@ -0,0 +1,93 @@
+//! Setting up and removing TOTP as second factor
+//!
+//! Logging in with a code is part of [login](super::login).
+
+use std::sync::Arc;
+
+use axum::{Extension, Json};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+
+use crate::{
+    database::{get_user_from_session, recovery_codes, totp},
+    error_handling::ApiError,
+    middlewares::session::AuthenticatedSession,
+    otp,
+    settings::Config,
+};
+
+/// Everything an authenticator app needs to generate codes
+#[derive(Debug, Serialize)]
+pub(crate) struct TotpEnrolment {
+    /// The base32 encoded secret, for manual entry
+    secret: String,
+    /// The `otpauth://` URI, supposed to be shown as QR code
+    provisioning_uri: String,
+}
+
+/// Starts setting up TOTP for the current user
+///
+/// The returned secret is not active until it is confirmed
+/// using [confirm_totp]. Returns 409 if TOTP is already active.
+#[tracing::instrument(skip(pool, config))]
+pub(crate) async fn begin_totp(
+    Extension(pool): Extension<PgPool>,
+    Extension(config): Extension<Arc<Config>>,
+    AuthenticatedSession(session_id): AuthenticatedSession,
+) -> Result<Json<TotpEnrolment>, ApiError> {
+    let user = get_user_from_session(&pool, &session_id)
+        .await?
+        .ok_or(ApiError::InvalidSession)?;
+
+    let secret = totp::begin_enrolment(&pool, &user.id).await??;
+
+    Ok(Json(TotpEnrolment {
+        secret: otp::encode_secret(&secret),
+        provisioning_uri: otp::provisioning_uri(&secret, &config.app.totp_issuer, &user.email.0)?
+            .into(),
+    }))
+}
+
+/// JSON containing a single TOTP code
+#[derive(Debug, Deserialize)]
+pub(crate) struct TotpCode {
+    /// The current code of the authenticator app
+    code: String,
+}
+
+/// Activates TOTP by submitting the first code
+///
+/// Returns 412 if no enrolment was started and 422 if the code is wrong.
+#[tracing::instrument(skip(pool, code))]
+pub(crate) async fn confirm_totp(
+    Extension(pool): Extension<PgPool>,
+    AuthenticatedSession(session_id): AuthenticatedSession,
+    Json(TotpCode { code }): Json<TotpCode>,
+) -> Result<(), ApiError> {
+    let user = get_user_from_session(&pool, &session_id)
+        .await?
+        .ok_or(ApiError::InvalidSession)?;
+
+    totp::confirm_enrolment(&pool, &user.id, &code).await??;
+
+    Ok(())
+}
+
+/// Deactivates TOTP, requires a current code
+///
+/// Removing the last second factor removes the recovery codes as well.
+#[tracing::instrument(skip(pool, code))]
+pub(crate) async fn disable_totp(
+    Extension(pool): Extension<PgPool>,
+    AuthenticatedSession(session_id): AuthenticatedSession,
+    Json(TotpCode { code }): Json<TotpCode>,
+) -> Result<(), ApiError> {
+    let user = get_user_from_session(&pool, &session_id)
+        .await?
+        .ok_or(ApiError::InvalidSession)?;
+
+    totp::disable(&pool, &user.id, &code).await??;
+    recovery_codes::delete_if_unused(&pool, &user.id).await?;
+
+    Ok(())
+}
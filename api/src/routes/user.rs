@@ -1,34 +1,183 @@
 //! Routes modifying or requesting user data
 
-use axum::{Extension, Json};
+use std::sync::Arc;
+
+use axum::{http::StatusCode, Extension, Json};
+use color_eyre::eyre::Context;
+use serde::{Deserialize, Serialize};
 use sqlx::PgPool;
+use uuid::Uuid;
 
 use crate::{
-    database::{get_user_from_session, update_current_user, User, UserUpdate},
+    database::{
+        auth::{self, LoginError},
+        get_user_from_session,
+        lockout::LoginAttempt,
+        organizations::{active_organization, memberships, Membership},
+        roles::user_roles,
+        sessions, update_current_user, User, UserUpdate,
+    },
     error_handling::ApiError,
-    middlewares::session::AuthenticatedSession,
+    hashing::Hasher,
+    mail::Mailer,
+    middlewares::{client_info::ClientInfo, session::AuthenticatedSession},
+    password_policy::PasswordPolicy,
+    settings::Config,
+    types::Password,
 };
 
+use super::email_change::start_email_change;
+
+/// The current user with their roles and organizations
+#[derive(Debug, Serialize)]
+pub(crate) struct CurrentUser {
+    /// Id, name, email and locale
+    #[serde(flatten)]
+    user: User,
+    /// Names of the global roles, see [roles](crate::database::roles)
+    roles: Vec<String>,
+    /// All organizations the user is a member of
+    memberships: Vec<Membership>,
+    /// The organization the current session acts on behalf of
+    active_organization: Option<Uuid>,
+}
+
+impl CurrentUser {
+    /// Loads roles and organizations of `user`
+    async fn load(pool: &PgPool, session_id: &Uuid, user: User) -> Result<Self, ApiError> {
+        Ok(Self {
+            roles: user_roles(pool, &user.id).await?,
+            memberships: memberships(pool, &user.id).await?,
+            active_organization: active_organization(pool, session_id).await?,
+            user,
+        })
+    }
+}
+
 #[tracing::instrument]
 pub(crate) async fn get_user(
     Extension(pool): Extension<PgPool>,
     AuthenticatedSession(session_id): AuthenticatedSession,
-) -> Result<Json<User>, ApiError> {
+) -> Result<Json<CurrentUser>, ApiError> {
     let user = get_user_from_session(&pool, &session_id)
         .await?
         .ok_or(ApiError::InvalidSession)?;
-    Ok(Json(user))
+    Ok(Json(CurrentUser::load(&pool, &session_id, user).await?))
 }
 
-#[tracing::instrument]
+/// Updates name, email or locale of the current user
+///
+/// A new email is not applied right away: The new address has to confirm
+/// it and the old address is told how to revert it. The returned user
+/// still has the old email. Returns 409 if the new email is taken.
+#[tracing::instrument(skip(pool, config, mailer))]
 pub(crate) async fn patch_user(
     Extension(pool): Extension<PgPool>,
+    Extension(config): Extension<Arc<Config>>,
+    Extension(mailer): Extension<Arc<Mailer>>,
     AuthenticatedSession(session_id): AuthenticatedSession,
-    Json(user_patch): Json<UserUpdate>,
-) -> Result<Json<User>, ApiError> {
+    Json(mut user_patch): Json<UserUpdate>,
+) -> Result<Json<CurrentUser>, ApiError> {
+    let new_email = user_patch.email.take();
+
     let user = update_current_user(&pool, &session_id, user_patch)
         .await?
         .ok_or(ApiError::InvalidSession)?;
 
-    Ok(Json(user))
+    if let Some(new_email) = new_email {
+        if new_email != user.email {
+            start_email_change(&pool, &config, &mailer, &user, &new_email).await?;
+        }
+    }
+
+    Ok(Json(CurrentUser::load(&pool, &session_id, user).await?))
+}
+
+/// JSON for changing the password of the current user
+#[derive(Debug, Deserialize)]
+pub(crate) struct PasswordChange {
+    /// Checked the same way as on login
+    current_password: Password,
+    /// The new, unhashed password
+    new_password: Password,
+    /// Log out all other sessions of the user afterwards
+    #[serde(default)]
+    revoke_other_sessions: bool,
+}
+
+/// Changes the password of the current user
+///
+/// Returns 401 if the current password is wrong and 422 if the new one
+/// does not follow the password policy. Wrong passwords count as failed
+/// logins, see [lockout](crate::database::lockout), so a stolen session
+/// can't be used to guess the password.
+#[tracing::instrument(skip_all)]
+#[expect(
+    clippy::too_many_arguments,
+    reason = "axum extractors, one per shared state the change needs"
+)]
+pub(crate) async fn change_password(
+    Extension(pool): Extension<PgPool>,
+    Extension(redis_client): Extension<Arc<redis::Client>>,
+    Extension(config): Extension<Arc<Config>>,
+    Extension(password_policy): Extension<Arc<PasswordPolicy>>,
+    Extension(hasher): Extension<Arc<Hasher>>,
+    AuthenticatedSession(session_id): AuthenticatedSession,
+    client: ClientInfo,
+    Json(change): Json<PasswordChange>,
+) -> Result<StatusCode, ApiError> {
+    let user = get_user_from_session(&pool, &session_id)
+        .await?
+        .ok_or(ApiError::InvalidSession)?;
+
+    let mut redis_connection = redis_client
+        .get_async_connection()
+        .await
+        .wrap_err("Redis error")?;
+
+    password_policy
+        .enforce(&change.new_password, &[&user.name, &user.email.0])
+        .await?;
+
+    let mut attempt = LoginAttempt::new(&config.lockout, &user.email, client.ip_address.as_deref());
+    if let Some(retry_after) = attempt
+        .reserve(&mut redis_connection, &config.lockout)
+        .await?
+    {
+        return Err(ApiError::TooManyAttempts(retry_after));
+    }
+
+    match auth::change_password(
+        &pool,
+        &hasher,
+        &user,
+        change.current_password,
+        &change.new_password,
+    )
+    .await?
+    {
+        Ok(()) => {
+            attempt
+                .succeeded(&mut redis_connection, &config.lockout)
+                .await?;
+        }
+        Err(err @ LoginError::InvalidCredentials) => {
+            attempt
+                .failed(&mut redis_connection, &config.lockout)
+                .await?;
+            return Err(err.into());
+        }
+        Err(err) => {
+            attempt
+                .cancelled(&mut redis_connection, &config.lockout)
+                .await?;
+            return Err(err.into());
+        }
+    }
+
+    if change.revoke_other_sessions {
+        sessions::revoke_other_sessions(&pool, &mut redis_connection, &session_id).await?;
+    }
+
+    Ok(StatusCode::NO_CONTENT)
 }
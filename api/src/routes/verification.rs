@@ -0,0 +1,126 @@
+//! Verifying email addresses
+//!
+//! Verification mails are sent on registration and when the email
+//! changes, these routes complete the verification or send a new mail.
+
+use std::sync::Arc;
+
+use axum::{response::IntoResponse, Extension, Json};
+use color_eyre::Report;
+use serde::Deserialize;
+use sqlx::PgPool;
+use tracing::{error, Instrument, Span};
+use uuid::Uuid;
+
+use crate::{
+    database::{
+        get_user_by_email,
+        verification::{self, create_verification, is_email_verified},
+        User,
+    },
+    error_handling::ApiError,
+    mail::Mailer,
+    settings::Config,
+    types::EMail,
+};
+
+/// Creates a verification token for the current email of `user`
+/// and mails the link to it
+pub(super) async fn send_verification(
+    pool: &PgPool,
+    config: &Config,
+    mailer: &Mailer,
+    user: &User,
+) -> Result<(), Report> {
+    let token = create_verification(pool, &user.id, &user.email).await?;
+    let link = config
+        .app
+        .frontend_link("verify-email", &[("token", &token.to_string())])?;
+
+    mailer
+        .send_email_verification(user, &user.email, &token, &link)
+        .await
+}
+
+/// JSON for verifying an email
+#[derive(Debug, Deserialize)]
+pub(crate) struct EmailVerification {
+    /// The token sent by mail
+    token: Uuid,
+}
+
+/// Marks the email of the user as verified
+///
+/// Returns 404 if the token does not exist (or the email was changed
+/// since) and 410 if it expired.
+#[tracing::instrument(skip(pool, config))]
+pub(crate) async fn verify_email(
+    Extension(pool): Extension<PgPool>,
+    Extension(config): Extension<Arc<Config>>,
+    Json(EmailVerification { token }): Json<EmailVerification>,
+) -> Result<impl IntoResponse, ApiError> {
+    verification::verify_email(&pool, &config.verification, &token).await??;
+
+    Ok("Email was verified")
+}
+
+/// Like [send_verification], but skips already verified accounts
+async fn send_verification_if_unverified(
+    pool: &PgPool,
+    config: &Config,
+    mailer: &Mailer,
+    user: &User,
+) -> Result<(), Report> {
+    if is_email_verified(pool, &user.id).await? {
+        return Ok(());
+    }
+
+    send_verification(pool, config, mailer, user).await
+}
+
+/// JSON for requesting a new verification mail
+#[derive(Debug, Deserialize)]
+pub(crate) struct VerificationRequest {
+    /// The email of the account
+    email: EMail,
+}
+
+/// Sends a new verification mail, i.e. if the last one expired
+///
+/// Returns 404 if there is no account with the email, unless
+/// [prevent_user_enumeration](crate::settings::AppConfig::prevent_user_enumeration)
+/// is set. Then the mail is sent in the background, so known and unknown
+/// emails take the same time to answer and mail errors are only logged.
+/// Already verified accounts don't get a mail.
+#[tracing::instrument(skip(pool, config, mailer))]
+pub(crate) async fn request_verification(
+    Extension(pool): Extension<PgPool>,
+    Extension(config): Extension<Arc<Config>>,
+    Extension(mailer): Extension<Arc<Mailer>>,
+    Json(VerificationRequest { email }): Json<VerificationRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    let Some(user) = get_user_by_email(&pool, &email).await? else {
+        if config.app.prevent_user_enumeration {
+            return Ok(());
+        }
+        return Err(ApiError::UserNotFound);
+    };
+
+    if config.app.prevent_user_enumeration {
+        tokio::spawn(
+            async move {
+                if let Err(e) =
+                    send_verification_if_unverified(&pool, &config, &mailer, &user).await
+                {
+                    error!("Sending verification mail failed: {e:?}");
+                }
+            }
+            .instrument(Span::current()),
+        );
+        return Ok(());
+    }
+
+    send_verification_if_unverified(&pool, &config, &mailer, &user).await?;
+
+    Ok(())
+}
@@ -0,0 +1,143 @@
+//! Managing WebAuthn credentials (passkeys & security keys)
+//!
+//! Logging in with a credential is part of [login](super::login).
+
+use std::sync::Arc;
+
+use axum::{extract::Path, Extension, Json};
+use color_eyre::eyre::Context;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use uuid::Uuid;
+use webauthn_rs::prelude::{CreationChallengeResponse, RegisterPublicKeyCredential, Webauthn};
+
+use crate::{
+    database::{
+        get_user_from_session, recovery_codes,
+        webauthn::{self, WebauthnCredential},
+    },
+    error_handling::ApiError,
+    middlewares::session::AuthenticatedSession,
+};
+
+/// JSON for starting a registration
+#[derive(Debug, Deserialize)]
+pub(crate) struct RegistrationStart {
+    /// Name of the new credential, so the user can tell them apart
+    name: String,
+}
+
+/// A started registration
+#[derive(Debug, Serialize)]
+pub(crate) struct RegistrationChallenge {
+    /// Has to be sent together with the created credential
+    ceremony_id: Uuid,
+    /// Options for `navigator.credentials.create()`
+    options: CreationChallengeResponse,
+}
+
+/// Starts registering a new credential for the current user
+#[tracing::instrument(skip(pool, redis_client, webauthn))]
+pub(crate) async fn begin_registration(
+    Extension(pool): Extension<PgPool>,
+    Extension(redis_client): Extension<Arc<redis::Client>>,
+    Extension(webauthn): Extension<Arc<Webauthn>>,
+    AuthenticatedSession(session_id): AuthenticatedSession,
+    Json(RegistrationStart { name }): Json<RegistrationStart>,
+) -> Result<Json<RegistrationChallenge>, ApiError> {
+    let user = get_user_from_session(&pool, &session_id)
+        .await?
+        .ok_or(ApiError::InvalidSession)?;
+    let mut redis_connection = redis_client
+        .get_async_connection()
+        .await
+        .wrap_err("Redis error")?;
+
+    let (ceremony_id, options) =
+        webauthn::begin_registration(&pool, &mut redis_connection, &webauthn, &user, name).await?;
+
+    Ok(Json(RegistrationChallenge {
+        ceremony_id,
+        options,
+    }))
+}
+
+/// JSON for finishing a registration
+#[derive(Debug, Deserialize)]
+pub(crate) struct RegistrationFinish {
+    /// The ceremony returned by [begin_registration]
+    ceremony_id: Uuid,
+    /// The result of `navigator.credentials.create()`
+    credential: RegisterPublicKeyCredential,
+}
+
+/// Verifies and stores the new credential
+///
+/// Returns 410 if the ceremony timed out and 422 if the
+/// credential was rejected.
+#[tracing::instrument(skip(pool, redis_client, webauthn, credential))]
+pub(crate) async fn finish_registration(
+    Extension(pool): Extension<PgPool>,
+    Extension(redis_client): Extension<Arc<redis::Client>>,
+    Extension(webauthn): Extension<Arc<Webauthn>>,
+    AuthenticatedSession(session_id): AuthenticatedSession,
+    Json(RegistrationFinish {
+        ceremony_id,
+        credential,
+    }): Json<RegistrationFinish>,
+) -> Result<(), ApiError> {
+    let user = get_user_from_session(&pool, &session_id)
+        .await?
+        .ok_or(ApiError::InvalidSession)?;
+    let mut redis_connection = redis_client
+        .get_async_connection()
+        .await
+        .wrap_err("Redis error")?;
+
+    webauthn::finish_registration(
+        &pool,
+        &mut redis_connection,
+        &webauthn,
+        &user.id,
+        &ceremony_id,
+        &credential,
+    )
+    .await??;
+
+    Ok(())
+}
+
+/// Lists the credentials of the current user
+#[tracing::instrument(skip(pool))]
+pub(crate) async fn list_credentials(
+    Extension(pool): Extension<PgPool>,
+    AuthenticatedSession(session_id): AuthenticatedSession,
+) -> Result<Json<Vec<WebauthnCredential>>, ApiError> {
+    let user = get_user_from_session(&pool, &session_id)
+        .await?
+        .ok_or(ApiError::InvalidSession)?;
+
+    Ok(Json(webauthn::list_credentials(&pool, &user.id).await?))
+}
+
+/// Removes a credential of the current user
+///
+/// Returns 404 if the user has no credential with this id. Removing the
+/// last second factor removes the recovery codes as well.
+#[tracing::instrument(skip(pool))]
+pub(crate) async fn remove_credential(
+    Extension(pool): Extension<PgPool>,
+    AuthenticatedSession(session_id): AuthenticatedSession,
+    Path(credential_id): Path<Uuid>,
+) -> Result<(), ApiError> {
+    let user = get_user_from_session(&pool, &session_id)
+        .await?
+        .ok_or(ApiError::InvalidSession)?;
+
+    if !webauthn::remove_credential(&pool, &user.id, &credential_id).await? {
+        return Err(ApiError::CredentialNotFound);
+    }
+    recovery_codes::delete_if_unused(&pool, &user.id).await?;
+
+    Ok(())
+}
@@ -1,8 +1,10 @@
 //! Loading settings from files and environment
 use std::collections::HashSet;
 
+use color_eyre::Report;
 use config::{Environment, File};
 use serde::Deserialize;
+use url::Url;
 
 /// Config for PostgreSQL Connection
 #[derive(Debug, Deserialize)]
@@ -23,6 +25,399 @@ pub(crate) struct AppConfig {
     /// Usefull for development, should be deactivated in production.
     #[serde(default = "false_default")]
     pub(crate) allow_localhost: bool,
+    /// Shown as the issuer in authenticator apps when setting up TOTP
+    #[serde(default = "default_totp_issuer")]
+    pub(crate) totp_issuer: String,
+    /// Base URL of the frontend, used for links in emails
+    #[serde(default = "default_frontend_url")]
+    pub(crate) frontend_url: String,
+    /// Hide whether an account exists: Logins with unknown emails fail
+    /// like wrong passwords (taking as long) and requesting a password
+    /// reset or verification mail always succeeds
+    #[serde(default = "false_default")]
+    pub(crate) prevent_user_enumeration: bool,
+}
+
+impl AppConfig {
+    /// Builds a link to a page of the frontend
+    pub(crate) fn frontend_link(&self, path: &str, query: &[(&str, &str)]) -> Result<Url, Report> {
+        let mut url = Url::parse(&self.frontend_url)?.join(path)?;
+        url.query_pairs_mut().extend_pairs(query);
+
+        Ok(url)
+    }
+}
+
+/// Which transport is used for sending mails
+#[derive(Debug, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum MailTransport {
+    /// Print mails to stdout, the default
+    #[default]
+    Stdout,
+    /// Write every mail as `.eml` file into [MailConfig::directory]
+    File,
+    /// Send mails using [MailConfig::smtp]
+    Smtp,
+}
+
+/// How the connection to the SMTP server is secured
+#[derive(Debug, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum SmtpTls {
+    /// Upgrade a plain connection using STARTTLS (usually port 587), the default
+    #[default]
+    Starttls,
+    /// Connect using TLS right away (usually port 465)
+    Tls,
+    /// Unencrypted, only for local development servers
+    None,
+}
+
+/// Config for an SMTP server
+#[derive(Debug, Deserialize)]
+pub(crate) struct SmtpConfig {
+    /// Hostname of the server
+    pub(crate) host: String,
+    /// Defaults to the port matching [SmtpConfig::tls]
+    pub(crate) port: Option<u16>,
+    /// See [SmtpTls]
+    #[serde(default)]
+    pub(crate) tls: SmtpTls,
+    /// Username for authentication, authentication is skipped if unset
+    pub(crate) username: Option<String>,
+    /// Password for authentication
+    pub(crate) password: Option<String>,
+}
+
+/// Config for sending mails
+#[derive(Debug, Deserialize)]
+pub(crate) struct MailConfig {
+    /// Sender of all mails, i.e. `Hausmeister <noreply@example.com>`
+    pub(crate) from: String,
+    /// See [MailTransport]
+    #[serde(default)]
+    pub(crate) transport: MailTransport,
+    /// Target directory of the file transport
+    pub(crate) directory: Option<String>,
+    /// Server of the SMTP transport
+    pub(crate) smtp: Option<SmtpConfig>,
+    /// Directory with custom templates, overriding the built-in ones,
+    /// see [mail::templates](crate::mail::templates)
+    pub(crate) templates: Option<String>,
+    /// Locale used if there are no templates for the user's locale
+    #[serde(default = "default_locale")]
+    pub(crate) default_locale: String,
+}
+
+impl Default for MailConfig {
+    /// Prints all mails to stdout
+    fn default() -> Self {
+        Self {
+            from: "Hausmeister <noreply@localhost>".to_owned(),
+            transport: MailTransport::Stdout,
+            directory: None,
+            smtp: None,
+            templates: None,
+            default_locale: default_locale(),
+        }
+    }
+}
+
+/// Config for session lifetimes, all values are in seconds
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub(crate) struct SessionConfig {
+    /// Maximum age of a session, no matter how active it is
+    pub(crate) lifetime: u64,
+    /// A session that has not been used for this long expires
+    pub(crate) idle_timeout: u64,
+    /// How often expired sessions and login challenges are removed from
+    /// the database
+    pub(crate) cleanup_interval: u64,
+}
+
+impl Default for SessionConfig {
+    /// 30 days lifetime, 7 days idle timeout, hourly cleanup
+    fn default() -> Self {
+        Self {
+            lifetime: 30 * 24 * 60 * 60,
+            idle_timeout: 7 * 24 * 60 * 60,
+            cleanup_interval: 60 * 60,
+        }
+    }
+}
+
+/// Config for password resets, all values are in seconds
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub(crate) struct ResetConfig {
+    /// How long a reset token can be used after it was requested
+    pub(crate) token_lifetime: u64,
+    /// How often expired reset tokens are removed from the database
+    pub(crate) cleanup_interval: u64,
+}
+
+impl Default for ResetConfig {
+    /// One hour token lifetime, hourly cleanup
+    fn default() -> Self {
+        Self {
+            token_lifetime: 60 * 60,
+            cleanup_interval: 60 * 60,
+        }
+    }
+}
+
+/// Config for logging in by mail without a password, all durations are
+/// in seconds
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub(crate) struct MagicLinkConfig {
+    /// Whether the magic link routes exist at all
+    pub(crate) enabled: bool,
+    /// How long a link can be used after it was requested
+    pub(crate) token_lifetime: u64,
+    /// How often expired links are removed from the database
+    pub(crate) cleanup_interval: u64,
+}
+
+impl Default for MagicLinkConfig {
+    /// Disabled, 15 minutes token lifetime, hourly cleanup
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            token_lifetime: 15 * 60,
+            cleanup_interval: 60 * 60,
+        }
+    }
+}
+
+/// Config for email verification, all durations are in seconds
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub(crate) struct VerificationConfig {
+    /// Refuse logins of users who have not verified their email yet
+    pub(crate) required_for_login: bool,
+    /// How long a verification link (or the confirmation of an email
+    /// change) can be used
+    pub(crate) token_lifetime: u64,
+    /// How long the old address can revert an email change
+    pub(crate) revert_lifetime: u64,
+    /// How often expired verification tokens are removed from the database
+    pub(crate) cleanup_interval: u64,
+}
+
+impl Default for VerificationConfig {
+    /// Logins are allowed without verification, one day token lifetime,
+    /// 7 days to revert email changes, hourly cleanup
+    fn default() -> Self {
+        Self {
+            required_for_login: false,
+            token_lifetime: 24 * 60 * 60,
+            revert_lifetime: 7 * 24 * 60 * 60,
+            cleanup_interval: 60 * 60,
+        }
+    }
+}
+
+/// Rules for new passwords, see [password_policy](crate::password_policy)
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub(crate) struct PasswordPolicyConfig {
+    /// Minimum number of characters
+    pub(crate) min_length: usize,
+    /// Maximum number of characters, bounds the cost of hashing
+    pub(crate) max_length: usize,
+    /// Require at least one lowercase letter
+    pub(crate) require_lowercase: bool,
+    /// Require at least one uppercase letter
+    pub(crate) require_uppercase: bool,
+    /// Require at least one digit
+    pub(crate) require_digit: bool,
+    /// Require at least one character which is neither letter nor digit
+    pub(crate) require_symbol: bool,
+    /// Forbid passwords containing the name or email of the user
+    pub(crate) forbid_personal_info: bool,
+    /// File with SHA-1 hashes of breached passwords, which are forbidden
+    pub(crate) breached_passwords: Option<String>,
+    /// Format of [PasswordPolicyConfig::breached_passwords]
+    pub(crate) breached_passwords_format: BreachListFormat,
+}
+
+impl Default for PasswordPolicyConfig {
+    /// 8 to 128 characters without name or email, no character classes
+    /// required and no list of breached passwords
+    fn default() -> Self {
+        Self {
+            min_length: 8,
+            max_length: 128,
+            require_lowercase: false,
+            require_uppercase: false,
+            require_digit: false,
+            require_symbol: false,
+            forbid_personal_info: true,
+            breached_passwords: None,
+            breached_passwords_format: BreachListFormat::default(),
+        }
+    }
+}
+
+/// Format of the list of breached passwords, see
+/// [breached](crate::password_policy::breached)
+#[derive(Debug, Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum BreachListFormat {
+    /// One `HASH:COUNT` per line, read into memory, the default
+    #[default]
+    Text,
+    /// Sorted raw hashes, looked up on disk
+    Binary,
+}
+
+/// Argon2id parameters for new hashes, see [hashing](crate::hashing)
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub(crate) struct HashingConfig {
+    /// Memory in KiB
+    pub(crate) memory_cost: u32,
+    /// Number of passes over the memory
+    pub(crate) time_cost: u32,
+    /// Number of lanes
+    pub(crate) parallelism: u32,
+    /// Hashes computed at the same time, further ones wait
+    pub(crate) max_concurrent: usize,
+}
+
+impl Default for HashingConfig {
+    /// The defaults of [argon2], 4 MiB, 3 passes and 1 lane, and as many
+    /// concurrent hashes as there are CPUs
+    fn default() -> Self {
+        Self {
+            memory_cost: argon2::Params::DEFAULT_M_COST,
+            time_cost: argon2::Params::DEFAULT_T_COST,
+            parallelism: argon2::Params::DEFAULT_P_COST,
+            max_concurrent: std::thread::available_parallelism().map_or(1, usize::from),
+        }
+    }
+}
+
+/// Config for limiting failed logins, all durations are in seconds
+///
+/// See [lockout](crate::database::lockout), a limit of 0 disables it.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub(crate) struct LockoutConfig {
+    /// Failed logins per account before it is locked
+    pub(crate) max_account_failures: u64,
+    /// Failed logins per IP address before it is locked
+    pub(crate) max_ip_failures: u64,
+    /// How long failed logins are counted after the last one
+    pub(crate) window: u64,
+    /// How long the first lock lasts, doubles with every further failure
+    pub(crate) lockout_duration: u64,
+    /// The longest a lock can last
+    pub(crate) max_lockout_duration: u64,
+}
+
+impl Default for LockoutConfig {
+    /// 5 failures per account and 50 per IP address within 15 minutes,
+    /// locks start at one minute and last at most one hour
+    fn default() -> Self {
+        Self {
+            max_account_failures: 5,
+            max_ip_failures: 50,
+            window: 15 * 60,
+            lockout_duration: 60,
+            max_lockout_duration: 60 * 60,
+        }
+    }
+}
+
+/// Who can create an account using `/register`
+#[derive(Debug, Deserialize, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum RegistrationMode {
+    /// Everybody
+    Open,
+    /// Only people invited by an admin
+    InviteOnly,
+    /// Nobody, the default
+    #[default]
+    Disabled,
+}
+
+/// Config for self-service registration
+#[derive(Debug, Deserialize)]
+pub(crate) struct RegistrationConfig {
+    /// See [RegistrationMode]
+    #[serde(default)]
+    pub(crate) mode: RegistrationMode,
+    /// How long an invite (to register or to join an organization) can
+    /// be used, in seconds
+    #[serde(default = "default_invite_lifetime")]
+    pub(crate) invite_lifetime: u64,
+}
+
+impl Default for RegistrationConfig {
+    /// Registration is disabled
+    fn default() -> Self {
+        Self {
+            mode: RegistrationMode::Disabled,
+            invite_lifetime: default_invite_lifetime(),
+        }
+    }
+}
+
+/// Config for WebAuthn (passkeys & security keys)
+///
+/// Credentials are bound to the relying party id, so changing it
+/// invalidates all registered credentials.
+#[derive(Debug, Deserialize)]
+pub(crate) struct WebauthnConfig {
+    /// The relying party id, the effective domain of the frontend
+    /// (i.e. `example.com`)
+    pub(crate) rp_id: String,
+    /// The origin of the frontend, has to be `rp_id` or a subdomain of it
+    pub(crate) rp_origin: String,
+    /// Name shown by the browser while registering a credential
+    pub(crate) rp_name: String,
+}
+
+impl Default for WebauthnConfig {
+    /// Works with the frontend dev server
+    fn default() -> Self {
+        Self {
+            rp_id: "localhost".to_owned(),
+            rp_origin: "http://localhost:5173".to_owned(),
+            rp_name: "Hausmeister".to_owned(),
+        }
+    }
+}
+
+/// Config for acting as OpenID Connect provider
+///
+/// If this section is missing, all OIDC routes are disabled.
+#[derive(Debug, Deserialize)]
+pub(crate) struct OidcConfig {
+    /// The public URL of this API, used as `iss` claim and to build the
+    /// endpoint URLs in the discovery document, without trailing slash
+    pub(crate) issuer: String,
+    /// Path to the PKCS#8 PEM encoded RSA private key used to sign tokens
+    pub(crate) signing_key: String,
+    /// Published as `kid`, change it when rotating the key
+    #[serde(default = "default_key_id")]
+    pub(crate) key_id: String,
+    /// The login page of the frontend, every authorization request is
+    /// redirected here with its query in `authorization_request`, see
+    /// [authorize](crate::routes::oidc::authorize)
+    pub(crate) login_url: String,
+    /// Lifetime of id and access tokens in seconds
+    #[serde(default = "default_token_lifetime")]
+    pub(crate) token_lifetime: i64,
+    /// How often expired authorization codes are removed from the
+    /// database, in seconds
+    #[serde(default = "default_cleanup_interval")]
+    pub(crate) cleanup_interval: u64,
 }
 
 /// Collection of all config areas
@@ -32,6 +427,38 @@ pub(crate) struct Config {
     pub(crate) database: DbConfig,
     /// General application config
     pub(crate) app: AppConfig,
+    /// Session lifetimes
+    #[serde(default)]
+    pub(crate) session: SessionConfig,
+    /// Password reset token lifetimes
+    #[serde(default)]
+    pub(crate) reset: ResetConfig,
+    /// Email delivery
+    #[serde(default)]
+    pub(crate) mail: MailConfig,
+    /// Self-service registration
+    #[serde(default)]
+    pub(crate) registration: RegistrationConfig,
+    /// Email verification
+    #[serde(default)]
+    pub(crate) verification: VerificationConfig,
+    /// Passwordless logins by mail
+    #[serde(default)]
+    pub(crate) magic_link: MagicLinkConfig,
+    /// Limits for failed logins
+    #[serde(default)]
+    pub(crate) lockout: LockoutConfig,
+    /// Rules for new passwords
+    #[serde(default)]
+    pub(crate) password_policy: PasswordPolicyConfig,
+    /// Cost of password hashing
+    #[serde(default)]
+    pub(crate) hashing: HashingConfig,
+    /// WebAuthn relying party config
+    #[serde(default)]
+    pub(crate) webauthn: WebauthnConfig,
+    /// OpenID Connect provider config, OIDC is disabled if unset
+    pub(crate) oidc: Option<OidcConfig>,
 }
 
 /// Reads config from config.toml + environment
@@ -56,3 +483,48 @@ pub(crate) fn read_config() -> color_eyre::Result<Config> {
 fn false_default() -> bool {
     false
 }
+
+/// Proxy for serde default, see [false_default]
+fn default_totp_issuer() -> String {
+    "Hausmeister".to_owned()
+}
+
+/// Proxy for serde default, see [false_default]
+fn default_key_id() -> String {
+    "hausmeister".to_owned()
+}
+
+/// Proxy for serde default, see [false_default]
+///
+/// One hour
+fn default_token_lifetime() -> i64 {
+    60 * 60
+}
+
+/// Proxy for serde default, see [false_default]
+///
+/// One hour
+fn default_cleanup_interval() -> u64 {
+    60 * 60
+}
+
+/// Proxy for serde default, see [false_default]
+///
+/// The address of the frontend dev server
+fn default_frontend_url() -> String {
+    "http://localhost:5173".to_owned()
+}
+
+/// Proxy for serde default, see [false_default]
+///
+/// The built-in templates are english
+fn default_locale() -> String {
+    "en".to_owned()
+}
+
+/// Proxy for serde default, see [false_default]
+///
+/// 7 days
+fn default_invite_lifetime() -> u64 {
+    7 * 24 * 60 * 60
+}